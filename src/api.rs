@@ -0,0 +1,1330 @@
+//! HTTP management API
+
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Extension, Json, Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put, MethodRouter},
+    Router,
+};
+use http_body::{LengthLimitError, Limited};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use std::sync::Arc;
+
+use crate::db::{self, Account, Admin, Role, Status};
+use crate::error::Error;
+use crate::metrics::METRICS;
+use crate::payment::{Payments, PendingInvoice};
+use crate::relays::RelayScore;
+use crate::reload::SharedConfig;
+use crate::repo::Repo;
+use crate::shutdown::Shutdown;
+use crate::{nip98, utils};
+
+/// Page size used when `limit` is not set
+const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Largest page that can be requested
+const MAX_PAGE_LIMIT: usize = 1000;
+/// Largest request body accepted, in bytes
+const MAX_BODY_SIZE: usize = 256 * 1024;
+/// Days of admission an invoice is created for when `days` is not set
+const DEFAULT_INVOICE_DAYS: u64 = 30;
+/// Most days of admission a single invoice can pay for
+const MAX_INVOICE_DAYS: u64 = 365;
+
+#[derive(Clone)]
+pub struct AppState {
+    /// Read on each request so `api_key` and `api_url` can be reloaded
+    config: SharedConfig,
+    repo: Arc<Mutex<Repo>>,
+    /// Paid admission, if enabled
+    payments: Option<Arc<Payments>>,
+}
+
+pub async fn start_server(
+    config: SharedConfig,
+    repo: Arc<Mutex<Repo>>,
+    payments: Option<Arc<Payments>>,
+    shutdown: Shutdown,
+) -> Result<(), Error> {
+    let shared_state = AppState {
+        config,
+        repo,
+        payments,
+    };
+
+    let app = router(shared_state);
+
+    // run it with hyper on localhost:3000
+    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown.wait())
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
+/// Routes every operation in `OPERATIONS`, behind `auth` unless it is public
+fn router(state: AppState) -> Router {
+    let mut authenticated = Router::new();
+    let mut public = Router::new();
+    for op in OPERATIONS {
+        let path = op.path.replace('{', ":").replace('}', "");
+        match op.public {
+            true => public = public.route(&path, (op.handler)()),
+            false => authenticated = authenticated.route(&path, (op.handler)()),
+        }
+    }
+
+    authenticated
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth))
+        .merge(public)
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state)
+}
+
+/// Error returned by every handler, serialized as `{"error": <message>}`
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "Not found")
+    }
+
+    fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, "Not permitted for role")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+    }
+}
+
+/// Authenticates requests with NIP-98 from a key with a role,
+/// falling back to the `X-Api-Key` header if an api key is configured.
+/// The role of the caller is added to the request extensions.
+async fn auth(
+    State(state): State<AppState>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, ApiError> {
+    if let Some(auth_header) = req.headers().get(header::AUTHORIZATION) {
+        let auth_header = auth_header
+            .to_str()
+            .map_err(|_| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid Authorization"))?
+            .to_string();
+
+        let base_url = match &state.config.current().settings.info.api_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => match req.headers().get(header::HOST).map(|h| h.to_str()) {
+                Some(Ok(host)) => format!("http://{host}"),
+                _ => return Err(ApiError::new(StatusCode::BAD_REQUEST, "No Host header")),
+            },
+        };
+        let url = match req.uri().path_and_query() {
+            Some(path) => format!("{base_url}{path}"),
+            None => base_url,
+        };
+        let method = req.method().to_string();
+
+        // The body is buffered so its hash can be checked against the `payload` tag,
+        // limited as this happens before the caller is authenticated
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(Limited::new(body, MAX_BODY_SIZE))
+            .await
+            .map_err(|err| match err.downcast_ref::<LengthLimitError>() {
+                Some(_) => ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Body too large"),
+                None => ApiError::new(StatusCode::BAD_REQUEST, "Invalid body"),
+            })?;
+
+        let pubkey =
+            nip98::verify_auth_header(&auth_header, &url, &method, &body).map_err(|err| {
+                warn!("Rejected NIP-98 auth: {err}");
+                ApiError::new(StatusCode::UNAUTHORIZED, &err.to_string())
+            })?;
+
+        let role = match state.repo.lock().await.get_role(&pubkey)? {
+            Some(role) => role,
+            None => return Err(ApiError::new(StatusCode::FORBIDDEN, "Not an admin key")),
+        };
+
+        debug!("NIP-98 authenticated: {pubkey} as {role:?}");
+        let mut req = Request::from_parts(parts, Body::from(body));
+        req.extensions_mut().insert(role);
+        return Ok(next.run(req).await);
+    }
+
+    let config = state.config.current();
+    match (
+        req.headers().get("X-Api-Key"),
+        &config.settings.info.api_key,
+    ) {
+        (Some(key), Some(api_key))
+            if utils::constant_time_eq(key.as_bytes(), api_key.as_bytes()) =>
+        {
+            // The api key is set in the config so has the same power as config keys
+            req.extensions_mut().insert(Role::Owner);
+            Ok(next.run(req).await)
+        }
+        (Some(_), Some(_)) => Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid API Key")),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "No Authorization")),
+    }
+}
+
+fn check_status_permitted(role: Role, status: Status) -> Result<(), ApiError> {
+    let permitted = match status {
+        Status::Allow => role.can_allow(),
+        Status::Deny => role.can_deny(),
+    };
+    match permitted {
+        true => Ok(()),
+        false => Err(ApiError::forbidden()),
+    }
+}
+
+/// Keys with a role can only be denied or removed by a higher role
+fn check_can_moderate(repo: &Repo, pubkey: &str, role: Role) -> Result<(), ApiError> {
+    match repo.can_moderate(pubkey, role)? {
+        true => Ok(()),
+        false => Err(ApiError::forbidden()),
+    }
+}
+
+fn check_hex_key(key: &str) -> Result<(), ApiError> {
+    if utils::is_hex_key(key) {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "Expected 32-bytes lowercase hex",
+    ))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Users {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+async fn update_users(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Json(payload): Json<Users>,
+) -> Result<(), ApiError> {
+    debug!("Users: {payload:?}");
+    for pubkey in payload.allow.iter().chain(&payload.deny).flatten() {
+        check_hex_key(pubkey)?;
+    }
+    if payload.allow.is_some() {
+        check_status_permitted(role, Status::Allow)?;
+    }
+    if payload.deny.is_some() {
+        check_status_permitted(role, Status::Deny)?;
+    }
+
+    let repo = state.repo.lock().await;
+    if let Some(pubkeys) = &payload.deny {
+        for pubkey in pubkeys {
+            check_can_moderate(&repo, pubkey, role)?;
+        }
+    }
+
+    // Admit pubkeys
+    if let Some(pubkeys) = &payload.allow {
+        debug!("Pubkeys to allow: {pubkeys:?}");
+        repo.admit_pubkeys(pubkeys).await?;
+    }
+
+    // Deny pubkeys
+    if let Some(pubkeys) = &payload.deny {
+        debug!("Pubkeys to deny: {pubkeys:?}");
+        repo.deny_pubkeys(pubkeys, role).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    status: Option<Status>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsersPage {
+    users: Vec<Account>,
+    next_cursor: Option<String>,
+}
+
+async fn get_users(
+    State(state): State<AppState>,
+    Query(query): Query<PageQuery>,
+) -> Result<Json<UsersPage>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let (users, next_cursor) =
+        state
+            .repo
+            .lock()
+            .await
+            .get_accounts_page(query.status, query.cursor.as_deref(), limit)?;
+
+    Ok(Json(UsersPage { users, next_cursor }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatusBody {
+    status: Status,
+}
+
+async fn get_user(
+    State(state): State<AppState>,
+    Path(pubkey): Path<String>,
+) -> Result<Json<Account>, ApiError> {
+    check_hex_key(&pubkey)?;
+
+    match state.repo.lock().await.get_account(&pubkey)? {
+        Some(account) => Ok(Json(account)),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+async fn put_user(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(pubkey): Path<String>,
+    Json(payload): Json<StatusBody>,
+) -> Result<Json<Account>, ApiError> {
+    check_hex_key(&pubkey)?;
+    check_status_permitted(role, payload.status)?;
+
+    let repo = state.repo.lock().await;
+    if payload.status == Status::Deny {
+        check_can_moderate(&repo, &pubkey, role)?;
+    }
+    let account = repo.update_account(&pubkey, payload.status).await?;
+
+    Ok(Json(account))
+}
+
+async fn delete_user(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(pubkey): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_hex_key(&pubkey)?;
+    check_status_permitted(role, Status::Allow)?;
+
+    let repo = state.repo.lock().await;
+    check_can_moderate(&repo, &pubkey, role)?;
+    match repo.remove_account(&pubkey)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found()),
+    }
+}
+
+async fn get_event(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<db::Event>, ApiError> {
+    check_hex_key(&id)?;
+
+    match state.repo.lock().await.get_event(&id)? {
+        Some(event) => Ok(Json(event)),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+async fn put_event(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(id): Path<String>,
+    Json(payload): Json<StatusBody>,
+) -> Result<Json<db::Event>, ApiError> {
+    check_hex_key(&id)?;
+    check_status_permitted(role, payload.status)?;
+
+    let event = db::Event {
+        id,
+        status: payload.status,
+    };
+    state.repo.lock().await.add_event(&event)?;
+
+    Ok(Json(event))
+}
+
+async fn delete_event(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_hex_key(&id)?;
+    check_status_permitted(role, Status::Allow)?;
+
+    match state.repo.lock().await.remove_event(&id)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found()),
+    }
+}
+
+async fn get_admins(State(state): State<AppState>) -> Result<Json<Vec<Admin>>, ApiError> {
+    let admins = state.repo.lock().await.get_admins()?;
+    Ok(Json(admins))
+}
+
+async fn get_relays(State(state): State<AppState>) -> Result<Json<Vec<RelayScore>>, ApiError> {
+    let relays = state.repo.lock().await.get_relay_scores()?;
+    Ok(Json(relays))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleBody {
+    role: Role,
+}
+
+async fn put_admin(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(pubkey): Path<String>,
+    Json(payload): Json<RoleBody>,
+) -> Result<Json<Admin>, ApiError> {
+    check_hex_key(&pubkey)?;
+
+    match state
+        .repo
+        .lock()
+        .await
+        .set_role(&pubkey, payload.role, role)?
+    {
+        true => Ok(Json(Admin {
+            pubkey,
+            role: payload.role,
+        })),
+        false => Err(ApiError::forbidden()),
+    }
+}
+
+async fn delete_admin(
+    State(state): State<AppState>,
+    Extension(role): Extension<Role>,
+    Path(pubkey): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_hex_key(&pubkey)?;
+
+    let repo = state.repo.lock().await;
+    let current = match repo.get_role(&pubkey)? {
+        Some(current) => current,
+        None => return Err(ApiError::not_found()),
+    };
+    match repo.remove_role(&pubkey, current, role)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::forbidden()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InvoiceRequest {
+    pubkey: String,
+    days: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct InvoiceStatus {
+    paid: bool,
+    account: Option<Account>,
+}
+
+fn payments(state: &AppState) -> Result<&Arc<Payments>, ApiError> {
+    state
+        .payments
+        .as_ref()
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Paid admission is not enabled"))
+}
+
+async fn create_invoice(
+    State(state): State<AppState>,
+    Json(payload): Json<InvoiceRequest>,
+) -> Result<Json<PendingInvoice>, ApiError> {
+    let payments = payments(&state)?;
+    check_hex_key(&payload.pubkey)?;
+
+    let days = payload.days.unwrap_or(DEFAULT_INVOICE_DAYS);
+    if days == 0 || days > MAX_INVOICE_DAYS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            &format!("days must be between 1 and {MAX_INVOICE_DAYS}"),
+        ));
+    }
+    if state.repo.lock().await.is_denied(&payload.pubkey)? {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Pubkey is denied"));
+    }
+
+    match payments.create_invoice(&payload.pubkey, days).await {
+        Ok(invoice) => Ok(Json(invoice)),
+        Err(Error::TooManyInvoices) => Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many invoices, try again later",
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn get_invoice(
+    State(state): State<AppState>,
+    Path(payment_hash): Path<String>,
+) -> Result<Json<InvoiceStatus>, ApiError> {
+    let payments = payments(&state)?;
+    check_hex_key(&payment_hash)?;
+
+    match payments.check_invoice(&payment_hash).await {
+        Ok(account) => Ok(Json(InvoiceStatus {
+            paid: account.is_some(),
+            account,
+        })),
+        Err(Error::NotFound) => Err(ApiError::not_found()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn get_openapi() -> Json<Value> {
+    Json(openapi())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+/// A route served by `start_server`, used to build both the router and the OpenAPI document
+struct Operation {
+    path: &'static str,
+    method: &'static str,
+    handler: fn() -> MethodRouter<AppState>,
+    summary: &'static str,
+    path_param: Option<&'static str>,
+    query: bool,
+    request: Option<&'static str>,
+    response: Option<&'static str>,
+    /// Served without authentication
+    public: bool,
+}
+
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        path: "/update",
+        method: "post",
+        handler: || post(update_users),
+        summary: "Allow and deny lists of pubkeys",
+        path_param: None,
+        query: false,
+        request: Some("Users"),
+        response: None,
+        public: false,
+    },
+    Operation {
+        path: "/users",
+        method: "get",
+        handler: || get(get_users),
+        summary: "List accounts, paginated by pubkey",
+        path_param: None,
+        query: true,
+        request: None,
+        response: Some("UsersPage"),
+        public: false,
+    },
+    Operation {
+        path: "/users/{pubkey}",
+        method: "get",
+        handler: || get(get_user),
+        summary: "Get an account",
+        path_param: Some("pubkey"),
+        query: false,
+        request: None,
+        response: Some("Account"),
+        public: false,
+    },
+    Operation {
+        path: "/users/{pubkey}",
+        method: "put",
+        handler: || put(put_user),
+        summary: "Set the status of an account",
+        path_param: Some("pubkey"),
+        query: false,
+        request: Some("StatusBody"),
+        response: Some("Account"),
+        public: false,
+    },
+    Operation {
+        path: "/users/{pubkey}",
+        method: "delete",
+        handler: || delete(delete_user),
+        summary: "Remove an account",
+        path_param: Some("pubkey"),
+        query: false,
+        request: None,
+        response: None,
+        public: false,
+    },
+    Operation {
+        path: "/events/{id}",
+        method: "get",
+        handler: || get(get_event),
+        summary: "Get an event admission entry",
+        path_param: Some("id"),
+        query: false,
+        request: None,
+        response: Some("Event"),
+        public: false,
+    },
+    Operation {
+        path: "/events/{id}",
+        method: "put",
+        handler: || put(put_event),
+        summary: "Set the status of an event",
+        path_param: Some("id"),
+        query: false,
+        request: Some("StatusBody"),
+        response: Some("Event"),
+        public: false,
+    },
+    Operation {
+        path: "/events/{id}",
+        method: "delete",
+        handler: || delete(delete_event),
+        summary: "Remove an event admission entry",
+        path_param: Some("id"),
+        query: false,
+        request: None,
+        response: None,
+        public: false,
+    },
+    Operation {
+        path: "/admins",
+        method: "get",
+        handler: || get(get_admins),
+        summary: "List keys with a role",
+        path_param: None,
+        query: false,
+        request: None,
+        response: Some("Admins"),
+        public: false,
+    },
+    Operation {
+        path: "/admins/{pubkey}",
+        method: "put",
+        handler: || put(put_admin),
+        summary: "Give a key a role below the caller's role",
+        path_param: Some("pubkey"),
+        query: false,
+        request: Some("RoleBody"),
+        response: Some("Admin"),
+        public: false,
+    },
+    Operation {
+        path: "/admins/{pubkey}",
+        method: "delete",
+        handler: || delete(delete_admin),
+        summary: "Remove the role of a key below the caller's role",
+        path_param: Some("pubkey"),
+        query: false,
+        request: None,
+        response: None,
+        public: false,
+    },
+    Operation {
+        path: "/relays",
+        method: "get",
+        handler: || get(get_relays),
+        summary: "Scores of relays events are fetched from, best first",
+        path_param: None,
+        query: false,
+        request: None,
+        response: Some("RelayScores"),
+        public: false,
+    },
+    Operation {
+        path: "/invoices",
+        method: "post",
+        handler: || post(create_invoice),
+        summary: "Create an invoice admitting a pubkey for a number of days",
+        path_param: None,
+        query: false,
+        request: Some("InvoiceRequest"),
+        response: Some("Invoice"),
+        public: true,
+    },
+    Operation {
+        path: "/invoices/{payment_hash}",
+        method: "get",
+        handler: || get(get_invoice),
+        summary: "Check an invoice, the pubkey is admitted once it is paid",
+        path_param: Some("payment_hash"),
+        query: false,
+        request: None,
+        response: Some("InvoiceStatus"),
+        public: true,
+    },
+    Operation {
+        path: "/metrics",
+        method: "get",
+        handler: || get(get_metrics),
+        summary: "Prometheus metrics",
+        path_param: None,
+        query: false,
+        request: None,
+        response: None,
+        public: true,
+    },
+    Operation {
+        path: "/openapi.json",
+        method: "get",
+        handler: || get(get_openapi),
+        summary: "This OpenAPI document",
+        path_param: None,
+        query: false,
+        request: None,
+        response: None,
+        public: true,
+    },
+];
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+/// Builds the OpenAPI document for the HTTP API
+pub fn openapi() -> Value {
+    let mut paths = serde_json::Map::new();
+
+    for op in OPERATIONS {
+        let mut parameters = Vec::new();
+        if let Some(name) = op.path_param {
+            parameters.push(json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string", "pattern": "^[0-9a-f]{64}$" }
+            }));
+        }
+        if op.query {
+            parameters
+                .push(json!({ "name": "status", "in": "query", "schema": schema_ref("Status") }));
+            parameters
+                .push(json!({ "name": "cursor", "in": "query", "schema": { "type": "string" } }));
+            parameters.push(json!({
+                "name": "limit",
+                "in": "query",
+                "schema": { "type": "integer", "default": DEFAULT_PAGE_LIMIT, "maximum": MAX_PAGE_LIMIT }
+            }));
+        }
+
+        let success = match (op.method, op.response) {
+            (_, Some(schema)) => json!({
+                "200": {
+                    "description": "Ok",
+                    "content": { "application/json": { "schema": schema_ref(schema) } }
+                }
+            }),
+            ("delete", None) => json!({ "204": { "description": "Removed" } }),
+            (_, None) => json!({ "200": { "description": "Ok" } }),
+        };
+        let mut responses = success.as_object().cloned().unwrap_or_default();
+        for (code, description) in [
+            ("400", "Bad request"),
+            ("401", "Unauthorized"),
+            ("403", "Forbidden"),
+            ("404", "Not found"),
+            ("413", "Body too large"),
+            ("429", "Too many requests"),
+            ("500", "Internal error"),
+        ] {
+            responses.insert(
+                code.to_string(),
+                json!({
+                    "description": description,
+                    "content": { "application/json": { "schema": schema_ref("Error") } }
+                }),
+            );
+        }
+
+        let security = match op.public {
+            true => json!([]),
+            false => json!([{ "nip98": [] }, { "apiKey": [] }]),
+        };
+        let mut operation = json!({
+            "summary": op.summary,
+            "security": security,
+            "parameters": parameters,
+            "responses": responses,
+        });
+        if let Some(schema) = op.request {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_ref(schema) } }
+            });
+        }
+
+        let item = paths
+            .entry(op.path.to_string())
+            .or_insert_with(|| json!({}));
+        item[op.method] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "my-local-relay", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "nip98": { "type": "http", "scheme": "Nostr", "description": "NIP-98 HTTP Auth signed by a key with a role" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" }
+            },
+            "schemas": {
+                "Status": { "type": "string", "enum": ["allow", "deny"] },
+                "StatusBody": {
+                    "type": "object",
+                    "required": ["status"],
+                    "properties": { "status": schema_ref("Status") }
+                },
+                "Role": { "type": "string", "enum": ["moderator", "admin", "owner"] },
+                "RoleBody": {
+                    "type": "object",
+                    "required": ["role"],
+                    "properties": { "role": schema_ref("Role") }
+                },
+                "Admin": {
+                    "type": "object",
+                    "properties": { "pubkey": { "type": "string" }, "role": schema_ref("Role") }
+                },
+                "Admins": { "type": "array", "items": schema_ref("Admin") },
+                "RelayScore": {
+                    "type": "object",
+                    "properties": {
+                        "url": { "type": "string" },
+                        "requests": { "type": "integer" },
+                        "failures": { "type": "integer" },
+                        "latency_ms": { "type": "integer" },
+                        "lookups": { "type": "integer" },
+                        "hits": { "type": "integer" },
+                        "invalid": { "type": "integer" },
+                        "consecutive_failures": { "type": "integer" },
+                        "disabled_until": { "type": "integer", "nullable": true },
+                        "score": { "type": "number" },
+                        "success_rate": { "type": "number" },
+                        "hit_rate": { "type": "number" },
+                        "average_latency_ms": { "type": "integer", "nullable": true },
+                        "disabled": { "type": "boolean" }
+                    }
+                },
+                "RelayScores": { "type": "array", "items": schema_ref("RelayScore") },
+                "Account": {
+                    "type": "object",
+                    "properties": {
+                        "pubkey": { "type": "string" },
+                        "status": schema_ref("Status"),
+                        "expires_at": { "type": "integer", "nullable": true }
+                    }
+                },
+                "Event": {
+                    "type": "object",
+                    "properties": { "id": { "type": "string" }, "status": schema_ref("Status") }
+                },
+                "Users": {
+                    "type": "object",
+                    "properties": {
+                        "allow": { "type": "array", "items": { "type": "string" } },
+                        "deny": { "type": "array", "items": { "type": "string" } }
+                    }
+                },
+                "UsersPage": {
+                    "type": "object",
+                    "properties": {
+                        "users": { "type": "array", "items": schema_ref("Account") },
+                        "next_cursor": { "type": "string", "nullable": true }
+                    }
+                },
+                "InvoiceRequest": {
+                    "type": "object",
+                    "required": ["pubkey"],
+                    "properties": {
+                        "pubkey": { "type": "string" },
+                        "days": { "type": "integer", "default": DEFAULT_INVOICE_DAYS, "maximum": MAX_INVOICE_DAYS }
+                    }
+                },
+                "Invoice": {
+                    "type": "object",
+                    "properties": {
+                        "payment_hash": { "type": "string" },
+                        "pubkey": { "type": "string" },
+                        "amount_msat": { "type": "integer" },
+                        "bolt11": { "type": "string" },
+                        "created_at": { "type": "integer" },
+                        "paid": { "type": "boolean" }
+                    }
+                },
+                "InvoiceStatus": {
+                    "type": "object",
+                    "properties": {
+                        "paid": { "type": "boolean" },
+                        "account": { "allOf": [schema_ref("Account")], "nullable": true }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use nostr_sdk::prelude::{sha256, EventBuilder, Hash, Keys, Kind, Tag};
+    use serial_test::serial;
+    use tower::ServiceExt;
+
+    use crate::config::Settings;
+    use crate::relays::RelayStats;
+    use crate::reload::LiveConfig;
+
+    use super::*;
+
+    const API_KEY: &str = "secret";
+    const PUBKEY: &str = "9dc4e4790da6e1f00285c493ba491bfda3c3cba0c4511ac60ddadd6e74cdc31c";
+
+    fn app(repo: Repo) -> Router {
+        let mut settings = Settings::default();
+        settings.info.api_key = Some(API_KEY.to_string());
+        settings.info.api_url = Some("http://localhost:3000".to_string());
+        router(AppState {
+            config: SharedConfig::new(LiveConfig::new(settings).unwrap()),
+            repo: Arc::new(Mutex::new(repo)),
+            payments: None,
+        })
+    }
+
+    fn request(method: &str, path: &str, body: Option<Value>) -> axum::http::request::Builder {
+        let builder = Request::builder().method(method).uri(path);
+        match body {
+            Some(_) => builder.header(header::CONTENT_TYPE, "application/json"),
+            None => builder,
+        }
+    }
+
+    fn body(body: Option<Value>) -> Body {
+        match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        }
+    }
+
+    /// Sends a request authenticated with the api key
+    async fn send(
+        app: &Router,
+        method: &str,
+        path: &str,
+        json: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let req = request(method, path, json.clone())
+            .header("X-Api-Key", API_KEY)
+            .body(body(json))
+            .unwrap();
+        response(app, req).await
+    }
+
+    /// Sends a request with NIP-98 auth signed by `keys`
+    async fn send_signed(
+        app: &Router,
+        keys: &Keys,
+        method: &str,
+        path: &str,
+        json: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let url = format!("http://localhost:3000{path}");
+        let mut tags = vec![
+            Tag::parse(vec!["u", &url]).unwrap(),
+            Tag::parse(vec!["method", method]).unwrap(),
+        ];
+        if let Some(json) = &json {
+            let hash = sha256::Hash::hash(json.to_string().as_bytes());
+            tags.push(Tag::parse(vec!["payload".to_string(), hash.to_string()]).unwrap());
+        }
+        let event = EventBuilder::new(Kind::from(nip98::HTTP_AUTH_KIND), "", &tags)
+            .to_event(keys)
+            .unwrap();
+        let req = request(method, path, json.clone())
+            .header(
+                header::AUTHORIZATION,
+                format!("Nostr {}", base64::encode(event.as_json())),
+            )
+            .body(body(json))
+            .unwrap();
+        response(app, req).await
+    }
+
+    async fn response(app: &Router, req: Request<Body>) -> (StatusCode, Value) {
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, value)
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_operations_routed_and_documented() {
+        let app = app(Repo::new());
+        let doc = openapi();
+
+        for op in OPERATIONS {
+            assert!(
+                doc["paths"][op.path][op.method].is_object(),
+                "{} {} is not documented",
+                op.method,
+                op.path
+            );
+
+            let path = op
+                .path
+                .replace("{pubkey}", PUBKEY)
+                .replace("{id}", PUBKEY)
+                .replace("{payment_hash}", PUBKEY);
+            let req = Request::builder()
+                .method(op.method.to_uppercase().as_str())
+                .uri(&path)
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            let status = res.status();
+            assert_ne!(
+                status,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {path}",
+                op.method
+            );
+            match op.public {
+                true => assert_ne!(status, StatusCode::UNAUTHORIZED, "{} {path}", op.method),
+                false => assert_eq!(status, StatusCode::UNAUTHORIZED, "{} {path}", op.method),
+            }
+            // The router's own 404 has no body, handlers always explain theirs
+            if status == StatusCode::NOT_FOUND {
+                assert!(!res.into_body().is_end_stream(), "{} {path}", op.method);
+            }
+        }
+    }
+
+    /// Checks the documented properties of `schema` are the fields of `value`
+    fn assert_documented(doc: &Value, schema: &str, value: impl Serialize) {
+        let value = serde_json::to_value(value).unwrap();
+        let mut documented: Vec<&String> = doc["components"]["schemas"][schema]["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("{schema} is not documented"))
+            .keys()
+            .collect();
+        let mut fields: Vec<&String> = value.as_object().unwrap().keys().collect();
+        documented.sort();
+        fields.sort();
+        assert_eq!(documented, fields, "{schema}");
+    }
+
+    #[test]
+    fn test_schemas_match_responses() {
+        let doc = openapi();
+        let account = || Account {
+            pubkey: PUBKEY.to_string(),
+            status: Status::Allow,
+            expires_at: Some(1),
+        };
+        assert_documented(&doc, "Account", account());
+        assert_documented(
+            &doc,
+            "Admin",
+            Admin {
+                pubkey: PUBKEY.to_string(),
+                role: Role::Admin,
+            },
+        );
+        assert_documented(
+            &doc,
+            "Event",
+            db::Event {
+                id: PUBKEY.to_string(),
+                status: Status::Deny,
+            },
+        );
+        assert_documented(
+            &doc,
+            "RelayScore",
+            RelayScore::new(RelayStats::new("wss://relay.example.com"), 0),
+        );
+        assert_documented(
+            &doc,
+            "Users",
+            Users {
+                allow: Some(vec![]),
+                deny: Some(vec![]),
+            },
+        );
+        assert_documented(
+            &doc,
+            "UsersPage",
+            UsersPage {
+                users: vec![account()],
+                next_cursor: None,
+            },
+        );
+        assert_documented(
+            &doc,
+            "Invoice",
+            PendingInvoice {
+                payment_hash: PUBKEY.to_string(),
+                pubkey: PUBKEY.to_string(),
+                amount_msat: 1000,
+                bolt11: "lnbc".to_string(),
+                created_at: 0,
+                paid: false,
+            },
+        );
+        assert_documented(
+            &doc,
+            "InvoiceStatus",
+            InvoiceStatus {
+                paid: true,
+                account: Some(account()),
+            },
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_user_endpoints() {
+        let repo = Repo::new();
+        repo.remove_account(PUBKEY).unwrap();
+        let app = app(repo);
+
+        let path = format!("/users/{PUBKEY}");
+        let (status, _) = send(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, account) = send(&app, "PUT", &path, Some(json!({ "status": "allow" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account["pubkey"], PUBKEY);
+        assert_eq!(account["status"], "allow");
+
+        let (status, account) = send(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account["status"], "allow");
+
+        let (status, page) = send(&app, "GET", "/users?status=allow&limit=1000", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(page["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|user| user["pubkey"] == PUBKEY));
+
+        let (status, _) = send(&app, "DELETE", &path, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "DELETE", &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, error) = send(&app, "GET", "/users/npub", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].is_string());
+        assert_documented(&openapi(), "Error", error);
+
+        // Every pubkey of a bulk update is checked before any is stored
+        for users in [
+            json!({ "allow": [PUBKEY.to_uppercase()] }),
+            json!({ "allow": [PUBKEY], "deny": ["garbage"] }),
+        ] {
+            let (status, error) = send(&app, "POST", "/update", Some(users)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(error["error"], "Expected 32-bytes lowercase hex");
+        }
+        let (status, _) = send(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let req = request("GET", &path, None)
+            .header("X-Api-Key", "wrong")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = response(&app, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_event_endpoints() {
+        let repo = Repo::new();
+        repo.remove_event(PUBKEY).unwrap();
+        let app = app(repo);
+
+        let path = format!("/events/{PUBKEY}");
+        let (status, event) = send(&app, "PUT", &path, Some(json!({ "status": "deny" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(event, json!({ "id": PUBKEY, "status": "deny" }));
+
+        let (status, event) = send(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(event["status"], "deny");
+
+        let (status, _) = send(&app, "DELETE", &path, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_nip98_roles() {
+        let repo = Repo::new();
+        let moderator = Keys::generate();
+        let unknown = Keys::generate();
+        let target = Keys::generate().public_key().to_string();
+        let admin = Keys::generate().public_key().to_string();
+        repo.set_role(
+            &moderator.public_key().to_string(),
+            Role::Moderator,
+            Role::Owner,
+        )
+        .unwrap();
+        repo.set_role(&admin, Role::Admin, Role::Owner).unwrap();
+        let app = app(repo);
+
+        let path = format!("/users/{target}");
+        let (status, _) = send_signed(&app, &unknown, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Moderators can deny but not allow
+        let (status, account) = send_signed(
+            &app,
+            &moderator,
+            "PUT",
+            &path,
+            Some(json!({ "status": "deny" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account["status"], "deny");
+        let (status, _) = send_signed(
+            &app,
+            &moderator,
+            "PUT",
+            &path,
+            Some(json!({ "status": "allow" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Or deny keys with a role
+        let (status, _) = send_signed(
+            &app,
+            &moderator,
+            "PUT",
+            &format!("/users/{admin}"),
+            Some(json!({ "status": "deny" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // And can't give out roles
+        let (status, _) = send_signed(
+            &app,
+            &moderator,
+            "PUT",
+            &format!("/admins/{target}"),
+            Some(json!({ "role": "moderator" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, admins) = send_signed(&app, &moderator, "GET", "/admins", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(admins
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|admin| admin["pubkey"] == moderator.public_key().to_string()));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_body_limit() {
+        let app = app(Repo::new());
+        let large = vec![b' '; MAX_BODY_SIZE + 1];
+
+        // Rejected before the auth header is checked
+        let req = request("POST", "/update", None)
+            .header(header::AUTHORIZATION, "Nostr invalid")
+            .body(Body::from(large.clone()))
+            .unwrap();
+        let (status, _) = response(&app, req).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = request("POST", "/invoices", Some(Value::Null))
+            .body(Body::from(large))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_public_endpoints() {
+        let app = app(Repo::new());
+
+        let req = request("GET", "/openapi.json", None)
+            .body(Body::empty())
+            .unwrap();
+        let (status, doc) = response(&app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(doc, openapi());
+
+        let req = request("GET", "/metrics", None)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Paid admission is not configured
+        let req = request("POST", "/invoices", Some(json!({ "pubkey": PUBKEY })))
+            .body(body(Some(json!({ "pubkey": PUBKEY }))))
+            .unwrap();
+        let (status, _) = response(&app, req).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,202 @@
+//! Minimal bech32 and BOLT11 invoice decoding, only what is needed to validate zap receipts
+
+const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// 5 bit groups of the signature and recovery id at the end of the data
+const SIGNATURE_LEN: usize = 104;
+/// 5 bit groups of the timestamp at the start of the data
+const TIMESTAMP_LEN: usize = 7;
+/// Tagged field type of the description hash
+const DESCRIPTION_HASH: u8 = 23;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invoice {
+    /// Amount in millisats, `None` if the invoice is for any amount
+    pub amount_msat: Option<u64>,
+    /// Sha256 hash of the description
+    pub description_hash: Option<[u8; 32]>,
+}
+
+impl Invoice {
+    /// Decodes a bolt11 invoice, checking the bech32 checksum
+    pub fn decode(invoice: &str) -> Option<Self> {
+        let invoice = invoice.trim().to_lowercase();
+        let invoice = invoice.strip_prefix("lightning:").unwrap_or(&invoice);
+        let (hrp, data) = decode_bech32(invoice)?;
+        if !hrp.starts_with("ln") {
+            return None;
+        }
+
+        let data = data.as_slice();
+        if data.len() < TIMESTAMP_LEN + SIGNATURE_LEN {
+            return None;
+        }
+
+        let mut description_hash = None;
+        let mut fields = &data[TIMESTAMP_LEN..data.len() - SIGNATURE_LEN];
+        while fields.len() >= 3 {
+            let kind = fields[0];
+            let len = fields[1] as usize * 32 + fields[2] as usize;
+            let value = fields.get(3..3 + len)?;
+            if kind == DESCRIPTION_HASH && len == 52 {
+                description_hash = to_bytes(value).try_into().ok();
+            }
+            fields = &fields[3 + len..];
+        }
+
+        Some(Self {
+            amount_msat: parse_amount(hrp)?,
+            description_hash,
+        })
+    }
+}
+
+/// Splits bech32 into its human readable part and 5 bit groups of data,
+/// checking and removing the checksum
+fn decode_bech32(value: &str) -> Option<(&str, Vec<u8>)> {
+    let (hrp, data) = value.rsplit_once('1')?;
+    let mut data = data
+        .chars()
+        .map(|c| CHARSET.find(c).map(|i| i as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    if !verify_checksum(hrp, &data) {
+        return None;
+    }
+    data.truncate(data.len() - 6);
+    Some((hrp, data))
+}
+
+/// Parses the amount of the human readable part, e.g. `lnbc2500u`.
+/// Returns `Some(None)` for invoices without an amount.
+fn parse_amount(hrp: &str) -> Option<Option<u64>> {
+    let amount = hrp.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    if amount.is_empty() {
+        return Some(None);
+    }
+
+    let (digits, multiplier) = match amount.chars().last()? {
+        c if c.is_ascii_digit() => (amount, None),
+        c => (&amount[..amount.len() - 1], Some(c)),
+    };
+    let value: u64 = digits.parse().ok()?;
+
+    // Millisats in one unit of the multiplier, picos are a tenth of a millisat
+    let msat = match multiplier {
+        None => value.checked_mul(100_000_000_000)?,
+        Some('m') => value.checked_mul(100_000_000)?,
+        Some('u') => value.checked_mul(100_000)?,
+        Some('n') => value.checked_mul(100)?,
+        Some('p') if value.is_multiple_of(10) => value / 10,
+        _ => return None,
+    };
+    Some(Some(msat))
+}
+
+/// Converts 5 bit groups to bytes, dropping the padding
+fn to_bytes(groups: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(groups.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for group in groups {
+        acc = (acc << 5) | *group as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    bytes
+}
+
+fn polymod(values: impl Iterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn verify_checksum(hrp: &str, data: &[u8]) -> bool {
+    if data.len() < 6 {
+        return false;
+    }
+    let expanded = hrp
+        .bytes()
+        .map(|b| b >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31));
+    polymod(expanded.chain(data.iter().copied())) == 1
+}
+
+/// Encodes an unsigned invoice with an amount and description hash, for tests
+#[cfg(test)]
+pub fn encode_unsigned(hrp: &str, description_hash: &[u8; 32]) -> String {
+    let mut data = vec![0; TIMESTAMP_LEN];
+    data.extend_from_slice(&[DESCRIPTION_HASH, 1, 20]);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for byte in description_hash {
+        acc = (acc << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            data.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    data.push(((acc << (5 - bits)) & 31) as u8);
+    data.extend_from_slice(&[0; SIGNATURE_LEN]);
+
+    let expanded: Vec<u8> = hrp
+        .bytes()
+        .map(|b| b >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31))
+        .collect();
+    let checksum = polymod(
+        expanded
+            .into_iter()
+            .chain(data.iter().copied())
+            .chain([0; 6]),
+    ) ^ 1;
+    data.extend((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+
+    let chars: Vec<char> = CHARSET.chars().collect();
+    let data: String = data.iter().map(|g| chars[*g as usize]).collect();
+    format!("{hrp}1{data}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_invoice() {
+        // Description hash example from BOLT11
+        let invoice = "lnbc20m1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqs9qrsgq7ea976txfraylvgzuxs8kgcw23ezlrszfnh8r6qtfpr6cxga50aj6txm9rxrydzd06dfeawfk6swupvz4erwnyutnjq7x39ymw6j38gp7ynn44";
+        let decoded = Invoice::decode(invoice).unwrap();
+        assert_eq!(decoded.amount_msat, Some(2_000_000_000));
+        assert_eq!(
+            hex::encode(decoded.description_hash.unwrap()),
+            "3925b6f67e2c340036ed12093dd44e0368df1b6ea26c53dbe4811f58fd5db8c1"
+        );
+
+        let hash = [7; 32];
+        let encoded = Invoice::decode(&encode_unsigned("lnbc10u", &hash)).unwrap();
+        assert_eq!(encoded.amount_msat, Some(1_000_000));
+        assert_eq!(encoded.description_hash, Some(hash));
+
+        // Corrupted checksum
+        assert!(Invoice::decode(&invoice.replace("7ynn44", "7ynn45")).is_none());
+
+        assert_eq!(parse_amount("lnbc2500u"), Some(Some(250_000_000)));
+        assert_eq!(parse_amount("lnbc10p"), Some(Some(1)));
+        assert_eq!(parse_amount("lnbc"), Some(None));
+        assert_eq!(parse_amount("lnbc1x"), None);
+    }
+}
@@ -1,9 +1,11 @@
 use nostr_sdk::event::tag::Tag;
 use nostr_sdk::prelude::schnorr::Signature;
 use nostr_sdk::prelude::*;
-use tungstenite::Message as WsMessage;
 
-use tracing::debug;
+use futures_util::future::join_all;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
 
 use crate::nauthz_grpc::event::TagEntry;
 
@@ -12,10 +14,20 @@ use crate::error::Error;
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::metrics::{Metrics, METRICS};
+use crate::relays::RelayFetch;
+use crate::sync::RelaySocket;
 use crate::{nauthz_grpc, utils};
 
+/// How long the home relay is waited for to acknowledge broadcast events
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the home relay is waited for to return stored events
+const HOME_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Relays asked at once for referenced events, the next are asked for those not found
+const FETCH_ROUND_SIZE: usize = 2;
+
 #[derive(Clone)]
 pub struct NostrClient {
     /// Nostr-sdk Client
@@ -24,75 +36,301 @@ pub struct NostrClient {
     pub relays: HashSet<Url>,
 }
 
+/// Events fetched and how each relay did
+#[derive(Debug, Default)]
+pub struct Fetched {
+    pub events: Vec<Event>,
+    pub relays: Vec<RelayFetch>,
+}
+
 impl NostrClient {
-    pub async fn new(relays: &HashSet<Url>) -> Result<Self, Error> {
+    pub async fn new(relays: &HashSet<Url>, keys: &Keys) -> Result<Self, Error> {
         debug!("Client Relays: {:?}", relays);
+        let client = utils::create_client(
+            Some(keys),
+            relays.iter().map(|r| r.to_string()).collect(),
+            0,
+        )
+        .await
+        .unwrap();
+
+        // Authenticate to relays that require NIP-42 to read
+        tokio::spawn(handle_auth(client.clone()));
+
         Ok(Self {
             relays: relays.to_owned(),
-            client: utils::create_client(None, relays.iter().map(|r| r.to_string()).collect(), 0)
-                .await
-                .unwrap(),
+            client,
         })
     }
 
+    /// Replaces the default relays, connecting to added relays
+    pub async fn set_relays(&mut self, relays: &HashSet<Url>) -> Result<(), Error> {
+        for relay in self.relays.difference(relays) {
+            debug!("Removing relay {}", relay);
+            self.client
+                .remove_relay(relay.to_string())
+                .await
+                .map_err(|_| Error::RelayError)?;
+        }
+        for relay in relays.difference(&self.relays) {
+            debug!("Adding relay {}", relay);
+            self.client
+                .add_relay(relay.to_string(), None)
+                .await
+                .map_err(|_| Error::RelayError)?;
+        }
+        self.client.connect().await;
+        self.relays = relays.to_owned();
+        Ok(())
+    }
+
+    /// Fetches events by id from `relays`, the ranked default relays and relays recommended
+    /// in `e` tags. Relays are asked in order, a few at a time, for the events not yet found.
     pub async fn fetch_events(
         &self,
         events: &HashMap<EventId, Option<String>>,
-    ) -> Result<Vec<Event>> {
-        let event_relays: HashSet<Url> = events
-            .values()
-            .flatten()
-            .filter(|r| !self.relays.contains(&Url::from_str(r).unwrap()))
-            .flat_map(|r| Url::from_str(r))
+        relays: &[Url],
+    ) -> Result<Fetched> {
+        // Connect to the relays recommended in `e` tags for the fetch
+        let added: Vec<&Url> = relays
+            .iter()
+            .filter(|relay| !self.relays.contains(relay))
             .collect();
+        for relay in &added {
+            if self.client.add_relay(relay.to_string(), None).await.is_ok() {
+                self.client.connect_relay(relay.to_string()).await.ok();
+            }
+        }
 
-        // Add the relays recommend in the `e` tag
-        self.client
-            .add_relays(event_relays.iter().map(|r| (r.to_string(), None)).collect())
-            .await
-            .unwrap();
+        let mut missing: HashSet<EventId> = events.keys().copied().collect();
+        let mut fetched = Fetched::default();
+        for round in relays.chunks(FETCH_ROUND_SIZE) {
+            if missing.is_empty() {
+                break;
+            }
+            let ids = missing.clone();
+            let filter =
+                Filter::new().ids(ids.iter().map(|id| id.to_hex()).collect::<Vec<String>>());
+            let round = self
+                .fetch_verified(
+                    round,
+                    vec![filter],
+                    Duration::from_secs(10),
+                    ids.len() as u64,
+                    |event| ids.contains(&event.id),
+                )
+                .await;
+            for event in &round.events {
+                missing.remove(&event.id);
+            }
+            fetched.events.extend(round.events);
+            fetched.relays.extend(round.relays);
+        }
 
-        // debug!("Fetching relays: {:?}", self.client.relays().await);
+        for relay in added {
+            self.client.remove_relay(relay.to_string()).await.ok();
+        }
+
+        Ok(fetched)
+    }
+
+    /// Fetches past events of authors from `relays`
+    pub async fn fetch_authors_events(
+        &self,
+        authors: &[String],
+        relays: &[Url],
+    ) -> Result<Fetched> {
+        let authors: Vec<XOnlyPublicKey> = authors
+            .iter()
+            .flat_map(|a| XOnlyPublicKey::from_str(a))
+            .collect();
+
+        Ok(self
+            .fetch_verified(
+                relays,
+                vec![Filter::new().authors(authors.clone())],
+                Duration::from_secs(30),
+                0,
+                |event| authors.contains(&event.pubkey),
+            )
+            .await)
+    }
+
+    /// Fetches events from each of `relays` at once, keeping the events that are valid
+    /// and were requested. Relays that return other events are logged.
+    async fn fetch_verified(
+        &self,
+        relays: &[Url],
+        filters: Vec<Filter>,
+        timeout: Duration,
+        requested_ids: u64,
+        requested: impl Fn(&Event) -> bool,
+    ) -> Fetched {
+        let pool = self.client.relays().await;
+        let fetches = relays
+            .iter()
+            .filter_map(|url| Some((url.clone(), pool.get(url)?.clone())))
+            .map(|(url, relay)| {
+                let filters = filters.clone();
+                async move {
+                    // Events received before a timeout are kept
+                    let started = Instant::now();
+                    let events = Mutex::new(Vec::new());
+                    let ok = match relay
+                        .get_events_of_with_callback(filters, Some(timeout), |event| async {
+                            events.lock().await.push(event);
+                        })
+                        .await
+                    {
+                        Ok(()) => true,
+                        Err(err) => {
+                            debug!("Could not fetch events from {url}: {err}");
+                            false
+                        }
+                    };
+                    (url, ok, started.elapsed(), events.into_inner())
+                }
+            });
+
+        let mut fetched = Fetched::default();
+        let mut verified: HashMap<EventId, Event> = HashMap::new();
+        for (url, ok, latency, events) in join_all(fetches).await {
+            let (mut found, mut invalid) = (0, 0);
+            for event in events {
+                let valid = match requested(&event) {
+                    true => utils::verify_event(&event),
+                    false => Err("not requested"),
+                };
+                match valid {
+                    Ok(()) => {
+                        found += 1;
+                        verified.insert(event.id, event);
+                    }
+                    Err(reason) => {
+                        debug!("Dropping event {} from {url}: {reason}", event.id);
+                        invalid += 1;
+                    }
+                }
+            }
+
+            if invalid > 0 {
+                Metrics::add(&METRICS.events_invalid, invalid);
+                warn!("{url} returned {invalid} invalid events");
+            }
+            fetched.relays.push(RelayFetch {
+                url,
+                ok,
+                latency,
+                requested: requested_ids,
+                found,
+                invalid,
+            });
+        }
+        fetched.events = verified.into_values().collect();
+        fetched
+    }
+
+    /// Fetches zap receipts to `recipients` since a unix time from the default relays
+    pub async fn fetch_zap_receipts(
+        &self,
+        recipients: &[String],
+        since: u64,
+    ) -> Result<Vec<Event>, Error> {
+        let recipients: Vec<XOnlyPublicKey> = recipients
+            .iter()
+            .flat_map(|p| XOnlyPublicKey::from_str(p))
+            .collect();
+        if recipients.is_empty() {
+            return Ok(vec![]);
+        }
 
-        let events = self
-            .client
+        self.client
             .get_events_of(
-                vec![Filter::new().ids(events.keys().map(|k| k.to_hex()).collect::<Vec<String>>())],
-                Some(Duration::from_secs(10)),
+                vec![Filter::new()
+                    .kind(Kind::Zap)
+                    .pubkeys(recipients)
+                    .since(Timestamp::from(since))],
+                Some(Duration::from_secs(30)),
             )
             .await
-            .unwrap();
+            .map_err(|_| Error::FetchError)
+    }
 
-        // Remove the relays recommended by `e` tag
-        for r in event_relays {
-            self.client.remove_relay(r).await.unwrap();
-        }
+    /// Returns the ids the home relay already stores,
+    /// authenticating with the service key if the relay requires NIP-42 to read
+    pub async fn stored_on_home_relay(
+        &self,
+        relay: &str,
+        ids: &HashSet<EventId>,
+    ) -> Result<HashSet<EventId>, Error> {
+        let relay = Url::parse(relay).map_err(|_| Error::RelayError)?;
+        let mut socket = RelaySocket::connect(&relay, &self.client.keys()).await?;
+        let filter = Filter::new().ids(ids.iter().map(|id| id.to_hex()).collect::<Vec<String>>());
+        let events = tokio::time::timeout(HOME_QUERY_TIMEOUT, socket.query(filter))
+            .await
+            .map_err(|_| {
+                Error::SyncError(format!("Timed out querying {relay} for stored events"))
+            })??;
+        socket.close().await;
 
-        Ok(events)
+        Ok(events
+            .into_iter()
+            .map(|event| event.id)
+            .filter(|id| ids.contains(id))
+            .collect())
     }
 
+    /// Sends events to the home relay and waits for them to be acknowledged,
+    /// authenticating with the service key if the relay requires NIP-42
     pub async fn broadcast_events(
         &self,
         relay: &str,
         events: Arc<Vec<Event>>,
     ) -> Result<(), Error> {
-        // Connect to relay
-        let (mut socket, _) = tungstenite::connect(relay).expect("Can't connect to relay");
+        let relay = Url::parse(relay).map_err(|_| Error::RelayError)?;
+        let mut socket = RelaySocket::connect(&relay, &self.client.keys()).await?;
+        let unacknowledged = socket.publish(&events, BROADCAST_TIMEOUT).await?;
+        socket.close().await;
 
-        for event in events.as_ref().iter() {
-            // Send msg
-            let msg = ClientMessage::new_event(event.to_owned()).as_json();
-            socket
-                .write_message(WsMessage::Text(msg.clone()))
-                .expect("Impossible to send message");
-
-            debug!("Sent event: {}", msg);
+        if !unacknowledged.is_empty() {
+            warn!(
+                "{} events not acknowledged by {relay}",
+                unacknowledged.len()
+            );
         }
-
         Ok(())
     }
 }
 
+/// Answers NIP-42 `AUTH` challenges from relays with the client keys
+async fn handle_auth(client: Client) {
+    let keys = client.keys();
+    let mut notifications = client.notifications();
+
+    loop {
+        match notifications.recv().await {
+            Ok(RelayPoolNotification::Message(url, RelayMessage::Auth { challenge })) => {
+                debug!("Auth challenge from {url}");
+                let event = match EventBuilder::auth(challenge, url.clone()).to_event(&keys) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("Could not sign auth event: {err}");
+                        continue;
+                    }
+                };
+                if let Err(err) = client
+                    .send_msg_to(url.clone(), ClientMessage::new_auth(event))
+                    .await
+                {
+                    warn!("Could not authenticate to {url}: {err}");
+                }
+            }
+            Ok(RelayPoolNotification::Shutdown) | Err(RecvError::Closed) => break,
+            _ => continue,
+        }
+    }
+}
+
 impl From<&nauthz_grpc::Event> for nostr::Event {
     fn from(event: &nauthz_grpc::Event) -> nostr::Event {
         let id = EventId::from_slice(&event.id).unwrap();
@@ -121,3 +359,108 @@ impl From<TagEntry> for Tag {
         Tag::parse(tag.values).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stored_on_home_relay() {
+        let keys = Keys::generate();
+        let stored = EventBuilder::new_text_note("stored", &[])
+            .to_event(&keys)
+            .unwrap();
+        let missing = EventBuilder::new_text_note("missing", &[])
+            .to_event(&keys)
+            .unwrap();
+
+        // Relay that has one event and answers a single REQ
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = format!("ws://{}", listener.local_addr().unwrap());
+        let event = stored.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(WsMessage::Text(msg))) = socket.next().await {
+                if let Ok(ClientMessage::Req {
+                    subscription_id, ..
+                }) = ClientMessage::from_json(msg)
+                {
+                    for msg in [
+                        RelayMessage::new_event(subscription_id.clone(), event.clone()),
+                        RelayMessage::new_eose(subscription_id),
+                    ] {
+                        socket.send(WsMessage::Text(msg.as_json())).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let client = NostrClient::new(&HashSet::new(), &keys).await.unwrap();
+        let ids = HashSet::from([stored.id, missing.id]);
+        assert_eq!(
+            client.stored_on_home_relay(&relay, &ids).await.unwrap(),
+            HashSet::from([stored.id])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_events_with_auth() {
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..2)
+            .map(|i| {
+                EventBuilder::new_text_note(format!("note {i}"), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+
+        // Relay that refuses events until the service has authenticated
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = format!("ws://{}", listener.local_addr().unwrap());
+        let (stored_tx, mut stored) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let challenge = RelayMessage::new_auth("challenge");
+            socket
+                .send(WsMessage::Text(challenge.as_json()))
+                .await
+                .unwrap();
+            let mut authenticated = false;
+            while let Some(Ok(WsMessage::Text(msg))) = socket.next().await {
+                let reply = match ClientMessage::from_json(msg) {
+                    Ok(ClientMessage::Auth(auth)) => {
+                        authenticated = true;
+                        RelayMessage::new_ok(auth.id, true, "")
+                    }
+                    Ok(ClientMessage::Event(event)) if authenticated => {
+                        stored_tx.send(event.id).unwrap();
+                        RelayMessage::new_ok(event.id, true, "")
+                    }
+                    Ok(ClientMessage::Event(event)) => {
+                        RelayMessage::new_ok(event.id, false, "auth-required: sign in first")
+                    }
+                    _ => continue,
+                };
+                socket.send(WsMessage::Text(reply.as_json())).await.unwrap();
+            }
+        });
+
+        let client = NostrClient::new(&HashSet::new(), &keys).await.unwrap();
+        client
+            .broadcast_events(&relay, Arc::new(events.clone()))
+            .await
+            .unwrap();
+
+        let mut received = HashSet::new();
+        while let Ok(id) = stored.try_recv() {
+            received.insert(id);
+        }
+        assert_eq!(received, events.iter().map(|e| e.id).collect());
+    }
+}
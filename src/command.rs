@@ -0,0 +1,227 @@
+//! Admin commands sent as tags of kind 4242 events
+//!
+//! Each tag is a command name followed by its values, e.g. `["allow", <pubkey>, ...]`.
+//! After the tags are applied the service publishes a kind 4243 event to the home relay,
+//! tagged `e` to the command event, listing what was applied and what was rejected.
+
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+
+use crate::db::Role;
+use crate::utils;
+
+/// Kind of events carrying admin commands
+pub const COMMAND_KIND: u64 = 4242;
+/// Kind of the service's response to an admin command event
+pub const RESPONSE_KIND: u64 = 4243;
+/// Seconds a command's `created_at` can be from now for it to be applied,
+/// so signed commands cannot be replayed long after they were sent
+pub const COMMAND_WINDOW: u64 = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Admit pubkeys
+    Allow(Vec<String>),
+    /// Deny pubkeys
+    Deny(Vec<String>),
+    /// Remove pubkeys from the account table
+    Remove(Vec<String>),
+    /// Set unix time the admission of pubkeys expires
+    Expire {
+        expires_at: u64,
+        pubkeys: Vec<String>,
+    },
+    /// Set events per day pubkeys can publish, 0 removes the quota
+    SetQuota { quota: u64, pubkeys: Vec<String> },
+    /// Give pubkeys a role
+    AddRole(Role, Vec<String>),
+    /// Remove a role from pubkeys
+    RemoveRole(Role, Vec<String>),
+    /// Admit event ids
+    AllowEvent(Vec<String>),
+    /// Deny event ids
+    DenyEvent(Vec<String>),
+    /// Fetch past events of pubkeys from the default relays
+    Backfill(Vec<String>),
+}
+
+impl Command {
+    /// Parses a tag into a command.
+    /// Values that are not valid hex keys are returned so they can be reported.
+    pub fn parse(values: &[String]) -> Result<(Self, Vec<String>), String> {
+        let (name, args) = match values.split_first() {
+            Some((name, args)) => (name.as_str(), args),
+            None => return Err("Empty tag".to_string()),
+        };
+
+        let command: fn(Vec<String>) -> Command = match name {
+            "allow" => Command::Allow,
+            "deny" => Command::Deny,
+            "remove" => Command::Remove,
+            "add-admin" => |keys| Command::AddRole(Role::Admin, keys),
+            "remove-admin" => |keys| Command::RemoveRole(Role::Admin, keys),
+            "add-moderator" => |keys| Command::AddRole(Role::Moderator, keys),
+            "remove-moderator" => |keys| Command::RemoveRole(Role::Moderator, keys),
+            "allow-event" => Command::AllowEvent,
+            "deny-event" => Command::DenyEvent,
+            "backfill" => Command::Backfill,
+            "expire" | "set-quota" => {
+                let (number, args) = args
+                    .split_first()
+                    .ok_or_else(|| format!("{name} requires a number"))?;
+                let number = number
+                    .parse::<u64>()
+                    .map_err(|_| format!("{name} requires a number, got {number}"))?;
+                let (keys, invalid) = split_keys(args)?;
+                let command = match name {
+                    "expire" => Command::Expire {
+                        expires_at: number,
+                        pubkeys: keys,
+                    },
+                    _ => Command::SetQuota {
+                        quota: number,
+                        pubkeys: keys,
+                    },
+                };
+                return Ok((command, invalid));
+            }
+            _ => return Err(format!("Unknown command {name}")),
+        };
+
+        let (keys, invalid) = split_keys(args)?;
+        Ok((command(keys), invalid))
+    }
+
+    /// Name of the command as used in tags
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Allow(_) => "allow",
+            Command::Deny(_) => "deny",
+            Command::Remove(_) => "remove",
+            Command::Expire { .. } => "expire",
+            Command::SetQuota { .. } => "set-quota",
+            Command::AddRole(Role::Admin, _) => "add-admin",
+            Command::AddRole(_, _) => "add-moderator",
+            Command::RemoveRole(Role::Admin, _) => "remove-admin",
+            Command::RemoveRole(_, _) => "remove-moderator",
+            Command::AllowEvent(_) => "allow-event",
+            Command::DenyEvent(_) => "deny-event",
+            Command::Backfill(_) => "backfill",
+        }
+    }
+
+    /// Checks a role can use the command
+    pub fn permitted(&self, role: Role) -> bool {
+        match self {
+            Command::Deny(_) | Command::Expire { .. } | Command::DenyEvent(_) => role.can_deny(),
+            Command::AddRole(target, _) | Command::RemoveRole(target, _) => {
+                role.can_manage(*target)
+            }
+            _ => role.can_allow(),
+        }
+    }
+}
+
+/// Splits values into valid hex keys and invalid values
+fn split_keys(values: &[String]) -> Result<(Vec<String>, Vec<String>), String> {
+    let (keys, invalid): (Vec<String>, Vec<String>) =
+        values.iter().cloned().partition(|k| utils::is_hex_key(k));
+    if keys.is_empty() {
+        return Err("No valid keys".to_string());
+    }
+    Ok((keys, invalid))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejected {
+    /// The tag, or the part of it, that was rejected
+    pub tag: Vec<String>,
+    pub reason: String,
+}
+
+/// Summary of an admin command event
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandReport {
+    /// Commands followed by the values they were applied to
+    pub applied: Vec<Vec<String>>,
+    pub rejected: Vec<Rejected>,
+    /// Pubkeys to backfill, the repo cannot fetch events itself
+    #[serde(skip)]
+    pub backfill: Vec<String>,
+}
+
+impl CommandReport {
+    pub fn apply(&mut self, name: &str, values: &[String]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut tag = vec![name.to_string()];
+        tag.extend_from_slice(values);
+        self.applied.push(tag);
+    }
+
+    pub fn reject(&mut self, tag: Vec<String>, reason: &str) {
+        self.rejected.push(Rejected {
+            tag,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Rejects the pubkeys that were not applied because they have an equal or higher role
+    pub fn reject_outranked(&mut self, name: &str, pubkeys: &[String], applied: &[String]) {
+        for pubkey in pubkeys.iter().filter(|pubkey| !applied.contains(pubkey)) {
+            self.reject(
+                vec![name.to_string(), pubkey.clone()],
+                "Key has an equal or higher role",
+            );
+        }
+    }
+
+    /// Signed response event tagged to the command event and the admin who sent it
+    pub fn to_event(
+        &self,
+        keys: &Keys,
+        command_id: EventId,
+        admin: XOnlyPublicKey,
+    ) -> Result<Event, crate::error::Error> {
+        let content = serde_json::to_string(self)?;
+        let tags = [Tag::Event(command_id, None, None), Tag::PubKey(admin, None)];
+        EventBuilder::new(Kind::from(RESPONSE_KIND), content, &tags)
+            .to_event(keys)
+            .map_err(|_| crate::error::Error::SigningError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let key = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_string();
+
+        let (command, invalid) =
+            Command::parse(&["allow".to_string(), key.clone(), "npub".to_string()]).unwrap();
+        assert_eq!(command, Command::Allow(vec![key.clone()]));
+        assert_eq!(invalid, vec!["npub".to_string()]);
+
+        let (command, _) =
+            Command::parse(&["expire".to_string(), "1700000000".to_string(), key.clone()]).unwrap();
+        assert_eq!(
+            command,
+            Command::Expire {
+                expires_at: 1700000000,
+                pubkeys: vec![key.clone()]
+            }
+        );
+        assert!(command.permitted(Role::Moderator));
+
+        assert!(Command::parse(&["expire".to_string(), key.clone()]).is_err());
+        assert!(Command::parse(&["set-quota".to_string(), "10".to_string()]).is_err());
+        assert!(Command::parse(&["unknown".to_string(), key.clone()]).is_err());
+        assert!(!Command::parse(&["add-admin".to_string(), key])
+            .unwrap()
+            .0
+            .permitted(Role::Admin));
+    }
+}
@@ -27,61 +27,554 @@ THE SOFTWARE.
 */
 //!
 //!
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 use config::{Config, ConfigError, File};
+use nostr_sdk::prelude::{FromBech32, XOnlyPublicKey};
 use nostr_sdk::Url;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, warn};
+use tracing::debug;
+
+use crate::utils;
+
+/// Prefix of environment variables overriding settings,
+/// e.g. `MY_LOCAL_RELAY__INFO__API_KEY` sets `api_key` in `[info]`
+pub const ENV_PREFIX: &str = "MY_LOCAL_RELAY__";
+/// Separator of the table and key in environment variable names
+const ENV_SEPARATOR: &str = "__";
+/// Settings that are lists, set from comma separated environment variables
+const ENV_LIST_KEYS: [&str; 7] = [
+    "info.admin_keys",
+    "info.default_relays",
+    "filters.denied_events",
+    "filters.content_patterns",
+    "inbox.kinds",
+    "zaps.trusted_providers",
+    "sync.relays",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Info {
     pub admin_keys: Vec<String>,
     pub api_key: Option<String>,
+    /// File the http api key is read from, e.g. a Docker secret
+    pub api_key_file: Option<String>,
+    /// Public url of the HTTP API, checked against the `u` tag of NIP-98 auth events
+    pub api_url: Option<String>,
     pub relay: String,
+    /// Hex secret key of the service, used to sign responses, decrypt admin commands
+    /// and authenticate to relays
+    pub secret_key: Option<String>,
+    /// File the service key is loaded from, or created in, if `secret_key` is not set
+    pub secret_key_file: Option<String>,
     pub default_relays: HashSet<Url>,
 }
 
+/// Filters for events fetched as context
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Filters {
+    /// Hex event ids that are never admitted, even if allowed in the event table
+    pub denied_events: HashSet<String>,
+    /// Regex patterns, fetched events with content matching any are not imported
+    pub content_patterns: Vec<String>,
+    /// Max characters of content of fetched events
+    pub max_content_length: Option<usize>,
+    /// Max http(s) links in content of fetched events
+    pub max_links: Option<usize>,
+}
+
+/// NIP-13 proof of work required of authors that are not admitted
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Pow {
+    /// Leading zero bits required by kind, keys are kind numbers
+    pub difficulty: HashMap<String, u8>,
+    /// Leading zero bits required of kinds not in `difficulty`,
+    /// if not set other kinds cannot be published with work
+    pub default_difficulty: Option<u8>,
+}
+
+/// Events from any author addressed to admitted pubkeys
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Inbox {
+    /// Kinds admitted when a `p` tag references an admitted pubkey, empty disables the inbox
+    pub kinds: HashSet<u64>,
+    /// NIP-13 leading zero bits also required of inbox events
+    pub difficulty: Option<u8>,
+    /// Max inbox events per day from each author
+    pub daily_limit: Option<u64>,
+}
+
+/// Background fetching of events to import
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Imports {
+    /// Jobs run at once
+    pub workers: usize,
+    /// Max jobs waiting for a worker, admitted events wait for space when full
+    pub queue_size: usize,
+    /// Max referenced ids fetched in one request
+    pub batch_size: usize,
+    /// Stop fetching from relays that return invalid events for an hour
+    pub penalise_bad_relays: bool,
+    /// Subscribe to new events of admitted pubkeys on the default relays
+    pub mirror: bool,
+}
+
+impl Default for Imports {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            queue_size: 1000,
+            batch_size: 250,
+            penalise_bad_relays: true,
+            mirror: true,
+        }
+    }
+}
+
+/// Paid admission with Lightning invoices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    /// `lnbits`, `lnd` or `cln`, or `fake` with the `fake-payments` feature
+    pub backend: String,
+    /// Url of the node or wallet api
+    #[serde(default)]
+    pub url: String,
+    /// LNbits invoice key, LND hex macaroon or CLN rune
+    pub api_key: Option<String>,
+    /// File `api_key` is read from, e.g. a Docker secret
+    pub api_key_file: Option<String>,
+    /// Certificate to trust for nodes with a self signed certificate
+    pub tls_cert: Option<String>,
+    /// Price of a day of admission
+    pub sats_per_day: u64,
+    /// Most invoices created in an hour, as anyone can create them
+    #[serde(default = "default_invoices_per_hour")]
+    pub invoices_per_hour: u64,
+}
+
+fn default_invoices_per_hour() -> u64 {
+    100
+}
+
+/// Admission of senders of zaps to admins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zaps {
+    /// Smallest zap that admits the sender
+    pub min_sats: u64,
+    /// Sats zapped for a day of admission
+    pub sats_per_day: u64,
+    /// Hex pubkeys of zap providers receipts are accepted from, required as anyone
+    /// can sign a receipt and the bolt11 signature is not checked
+    #[serde(default)]
+    pub trusted_providers: HashSet<String>,
+}
+
+/// Sync of the events of admitted pubkeys with other relays
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelaySync {
+    /// Relays synced with
+    pub relays: Vec<String>,
+    /// Seconds between syncs
+    pub interval: u64,
+    /// Send relays the events they are missing, otherwise only fetch from them.
+    /// Off by default as it publishes events to relays their authors did not choose.
+    pub upload: bool,
+}
+
+impl Default for RelaySync {
+    fn default() -> Self {
+        Self {
+            relays: vec![],
+            interval: 3600,
+            upload: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Settings {
     pub info: Info,
+    pub filters: Filters,
+    pub pow: Pow,
+    pub inbox: Inbox,
+    pub imports: Imports,
+    /// Paid admission is disabled if not set
+    pub payment: Option<Payment>,
+    /// Zap admission is disabled if not set
+    pub zaps: Option<Zaps>,
+    /// Sync with other relays is disabled if not set
+    pub sync: Option<RelaySync>,
 }
 
 impl Settings {
-    #[must_use]
-    pub fn new(config_file_name: &Option<String>) -> Self {
-        let default_settings = Self::default();
-        // attempt to construct settings with file
-        let from_file = Self::new_from_default(&default_settings, config_file_name);
-        match from_file {
-            Ok(f) => f,
-            Err(e) => {
-                warn!("Error reading config file ({:?})", e);
-                default_settings
-            }
-        }
+    /// Loads settings from the config file, if it exists, overridden by environment variables.
+    /// Fails if the file cannot be parsed, a secret file cannot be read or `relay` is not set.
+    pub fn load(config_file_name: &str) -> Result<Self, ConfigError> {
+        Self::new_from_default(&Self::default(), config_file_name, std::env::vars())
     }
 
     fn new_from_default(
         default: &Settings,
-        config_file_name: &Option<String>,
+        config_file_name: &str,
+        vars: impl Iterator<Item = (String, String)>,
     ) -> Result<Self, ConfigError> {
-        let default_config_file_name = "config.toml".to_string();
-        let config: &String = match config_file_name {
-            Some(value) => value,
-            None => &default_config_file_name,
-        };
-        let builder = Config::builder();
-        let config: Config = builder
+        let mut builder = Config::builder()
             // use defaults
             .add_source(Config::try_from(default)?)
             // override with file contents
-            .add_source(File::with_name(config))
-            .build()?;
-        let settings: Settings = config.try_deserialize()?;
+            .add_source(File::with_name(config_file_name).required(false));
+        // override with environment variables
+        for (key, value) in vars {
+            let key = match key.strip_prefix(ENV_PREFIX) {
+                Some(key) => key.to_lowercase().replace(ENV_SEPARATOR, "."),
+                None => continue,
+            };
+            builder = if ENV_LIST_KEYS.contains(&key.as_str()) {
+                let values: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                builder.set_override(key, values)?
+            } else {
+                builder.set_override(key, value)?
+            };
+        }
+        let mut settings: Settings = builder.build()?.try_deserialize()?;
+
+        read_secret(&mut settings.info.api_key, &settings.info.api_key_file)?;
+        if let Some(payment) = settings.payment.as_mut() {
+            read_secret(&mut payment.api_key, &payment.api_key_file)?;
+        }
+
+        // Keys can be given as npub but are used as hex
+        for key in settings.info.admin_keys.iter_mut() {
+            *key = npub_to_hex(key);
+        }
+        if let Some(zaps) = settings.zaps.as_mut() {
+            zaps.trusted_providers = zaps
+                .trusted_providers
+                .iter()
+                .map(|key| npub_to_hex(key))
+                .collect();
+        }
+
+        if settings.info.relay.is_empty() {
+            return Err(ConfigError::Message(format!(
+                "relay is not set in {config_file_name} or {ENV_PREFIX}INFO__RELAY"
+            )));
+        }
 
         debug!("{settings:?}");
 
         Ok(settings)
     }
+
+    /// Checks every setting, returning a description of each problem
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let info = &self.info;
+
+        if info.admin_keys.is_empty() {
+            problems.push("info.admin_keys is empty, no events will be admitted".to_string());
+        }
+        for key in &info.admin_keys {
+            if !utils::is_hex_key(key) {
+                problems.push(format!(
+                    "info.admin_keys: {key} is not a lowercase hex or npub pubkey"
+                ));
+            }
+        }
+        check_url(&mut problems, "info.relay", &info.relay, &["ws", "wss"]);
+        for relay in &info.default_relays {
+            check_url(
+                &mut problems,
+                "info.default_relays",
+                relay.as_str(),
+                &["ws", "wss"],
+            );
+        }
+        if let Some(api_url) = &info.api_url {
+            check_url(&mut problems, "info.api_url", api_url, &["http", "https"]);
+        }
+        if let Some(secret_key) = &info.secret_key {
+            if !utils::is_hex_key(secret_key) {
+                problems.push("info.secret_key is not a lowercase hex secret key".to_string());
+            }
+        }
+        if let Some(file) = &info.secret_key_file {
+            // The key file is created on first run so only its directory has to exist
+            let path = Path::new(file);
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            if !path.is_file() && dir.is_some_and(|dir| !dir.is_dir()) {
+                problems.push(format!("info.secret_key_file: {file} cannot be created"));
+            }
+        }
+
+        for id in &self.filters.denied_events {
+            if !utils::is_hex_key(id) {
+                problems.push(format!(
+                    "filters.denied_events: {id} is not a lowercase hex event id"
+                ));
+            }
+        }
+        for pattern in &self.filters.content_patterns {
+            if let Err(err) = regex::Regex::new(pattern) {
+                problems.push(format!("filters.content_patterns: {err}"));
+            }
+        }
+
+        for kind in self.pow.difficulty.keys() {
+            if kind.parse::<u64>().is_err() {
+                problems.push(format!("pow.difficulty: {kind} is not a kind number"));
+            }
+        }
+
+        for (name, value) in [
+            ("imports.workers", self.imports.workers),
+            ("imports.queue_size", self.imports.queue_size),
+            ("imports.batch_size", self.imports.batch_size),
+        ] {
+            if value == 0 {
+                problems.push(format!("{name} must be more than 0"));
+            }
+        }
+
+        if let Some(payment) = &self.payment {
+            match payment.backend.as_str() {
+                #[cfg(any(test, feature = "fake-payments"))]
+                "fake" => (),
+                "lnbits" | "lnd" | "cln" => {
+                    check_url(
+                        &mut problems,
+                        "payment.url",
+                        &payment.url,
+                        &["http", "https"],
+                    );
+                    if payment.api_key.is_none() {
+                        problems.push("payment.api_key is not set".to_string());
+                    }
+                }
+                backend => problems.push(format!("payment.backend: {backend} is not supported")),
+            }
+            if let Some(cert) = &payment.tls_cert {
+                if !Path::new(cert).is_file() {
+                    problems.push(format!("payment.tls_cert: {cert} does not exist"));
+                }
+            }
+            if payment.sats_per_day == 0 {
+                problems.push("payment.sats_per_day must be more than 0".to_string());
+            }
+            if payment.invoices_per_hour == 0 {
+                problems.push("payment.invoices_per_hour must be more than 0".to_string());
+            }
+        }
+
+        if let Some(zaps) = &self.zaps {
+            if zaps.sats_per_day == 0 {
+                problems.push("zaps.sats_per_day must be more than 0".to_string());
+            }
+            if zaps.trusted_providers.is_empty() {
+                problems.push("zaps.trusted_providers is not set".to_string());
+            }
+            for key in &zaps.trusted_providers {
+                if !utils::is_hex_key(key) {
+                    problems.push(format!(
+                        "zaps.trusted_providers: {key} is not a lowercase hex or npub pubkey"
+                    ));
+                }
+            }
+        }
+
+        if let Some(sync) = &self.sync {
+            for relay in &sync.relays {
+                check_url(&mut problems, "sync.relays", relay, &["ws", "wss"]);
+            }
+            if sync.interval == 0 {
+                problems.push("sync.interval must be more than 0".to_string());
+            }
+        }
+
+        problems
+    }
+
+    /// Settings with secrets replaced, for printing
+    pub fn redacted(&self) -> Self {
+        let redact = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>".to_string());
+        let mut settings = self.clone();
+        settings.info.api_key = redact(&settings.info.api_key);
+        settings.info.secret_key = redact(&settings.info.secret_key);
+        if let Some(payment) = settings.payment.as_mut() {
+            payment.api_key = redact(&payment.api_key);
+        }
+        settings
+    }
+}
+
+/// Converts an npub to hex, leaving other values as they are
+fn npub_to_hex(key: &str) -> String {
+    match key.starts_with("npub1") {
+        true => XOnlyPublicKey::from_bech32(key)
+            .map(|pubkey| pubkey.to_string())
+            .unwrap_or_else(|_| key.to_string()),
+        false => key.to_string(),
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, name: &str, url: &str, schemes: &[&str]) {
+    match Url::parse(url) {
+        Ok(url) if schemes.contains(&url.scheme()) => (),
+        Ok(_) => problems.push(format!(
+            "{name}: {url} must be a {} url",
+            schemes.join(" or ")
+        )),
+        Err(err) => problems.push(format!("{name}: {url} is not a valid url, {err}")),
+    }
+}
+
+/// Sets a secret from its file, failing if the file cannot be read or the secret is also set
+fn read_secret(secret: &mut Option<String>, file: &Option<String>) -> Result<(), ConfigError> {
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    if secret.is_some() {
+        return Err(ConfigError::Message(format!(
+            "Both a secret and the secret file {file} are set"
+        )));
+    }
+    let value = std::fs::read_to_string(file)
+        .map_err(|err| ConfigError::Message(format!("Could not read secret file {file}: {err}")))?;
+    *secret = Some(value.trim().to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let dir = std::env::temp_dir().join("my_local_relay_config_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("api_key");
+        std::fs::File::create(&secret)
+            .unwrap()
+            .write_all(b"secret\n")
+            .unwrap();
+        let missing = dir.join("missing.toml");
+        let missing = missing.to_str().unwrap();
+
+        let settings = Settings::new_from_default(
+            &Settings::default(),
+            missing,
+            vars(&[
+                ("MY_LOCAL_RELAY__INFO__RELAY", "ws://localhost:8081"),
+                ("MY_LOCAL_RELAY__INFO__ADMIN_KEYS", "abc, def"),
+                (
+                    "MY_LOCAL_RELAY__INFO__API_KEY_FILE",
+                    secret.to_str().unwrap(),
+                ),
+                ("MY_LOCAL_RELAY__INBOX__KINDS", "1,7"),
+                ("MY_LOCAL_RELAY__INBOX__DAILY_LIMIT", "10"),
+                ("MY_LOCAL_RELAY__POW__DEFAULT_DIFFICULTY", "20"),
+                ("MY_LOCAL_RELAY__POW__DIFFICULTY__1", "24"),
+                ("OTHER__INFO__RELAY", "ws://example.com"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(settings.info.relay, "ws://localhost:8081");
+        assert_eq!(settings.info.admin_keys, vec!["abc", "def"]);
+        assert_eq!(settings.info.api_key.as_deref(), Some("secret"));
+        assert_eq!(settings.inbox.kinds, HashSet::from([1, 7]));
+        assert_eq!(settings.inbox.daily_limit, Some(10));
+        assert_eq!(settings.pow.default_difficulty, Some(20));
+        assert_eq!(settings.pow.difficulty.get("1"), Some(&24));
+
+        // Invalid settings are reported, not rejected on load
+        assert_eq!(settings.validate().len(), 2);
+        let settings = Settings::new_from_default(
+            &Settings::default(),
+            missing,
+            vars(&[
+                ("MY_LOCAL_RELAY__INFO__RELAY", "http://localhost:8081"),
+                (
+                    "MY_LOCAL_RELAY__INFO__ADMIN_KEYS",
+                    "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg",
+                ),
+                (
+                    "MY_LOCAL_RELAY__INFO__DEFAULT_RELAYS",
+                    "wss://relay.damus.io",
+                ),
+                ("MY_LOCAL_RELAY__FILTERS__CONTENT_PATTERNS", "("),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.info.admin_keys,
+            vec!["7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e"]
+        );
+        let problems = settings.validate();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("info.relay"));
+        assert!(problems[1].starts_with("filters.content_patterns"));
+
+        // Uppercase keys would never match the lowercase authors of events
+        let uppercase = Settings {
+            info: Info {
+                admin_keys: vec![settings.info.admin_keys[0].to_uppercase()],
+                ..settings.info.clone()
+            },
+            ..settings.clone()
+        };
+        let problems = uppercase.validate();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("info.admin_keys"));
+
+        // Zap receipts are only accepted from trusted providers
+        let settings = Settings {
+            zaps: Some(Zaps {
+                min_sats: 1000,
+                sats_per_day: 100,
+                trusted_providers: HashSet::new(),
+            }),
+            ..settings
+        };
+        assert_eq!(settings.validate().len(), 3);
+        assert_eq!(
+            settings.validate()[2],
+            "zaps.trusted_providers is not set".to_string()
+        );
+
+        // Missing relay
+        assert!(Settings::new_from_default(&Settings::default(), missing, vars(&[])).is_err());
+        // Unreadable secret file
+        assert!(Settings::new_from_default(
+            &Settings::default(),
+            missing,
+            vars(&[
+                ("MY_LOCAL_RELAY__INFO__RELAY", "ws://localhost:8081"),
+                ("MY_LOCAL_RELAY__INFO__API_KEY_FILE", missing),
+            ]),
+        )
+        .is_err());
+    }
 }
@@ -1,12 +1,41 @@
-use redb::{Database, ReadableTable, TableDefinition};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use crate::{error::Error, Users};
+use std::ops::Bound;
+
+use crate::{
+    api::Users, error::Error, imports::ImportJob, payment::PendingInvoice, relays::RelayStats,
+    utils,
+};
 // key is hex pubkey value is name
 const ACCOUNTTABLE: TableDefinition<&str, u8> = TableDefinition::new("account");
+// key is hex pubkey value is unix time the admission expires
+const ACCOUNTEXPIRYTABLE: TableDefinition<&str, u64> = TableDefinition::new("account_expiry");
 const EVENTTABLE: TableDefinition<&str, u8> = TableDefinition::new("event");
+// key is hex event id value is NIP-40 expiration unix time
+const EVENTEXPIRYTABLE: TableDefinition<&str, u64> = TableDefinition::new("event_expiry");
+// key is hex event id followed by the hex pubkey that deleted it value is unix time of the deletion
+const DELETIONTABLE: TableDefinition<&str, u64> = TableDefinition::new("deletion");
+// key is hex payment hash value is json of the pending invoice
+const INVOICETABLE: TableDefinition<&str, &str> = TableDefinition::new("invoice");
+// key is hex id of a zap receipt value is unix time it was counted
+const ZAPTABLE: TableDefinition<&str, u64> = TableDefinition::new("zap");
+// key is hex id of an applied admin command value is unix time it was applied
+const COMMANDTABLE: TableDefinition<&str, u64> = TableDefinition::new("command");
+// key is order the job was saved value is json of an import unfinished at shutdown
+const IMPORTJOBTABLE: TableDefinition<u64, &str> = TableDefinition::new("import_job");
+// key is relay url value is json of its stats
+const RELAYTABLE: TableDefinition<&str, &str> = TableDefinition::new("relay");
+// key is hex pubkey value is role
+const ROLETABLE: TableDefinition<&str, u8> = TableDefinition::new("role");
+// key is hex pubkey value is events per day
+const QUOTATABLE: TableDefinition<&str, u64> = TableDefinition::new("quota");
+// key is hex pubkey value is (day since epoch, events that day)
+const USAGETABLE: TableDefinition<&str, (u64, u64)> = TableDefinition::new("usage");
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Status {
     Deny,
@@ -24,13 +53,58 @@ impl Status {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Role of a key that can manage the relay, ordered by power
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Role {
+    /// Can deny and expire accounts
+    Moderator,
+    /// Can allow accounts and events and manage moderators
+    Admin,
+    /// Can manage admins, set from `admin_keys` in the config
+    Owner,
+}
+
+impl Role {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            2 => Role::Owner,
+            1 => Role::Admin,
+            // Unknown roles get the least power
+            _ => Role::Moderator,
+        }
+    }
+
+    pub fn can_allow(&self) -> bool {
+        self >= &Role::Admin
+    }
+
+    pub fn can_deny(&self) -> bool {
+        self >= &Role::Moderator
+    }
+
+    /// Keys can only add or remove roles below their own
+    pub fn can_manage(&self, role: Role) -> bool {
+        self > &role
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Account {
     pub pubkey: String,
     pub status: Status,
+    /// Unix time the admission expires
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Admin {
+    pub pubkey: String,
+    pub role: Role,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Event {
     pub id: String,
     pub status: Status,
@@ -38,11 +112,32 @@ pub struct Event {
 
 impl Account {
     pub fn is_admitted(&self) -> bool {
-        if self.status.eq(&Status::Allow) {
+        if self.status.eq(&Status::Allow) && !self.is_expired() {
             return true;
         }
         false
     }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= utils::unix_time())
+    }
+
+    /// The account of `pubkey` admitted for `seconds` more, from its expiry if still admitted.
+    /// Accounts admitted without an expiry are unchanged.
+    pub fn extended(current: Option<Account>, pubkey: &str, seconds: u64, now: u64) -> Account {
+        let from = match current {
+            Some(account) if account.is_admitted() => match account.expires_at {
+                Some(expires_at) => expires_at.max(now),
+                None => return account,
+            },
+            _ => now,
+        };
+        Account {
+            pubkey: pubkey.to_string(),
+            status: Status::Allow,
+            expires_at: Some(from + seconds),
+        }
+    }
 }
 
 impl Event {
@@ -55,7 +150,8 @@ impl Event {
 }
 
 pub struct Db {
-    db: Database,
+    /// `None` once closed
+    db: Option<Database>,
 }
 
 impl Default for Db {
@@ -73,30 +169,52 @@ impl Db {
         {
             // Opens the table to create it
             let _ = write_txn.open_table(ACCOUNTTABLE).unwrap();
+            let _ = write_txn.open_table(ACCOUNTEXPIRYTABLE).unwrap();
             let _ = write_txn.open_table(EVENTTABLE).unwrap();
+            let _ = write_txn.open_table(EVENTEXPIRYTABLE).unwrap();
+            let _ = write_txn.open_table(DELETIONTABLE).unwrap();
+            let _ = write_txn.open_table(ROLETABLE).unwrap();
+            let _ = write_txn.open_table(QUOTATABLE).unwrap();
+            let _ = write_txn.open_table(USAGETABLE).unwrap();
+            let _ = write_txn.open_table(INVOICETABLE).unwrap();
+            let _ = write_txn.open_table(ZAPTABLE).unwrap();
+            let _ = write_txn.open_table(COMMANDTABLE).unwrap();
+            let _ = write_txn.open_table(IMPORTJOBTABLE).unwrap();
+            let _ = write_txn.open_table(RELAYTABLE).unwrap();
         }
         write_txn.commit().unwrap();
 
-        Self { db }
+        Self { db: Some(db) }
     }
 
-    pub fn write_account(&self, account: &Account) -> Result<(), Error> {
-        let write_txn = self.db.begin_write()?;
-        {
-            let mut table = write_txn.open_table(ACCOUNTTABLE)?;
-            table.insert(account.pubkey.as_str(), account.status as u8)?;
+    fn db(&self) -> Result<&Database, Error> {
+        self.db.as_ref().ok_or(Error::DbClosed)
+    }
+
+    /// Closes the database so it does not need repair when next opened.
+    /// Later reads and writes fail.
+    pub fn close(&mut self) {
+        if self.db.take().is_some() {
+            debug!("Closed DB");
         }
+    }
+
+    pub fn write_account(&self, account: &Account) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        insert_account(&write_txn, account)?;
         write_txn.commit().unwrap();
         Ok(())
     }
 
     pub fn read_account(&self, pubkey: &str) -> Result<Option<Account>, Error> {
-        let read_txn = self.db.begin_read()?;
+        let read_txn = self.db()?.begin_read()?;
         let table = read_txn.open_table(ACCOUNTTABLE)?;
+        let expiry_table = read_txn.open_table(ACCOUNTEXPIRYTABLE)?;
         if let Some(account_info) = table.get(pubkey)? {
             let account = Account {
                 pubkey: pubkey.to_string(),
                 status: Status::from_u8(account_info.value()),
+                expires_at: expiry_table.get(pubkey)?.map(|e| e.value()),
             };
             return Ok(Some(account));
         }
@@ -104,7 +222,7 @@ impl Db {
     }
 
     pub fn read_all_accounts(&self) -> Result<(), Error> {
-        let read_txn = self.db.begin_read()?;
+        let read_txn = self.db()?.begin_read()?;
         let table = read_txn.open_table(ACCOUNTTABLE)?;
 
         for a in table.iter()? {
@@ -114,7 +232,7 @@ impl Db {
     }
 
     pub fn read_accounts(&self) -> Result<Users, Error> {
-        let read_txn = self.db.begin_read()?;
+        let read_txn = self.db()?.begin_read()?;
         let table = read_txn.open_table(ACCOUNTTABLE)?;
 
         let users: Vec<(String, u8)> = table
@@ -141,8 +259,64 @@ impl Db {
         })
     }
 
+    /// Reads up to `limit` accounts with a pubkey after `cursor`, optionally
+    /// filtered by status. Returns the page and the cursor for the next page.
+    pub fn read_accounts_page(
+        &self,
+        status: Option<Status>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<Account>, Option<String>), Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(ACCOUNTTABLE)?;
+        let expiry_table = read_txn.open_table(ACCOUNTEXPIRYTABLE)?;
+
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+
+        let mut accounts: Vec<Account> = table
+            .range::<&str>((start, Bound::Unbounded))?
+            .map(|(k, s)| Account {
+                pubkey: k.value().to_string(),
+                status: Status::from_u8(s.value()),
+                expires_at: None,
+            })
+            .filter(|a| status.is_none_or(|status| a.status.eq(&status)))
+            .take(limit + 1)
+            .collect();
+
+        for account in accounts.iter_mut() {
+            account.expires_at = expiry_table
+                .get(account.pubkey.as_str())?
+                .map(|e| e.value());
+        }
+
+        let next_cursor = if accounts.len() > limit {
+            accounts.truncate(limit);
+            accounts.last().map(|a| a.pubkey.clone())
+        } else {
+            None
+        };
+
+        Ok((accounts, next_cursor))
+    }
+
+    pub fn delete_account(&self, pubkey: &str) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(ACCOUNTTABLE)?;
+            let removed = table.remove(pubkey)?.is_some();
+            write_txn.open_table(ACCOUNTEXPIRYTABLE)?.remove(pubkey)?;
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
     pub fn write_event(&self, event: &Event) -> Result<(), Error> {
-        let write_txn = self.db.begin_write()?;
+        let write_txn = self.db()?.begin_write()?;
         {
             let mut table = write_txn.open_table(EVENTTABLE)?;
             table.insert(event.id.as_str(), event.status as u8)?;
@@ -151,7 +325,7 @@ impl Db {
         Ok(())
     }
     pub fn write_events(&self, events: &[Event]) -> Result<(), Error> {
-        let write_txn = self.db.begin_write()?;
+        let write_txn = self.db()?.begin_write()?;
         {
             let mut table = write_txn.open_table(EVENTTABLE)?;
             for event in events {
@@ -163,7 +337,7 @@ impl Db {
     }
 
     pub fn read_event(&self, event_id: &str) -> Result<Option<Event>, Error> {
-        let read_txn = self.db.begin_read()?;
+        let read_txn = self.db()?.begin_read()?;
         let table = read_txn.open_table(EVENTTABLE)?;
         if let Some(event_info) = table.get(&event_id)? {
             let event = Event {
@@ -175,8 +349,82 @@ impl Db {
         Ok(None)
     }
 
+    /// Records NIP-09 deletions of `event_ids` by `pubkey`
+    pub fn write_deletions(
+        &self,
+        pubkey: &str,
+        event_ids: &[String],
+        deleted_at: u64,
+    ) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(DELETIONTABLE)?;
+            for id in event_ids {
+                table.insert(format!("{id}{pubkey}").as_str(), deleted_at)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Checks `pubkey` has deleted the event
+    pub fn read_deletion(&self, event_id: &str, pubkey: &str) -> Result<bool, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(DELETIONTABLE)?;
+        let deleted = table.get(format!("{event_id}{pubkey}").as_str())?.is_some();
+        Ok(deleted)
+    }
+
+    pub fn delete_event(&self, event_id: &str) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(EVENTTABLE)?;
+            let removed = table.remove(event_id)?.is_some();
+            write_txn.open_table(EVENTEXPIRYTABLE)?.remove(event_id)?;
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// Records when events expire, keyed by hex event id
+    pub fn write_event_expiries(&self, expiries: &[(String, u64)]) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(EVENTEXPIRYTABLE)?;
+            for (id, expires_at) in expiries {
+                table.insert(id.as_str(), expires_at)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Denies events that expired before `now` and removes their expiry.
+    /// Returns the ids of the expired events.
+    pub fn deny_expired_events(&self, now: u64) -> Result<Vec<String>, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let expired = {
+            let mut expiry_table = write_txn.open_table(EVENTEXPIRYTABLE)?;
+            let expired: Vec<String> = expiry_table
+                .iter()?
+                .filter(|(_, expires_at)| expires_at.value() <= now)
+                .map(|(id, _)| id.value().to_string())
+                .collect();
+
+            let mut table = write_txn.open_table(EVENTTABLE)?;
+            for id in &expired {
+                expiry_table.remove(id.as_str())?;
+                table.insert(id.as_str(), Status::Deny as u8)?;
+            }
+            expired
+        };
+        write_txn.commit()?;
+        Ok(expired)
+    }
+
     pub fn read_all_events(&self) -> Result<(), Error> {
-        let read_txn = self.db.begin_read()?;
+        let read_txn = self.db()?.begin_read()?;
         let table = read_txn.open_table(EVENTTABLE)?;
 
         for a in table.iter()? {
@@ -185,8 +433,289 @@ impl Db {
         Ok(())
     }
 
+    pub fn write_role(&self, pubkey: &str, role: Role) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(ROLETABLE)?;
+            table.insert(pubkey, role as u8)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn read_role(&self, pubkey: &str) -> Result<Option<Role>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(ROLETABLE)?;
+        let role = table.get(pubkey)?.map(|r| Role::from_u8(r.value()));
+        Ok(role)
+    }
+
+    pub fn read_roles(&self) -> Result<Vec<Admin>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(ROLETABLE)?;
+
+        let admins = table
+            .iter()?
+            .map(|(k, r)| Admin {
+                pubkey: k.value().to_string(),
+                role: Role::from_u8(r.value()),
+            })
+            .collect();
+        Ok(admins)
+    }
+
+    pub fn delete_role(&self, pubkey: &str) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(ROLETABLE)?;
+            let removed = table.remove(pubkey)?.is_some();
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn write_quota(&self, pubkey: &str, quota: Option<u64>) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(QUOTATABLE)?;
+            match quota {
+                Some(quota) => {
+                    table.insert(pubkey, quota)?;
+                }
+                None => {
+                    table.remove(pubkey)?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn read_quota(&self, pubkey: &str) -> Result<Option<u64>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(QUOTATABLE)?;
+        let quota = table.get(pubkey)?.map(|q| q.value());
+        Ok(quota)
+    }
+
+    /// Counts an event against the pubkey's usage for `day`, returning the new count
+    pub fn increment_usage(&self, pubkey: &str, day: u64) -> Result<u64, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let count = {
+            let mut table = write_txn.open_table(USAGETABLE)?;
+            let count = match table.get(pubkey)?.map(|u| u.value()) {
+                Some((usage_day, count)) if usage_day == day => count + 1,
+                _ => 1,
+            };
+            table.insert(pubkey, (day, count))?;
+            count
+        };
+        write_txn.commit()?;
+        Ok(count)
+    }
+
+    pub fn write_invoice(&self, invoice: &PendingInvoice) -> Result<(), Error> {
+        let value = serde_json::to_string(invoice)?;
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(INVOICETABLE)?;
+            table.insert(invoice.payment_hash.as_str(), value.as_str())?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn read_invoice(&self, payment_hash: &str) -> Result<Option<PendingInvoice>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(INVOICETABLE)?;
+        let invoice = match table.get(payment_hash)? {
+            Some(value) => Some(serde_json::from_str(value.value())?),
+            None => None,
+        };
+        Ok(invoice)
+    }
+
+    pub fn read_invoices(&self) -> Result<Vec<PendingInvoice>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(INVOICETABLE)?;
+        let invoices = table
+            .iter()?
+            .map(|(_, value)| serde_json::from_str(value.value()))
+            .collect::<Result<Vec<PendingInvoice>, serde_json::Error>>()?;
+        Ok(invoices)
+    }
+
+    pub fn write_relay_stats(&self, stats: &[RelayStats]) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(RELAYTABLE)?;
+            for stats in stats {
+                table.insert(stats.url.as_str(), serde_json::to_string(stats)?.as_str())?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn read_relay_stats(&self, url: &str) -> Result<Option<RelayStats>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(RELAYTABLE)?;
+        let stats = match table.get(url)? {
+            Some(value) => Some(serde_json::from_str(value.value())?),
+            None => None,
+        };
+        Ok(stats)
+    }
+
+    pub fn read_all_relay_stats(&self) -> Result<Vec<RelayStats>, Error> {
+        let read_txn = self.db()?.begin_read()?;
+        let table = read_txn.open_table(RELAYTABLE)?;
+        let stats = table
+            .iter()?
+            .map(|(_, value)| serde_json::from_str(value.value()))
+            .collect::<Result<Vec<RelayStats>, serde_json::Error>>()?;
+        Ok(stats)
+    }
+
+    /// Saves imports that did not finish before shutdown
+    pub fn write_import_jobs(&self, jobs: &[ImportJob]) -> Result<(), Error> {
+        let write_txn = self.db()?.begin_write()?;
+        {
+            let mut table = write_txn.open_table(IMPORTJOBTABLE)?;
+            let next = table
+                .iter()?
+                .next_back()
+                .map(|(key, _)| key.value() + 1)
+                .unwrap_or_default();
+            for (key, job) in (next..).zip(jobs) {
+                table.insert(key, serde_json::to_string(job)?.as_str())?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Removes and returns the saved imports
+    pub fn take_import_jobs(&self) -> Result<Vec<ImportJob>, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let jobs = {
+            let mut table = write_txn.open_table(IMPORTJOBTABLE)?;
+            let jobs = table
+                .drain::<u64>(..)?
+                .map(|(_, value)| serde_json::from_str(value.value()))
+                .collect::<Result<Vec<ImportJob>, serde_json::Error>>()?;
+            jobs
+        };
+        write_txn.commit()?;
+        Ok(jobs)
+    }
+
+    pub fn delete_invoice(&self, payment_hash: &str) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(INVOICETABLE)?;
+            let removed = table.remove(payment_hash)?.is_some();
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// Marks an invoice paid and extends the admission of its pubkey by `seconds` in one
+    /// transaction, so a payment is only counted once. Denied pubkeys stay denied.
+    /// Returns `None` if it was already paid.
+    pub fn pay_invoice(&self, payment_hash: &str, seconds: u64) -> Result<Option<Account>, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let account = {
+            let mut table = write_txn.open_table(INVOICETABLE)?;
+            let mut invoice: PendingInvoice = match table.get(payment_hash)? {
+                Some(value) => serde_json::from_str(value.value())?,
+                None => return Err(Error::NotFound),
+            };
+            if invoice.paid {
+                None
+            } else {
+                invoice.paid = true;
+                table.insert(payment_hash, serde_json::to_string(&invoice)?.as_str())?;
+
+                let current = {
+                    let accounts = write_txn.open_table(ACCOUNTTABLE)?;
+                    let expiries = write_txn.open_table(ACCOUNTEXPIRYTABLE)?;
+                    let status = accounts.get(invoice.pubkey.as_str())?.map(|s| s.value());
+                    let expires_at = expiries.get(invoice.pubkey.as_str())?.map(|e| e.value());
+                    status.map(|status| Account {
+                        pubkey: invoice.pubkey.clone(),
+                        status: Status::from_u8(status),
+                        expires_at,
+                    })
+                };
+                match current {
+                    Some(account) if account.status == Status::Deny => Some(account),
+                    current => {
+                        let account = Account::extended(
+                            current,
+                            &invoice.pubkey,
+                            seconds,
+                            utils::unix_time(),
+                        );
+                        insert_account(&write_txn, &account)?;
+                        Some(account)
+                    }
+                }
+            }
+        };
+        write_txn.commit()?;
+        Ok(account)
+    }
+
+    /// Records a zap receipt, returning false if it was already recorded
+    pub fn write_zap(&self, receipt_id: &str, now: u64) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let new = {
+            let mut table = write_txn.open_table(ZAPTABLE)?;
+            let new = table.get(receipt_id)?.is_none();
+            if new {
+                table.insert(receipt_id, now)?;
+            }
+            new
+        };
+        write_txn.commit()?;
+        Ok(new)
+    }
+
+    /// Records an admin command, returning false if it was already recorded.
+    /// Commands recorded before `forget_before` are removed.
+    pub fn write_command(
+        &self,
+        command_id: &str,
+        now: u64,
+        forget_before: u64,
+    ) -> Result<bool, Error> {
+        let write_txn = self.db()?.begin_write()?;
+        let new = {
+            let mut table = write_txn.open_table(COMMANDTABLE)?;
+            let forgotten: Vec<String> = table
+                .iter()?
+                .filter(|(_, applied_at)| applied_at.value() < forget_before)
+                .map(|(id, _)| id.value().to_string())
+                .collect();
+            for id in forgotten {
+                table.remove(id.as_str())?;
+            }
+
+            let new = table.get(command_id)?.is_none();
+            if new {
+                table.insert(command_id, now)?;
+            }
+            new
+        };
+        write_txn.commit()?;
+        Ok(new)
+    }
+
     pub fn clear_tables(&self) -> Result<(), Error> {
-        let write_txn = self.db.begin_write()?;
+        let write_txn = self.db()?.begin_write()?;
 
         {
             let mut table = write_txn.open_table(ACCOUNTTABLE)?;
@@ -199,3 +728,20 @@ impl Db {
         Ok(())
     }
 }
+
+/// Writes an account in a transaction that may also write other tables
+fn insert_account(write_txn: &WriteTransaction, account: &Account) -> Result<(), Error> {
+    let mut table = write_txn.open_table(ACCOUNTTABLE)?;
+    table.insert(account.pubkey.as_str(), account.status as u8)?;
+
+    let mut expiry_table = write_txn.open_table(ACCOUNTEXPIRYTABLE)?;
+    match account.expires_at {
+        Some(expires_at) => {
+            expiry_table.insert(account.pubkey.as_str(), expires_at)?;
+        }
+        None => {
+            expiry_table.remove(account.pubkey.as_str())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Admin commands sent as encrypted direct messages to the service key
+//!
+//! The decrypted content is a json array of command tags, e.g. `[["allow", <pubkey>]]`,
+//! using the same commands as kind 4242 events. The response is sent back encrypted the
+//! same way as the command. Content can be encrypted with NIP-44 or NIP-04. NIP-17 gift
+//! wrapped messages are not supported.
+
+use nostr_sdk::nips::nip04;
+use nostr_sdk::prelude::*;
+
+use crate::command::CommandReport;
+use crate::error::Error;
+use crate::nauthz_grpc::event::TagEntry;
+use crate::nauthz_grpc::Event;
+use crate::nip44;
+
+/// Kind of encrypted direct messages
+pub const DM_KIND: u64 = 4;
+
+/// How the content of a direct message is encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Nip04,
+    Nip44,
+}
+
+impl Encryption {
+    /// NIP-04 content is base64 with an `?iv=` suffix, NIP-44 content is plain base64
+    pub fn of(content: &str) -> Self {
+        match content.contains("?iv=") {
+            true => Encryption::Nip04,
+            false => Encryption::Nip44,
+        }
+    }
+
+    fn decrypt(
+        &self,
+        secret_key: &SecretKey,
+        pubkey: &XOnlyPublicKey,
+        content: &str,
+    ) -> Result<String, Error> {
+        match self {
+            Encryption::Nip04 => {
+                nip04::decrypt(secret_key, pubkey, content).map_err(|_| Error::DecryptionError)
+            }
+            Encryption::Nip44 => {
+                nip44::decrypt(&nip44::conversation_key(secret_key, pubkey)?, content)
+            }
+        }
+    }
+
+    fn encrypt(
+        &self,
+        secret_key: &SecretKey,
+        pubkey: &XOnlyPublicKey,
+        content: &str,
+    ) -> Result<String, Error> {
+        match self {
+            Encryption::Nip04 => {
+                nip04::encrypt(secret_key, pubkey, content).map_err(|_| Error::EncryptionError)
+            }
+            Encryption::Nip44 => {
+                nip44::encrypt(&nip44::conversation_key(secret_key, pubkey)?, content)
+            }
+        }
+    }
+}
+
+/// Checks an event is a direct message to the hex `pubkey`
+pub fn is_addressed_to(event: &Event, pubkey: &str) -> bool {
+    event.kind == DM_KIND
+        && event.tags.iter().any(|tag| {
+            tag.values.first().map(|v| v.as_str()) == Some("p")
+                && tag.values.get(1).map(|v| v.as_str()) == Some(pubkey)
+        })
+}
+
+/// Decrypts the command tags of a direct message sent to the service,
+/// with the encryption the response should use
+pub fn decrypt_commands(keys: &Keys, event: &Event) -> Result<(Vec<TagEntry>, Encryption), Error> {
+    let sender = XOnlyPublicKey::from_slice(&event.pubkey).map_err(|_| Error::DecryptionError)?;
+    let secret_key = keys.secret_key().map_err(|_| Error::DecryptionError)?;
+    let encryption = Encryption::of(&event.content);
+    let content = encryption.decrypt(&secret_key, &sender, &event.content)?;
+
+    let tags: Vec<Vec<String>> = serde_json::from_str(&content)?;
+    let tags = tags.into_iter().map(|values| TagEntry { values }).collect();
+    Ok((tags, encryption))
+}
+
+/// Encrypted direct message to the admin tagged to the command event
+pub fn encrypted_response(
+    keys: &Keys,
+    command_id: EventId,
+    admin: XOnlyPublicKey,
+    report: &CommandReport,
+    encryption: Encryption,
+) -> Result<nostr_sdk::Event, Error> {
+    let content = serde_json::to_string(report)?;
+    let secret_key = keys.secret_key().map_err(|_| Error::SigningError)?;
+    let content = encryption.encrypt(&secret_key, &admin, &content)?;
+    let tags = [Tag::PubKey(admin, None), Tag::Event(command_id, None, None)];
+
+    EventBuilder::new(Kind::EncryptedDirectMessage, content, &tags)
+        .to_event(keys)
+        .map_err(|_| Error::SigningError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Direct message from `admin` to `service` as the home relay sends it
+    fn direct_message(admin: &Keys, service: &Keys, content: String) -> Event {
+        let tags = [Tag::PubKey(service.public_key(), None)];
+        let event = EventBuilder::new(Kind::EncryptedDirectMessage, content, &tags)
+            .to_event(admin)
+            .unwrap();
+        Event {
+            id: event.id.as_bytes().to_vec(),
+            pubkey: event.pubkey.serialize().to_vec(),
+            created_at: event.created_at.as_u64(),
+            kind: DM_KIND,
+            content: event.content,
+            tags: event
+                .tags
+                .iter()
+                .map(|tag| TagEntry {
+                    values: tag.as_vec(),
+                })
+                .collect(),
+            sig: event.sig.as_ref().to_vec(),
+        }
+    }
+
+    /// Checks the admin can read the response to a command
+    fn check_response(service: &Keys, admin: &Keys, encryption: Encryption) {
+        let mut report = CommandReport::default();
+        report.apply("deny", &["a".repeat(64)]);
+        let command_id = EventId::from_slice(&[1; 32]).unwrap();
+        let response =
+            encrypted_response(service, command_id, admin.public_key(), &report, encryption)
+                .unwrap();
+
+        assert_eq!(response.kind, Kind::EncryptedDirectMessage);
+        assert_eq!(response.pubkey, service.public_key());
+        assert!(response
+            .tags
+            .contains(&Tag::PubKey(admin.public_key(), None)));
+        assert!(response.tags.contains(&Tag::Event(command_id, None, None)));
+
+        let content = encryption
+            .decrypt(
+                &admin.secret_key().unwrap(),
+                &service.public_key(),
+                &response.content,
+            )
+            .unwrap();
+        assert_eq!(content, serde_json::to_string(&report).unwrap());
+    }
+
+    #[test]
+    fn test_nip44_commands() {
+        let (admin, service) = (Keys::generate(), Keys::generate());
+        let service_hex = service.public_key().to_string();
+        let commands =
+            r#"[["allow","3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d"]]"#;
+
+        let key =
+            nip44::conversation_key(&admin.secret_key().unwrap(), &service.public_key()).unwrap();
+        let event = direct_message(&admin, &service, nip44::encrypt(&key, commands).unwrap());
+        assert!(is_addressed_to(&event, &service_hex));
+        assert!(!is_addressed_to(&event, &admin.public_key().to_string()));
+
+        let (tags, encryption) = decrypt_commands(&service, &event).unwrap();
+        assert_eq!(encryption, Encryption::Nip44);
+        assert_eq!(
+            tags,
+            vec![TagEntry {
+                values: vec![
+                    "allow".to_string(),
+                    "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_string()
+                ]
+            }]
+        );
+
+        // Only the service can decrypt it
+        assert!(decrypt_commands(&Keys::generate(), &event).is_err());
+
+        check_response(&service, &admin, Encryption::Nip44);
+    }
+
+    #[test]
+    fn test_nip04_commands() {
+        let (admin, service) = (Keys::generate(), Keys::generate());
+        let content = "AAAA?iv=AAAAAAAAAAAAAAAAAAAAAA==";
+        assert_eq!(Encryption::of(content), Encryption::Nip04);
+
+        let content = nip04::encrypt(
+            &admin.secret_key().unwrap(),
+            &service.public_key(),
+            r#"[["deny","3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d"]]"#,
+        )
+        .unwrap();
+        let event = direct_message(&admin, &service, content);
+        let (tags, encryption) = decrypt_commands(&service, &event).unwrap();
+        assert_eq!(encryption, Encryption::Nip04);
+        assert_eq!(tags[0].values[0], "deny");
+
+        check_response(&service, &admin, Encryption::Nip04);
+    }
+}
@@ -6,12 +6,42 @@ pub enum Error {
     DBError(redb::Error),
     #[error("Not in db")]
     NotFound,
+    #[error("DB is closed")]
+    DbClosed,
     #[error("Serde error")]
     SerdeError(serde_json::Error),
     #[error("Join error")]
     JoinError(tokio::task::JoinError),
     #[error("Invoice Error")]
     InvoiceError,
+    #[error("Too many invoices")]
+    TooManyInvoices,
+    #[error("Relay error")]
+    RelayError,
+    #[error("Fetch error")]
+    FetchError,
+    #[error("Sync error: {0}")]
+    SyncError(String),
+    #[error("Invalid key")]
+    InvalidKey,
+    #[error("IO error")]
+    IoError(std::io::Error),
+    #[error("Decryption error")]
+    DecryptionError,
+    #[error("Encryption error")]
+    EncryptionError,
+    #[error("Signing error")]
+    SigningError,
+    #[error("Auth error: {0}")]
+    AuthError(&'static str),
+    #[error("Config error: {0}")]
+    ConfigError(config::ConfigError),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid zap: {0}")]
+    InvalidZap(&'static str),
+    #[error("Invalid content pattern")]
+    RegexError(regex::Error),
 }
 
 impl From<redb::Error> for Error {
@@ -31,3 +61,21 @@ impl From<tokio::task::JoinError> for Error {
         Self::JoinError(err)
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Self {
+        Self::RegexError(err)
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Self {
+        Self::ConfigError(err)
+    }
+}
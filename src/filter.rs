@@ -0,0 +1,121 @@
+//! Filters applied to events fetched as context before they are admitted and imported
+
+use nostr_sdk::Event;
+use regex::Regex;
+
+use std::collections::HashSet;
+
+use crate::config::Filters;
+use crate::error::Error;
+
+#[derive(Debug, Default)]
+pub struct ContentFilter {
+    denied_events: HashSet<String>,
+    patterns: Vec<Regex>,
+    max_content_length: Option<usize>,
+    max_links: Option<usize>,
+}
+
+impl ContentFilter {
+    pub fn new(filters: &Filters) -> Result<Self, Error> {
+        let patterns = filters
+            .content_patterns
+            .iter()
+            .map(|p| Regex::new(p))
+            .collect::<Result<Vec<Regex>, regex::Error>>()?;
+
+        Ok(Self {
+            denied_events: filters.denied_events.clone(),
+            patterns,
+            max_content_length: filters.max_content_length,
+            max_links: filters.max_links,
+        })
+    }
+
+    /// Checks a hex event id is on the denylist
+    pub fn is_denied(&self, event_id: &str) -> bool {
+        self.denied_events.contains(event_id)
+    }
+
+    /// Checks an event can be imported, returning the reason if it cannot
+    pub fn check(&self, event: &Event) -> Result<(), &'static str> {
+        if self.is_denied(&event.id.to_hex()) {
+            return Err("event is denied");
+        }
+
+        if self
+            .max_content_length
+            .is_some_and(|max| event.content.chars().count() > max)
+        {
+            return Err("content too long");
+        }
+
+        if self
+            .max_links
+            .is_some_and(|max| count_links(&event.content) > max)
+        {
+            return Err("too many links");
+        }
+
+        if self.patterns.iter().any(|p| p.is_match(&event.content)) {
+            return Err("content matches a denied pattern");
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts http and https urls in content
+fn count_links(content: &str) -> usize {
+    content.matches("http://").count() + content.matches("https://").count()
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_content_filter() {
+        let keys = Keys::generate();
+        let note = |content: &str| {
+            EventBuilder::new_text_note(content, &[])
+                .to_event(&keys)
+                .unwrap()
+        };
+        let spam = note("buy now https://a.example https://b.example");
+
+        let filter = ContentFilter::new(&Filters {
+            denied_events: HashSet::from([spam.id.to_hex()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(filter.check(&spam).is_err());
+        assert!(filter.check(&note("hello")).is_ok());
+
+        let filter = ContentFilter::new(&Filters {
+            content_patterns: vec!["(?i)buy now".to_string()],
+            max_content_length: Some(20),
+            max_links: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(filter.check(&spam), Err("content too long"));
+        assert_eq!(
+            filter.check(&note("https://a https://b")),
+            Err("too many links")
+        );
+        assert_eq!(
+            filter.check(&note("BUY NOW")),
+            Err("content matches a denied pattern")
+        );
+        assert!(filter.check(&note("gm https://a")).is_ok());
+
+        assert!(ContentFilter::new(&Filters {
+            content_patterns: vec!["(".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
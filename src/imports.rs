@@ -0,0 +1,648 @@
+//! Events the service is importing to the home relay
+//!
+//! Fetched events are filtered, admitted and broadcast to the home relay.
+//! Ids are added to the pending imports before events are broadcast so that when they
+//! come back through `event_admit` they are permitted without a db lookup. Entries expire
+//! after a TTL in case the home relay never sends the event back, e.g. it already had it.
+//!
+//! Fetches and broadcasts run in the background as jobs. On shutdown running jobs are
+//! given time to finish, those that do not are saved and run again on the next start.
+
+use nostr_sdk::prelude::XOnlyPublicKey;
+use nostr_sdk::{Event, EventId, Filter, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::NostrClient;
+use crate::config::Imports;
+use crate::error::Error;
+use crate::metrics::{Metrics, METRICS};
+use crate::relays::RelayFetch;
+use crate::reload::SharedConfig;
+use crate::repo::Repo;
+use crate::shutdown::Shutdown;
+use crate::{sync, utils};
+
+/// How long an import is waited for
+pub const IMPORT_TTL: Duration = Duration::from_secs(120);
+/// Authors synced with one filter
+const SYNC_AUTHORS: usize = 100;
+/// How often the config is checked for sync relays while sync is disabled
+const SYNC_DISABLED_CHECK: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct PendingImports {
+    ids: HashMap<String, Instant>,
+    ttl: Duration,
+}
+
+impl Default for PendingImports {
+    fn default() -> Self {
+        Self::new(IMPORT_TTL)
+    }
+}
+
+impl PendingImports {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ids: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Adds hex event ids about to be broadcast
+    pub fn insert<I>(&mut self, ids: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let now = Instant::now();
+        self.ids
+            .retain(|_, added| now.duration_since(*added) < self.ttl);
+        self.ids.extend(ids.into_iter().map(|id| (id, now)));
+    }
+
+    /// Removes an id, returning true if it was a pending import
+    pub fn take(&mut self, id: &str) -> bool {
+        match self.ids.remove(id) {
+            Some(added) => added.elapsed() < self.ttl,
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+/// Background work of the importer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportJob {
+    /// Fetch and import events referenced by an admitted event, with relay hints
+    Referenced(HashMap<EventId, Option<String>>),
+    /// Fetch and import past events of authors
+    Backfill(Vec<String>),
+    /// Broadcast events signed by the service to the home relay
+    Publish(Vec<Event>),
+    /// Sync the events of admitted pubkeys with relays
+    Sync(Vec<String>),
+    /// Import new events of admitted pubkeys received from a default relay
+    Mirrored(Vec<Event>),
+}
+
+/// Jobs waiting for a worker and running.
+/// Referenced ids already queued or being fetched are not queued again, and queued
+/// referenced jobs are merged so their ids are fetched in one request.
+#[derive(Debug)]
+pub struct ImportQueue {
+    queued: VecDeque<ImportJob>,
+    running: HashMap<u64, ImportJob>,
+    /// Referenced ids queued or being fetched
+    ids: HashSet<EventId>,
+    next_id: u64,
+    /// Max queued jobs
+    capacity: usize,
+    /// Max ids fetched in one request
+    batch_size: usize,
+}
+
+impl ImportQueue {
+    pub fn new(settings: &Imports) -> Self {
+        Self {
+            queued: VecDeque::new(),
+            running: HashMap::new(),
+            ids: HashSet::new(),
+            next_id: 0,
+            capacity: settings.queue_size,
+            batch_size: settings.batch_size,
+        }
+    }
+
+    /// Queues a job, returning it if the queue is full
+    pub fn push(&mut self, job: ImportJob) -> Result<(), ImportJob> {
+        if self.queued.len() >= self.capacity {
+            return Err(job);
+        }
+
+        let job = match job {
+            ImportJob::Referenced(mut referenced) => {
+                referenced.retain(|id, _| !self.ids.contains(id));
+                if referenced.is_empty() {
+                    return Ok(());
+                }
+                self.ids.extend(referenced.keys());
+                ImportJob::Referenced(referenced)
+            }
+            ImportJob::Sync(_) if self.is_syncing() => return Ok(()),
+            job => job,
+        };
+        self.queued.push_back(job);
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Takes the next job, merging queued referenced jobs up to the batch size
+    pub fn take(&mut self) -> Option<(u64, ImportJob)> {
+        let job = match self.queued.pop_front()? {
+            ImportJob::Referenced(mut referenced) => {
+                while referenced.len() < self.batch_size {
+                    let position = self.queued.iter().position(|job| {
+                        matches!(job, ImportJob::Referenced(more)
+                            if referenced.len() + more.len() <= self.batch_size)
+                    });
+                    match position.and_then(|position| self.queued.remove(position)) {
+                        Some(ImportJob::Referenced(more)) => referenced.extend(more),
+                        _ => break,
+                    }
+                }
+                ImportJob::Referenced(referenced)
+            }
+            job => job,
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.insert(id, job.clone());
+        self.update_metrics();
+        Some((id, job))
+    }
+
+    /// Removes a job a worker has finished
+    pub fn finish(&mut self, id: u64) {
+        if let Some(ImportJob::Referenced(referenced)) = self.running.remove(&id) {
+            for id in referenced.keys() {
+                self.ids.remove(id);
+            }
+        }
+        self.update_metrics();
+    }
+
+    /// Jobs running and queued
+    pub fn unfinished(&self) -> Vec<ImportJob> {
+        self.running
+            .values()
+            .chain(self.queued.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// True if a sync is queued or running
+    fn is_syncing(&self) -> bool {
+        self.queued
+            .iter()
+            .chain(self.running.values())
+            .any(|job| matches!(job, ImportJob::Sync(_)))
+    }
+
+    /// Number of queued jobs
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// True if no jobs are queued or running
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.running.is_empty()
+    }
+
+    fn update_metrics(&self) {
+        Metrics::set(&METRICS.import_queue, self.queued.len() as u64);
+        Metrics::set(&METRICS.imports_running, self.running.len() as u64);
+    }
+}
+
+/// Queue shared by the importer and its workers
+#[derive(Debug)]
+pub struct ImportJobs {
+    queue: std::sync::Mutex<ImportQueue>,
+    /// Notified when a job is queued
+    queued: Notify,
+    /// Notified when a worker takes a job
+    space: Notify,
+}
+
+impl ImportJobs {
+    pub fn new(settings: &Imports) -> Self {
+        Self {
+            queue: std::sync::Mutex::new(ImportQueue::new(settings)),
+            queued: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+}
+
+/// Fetches events and imports them to the home relay
+#[derive(Clone)]
+pub struct Importer {
+    pub repo: Arc<Mutex<Repo>>,
+    pub nostr: Arc<Mutex<NostrClient>>,
+    pub pending: Arc<std::sync::Mutex<PendingImports>>,
+    /// Jobs run by the workers
+    pub jobs: Arc<ImportJobs>,
+    /// Filters and home relay are read from the config
+    pub config: SharedConfig,
+}
+
+impl Importer {
+    /// Queues a job to run in the background, waiting for space if the queue is full
+    pub async fn queue(&self, mut job: ImportJob) {
+        loop {
+            let space = self.jobs.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match self.jobs.queue.lock().unwrap().push(job) {
+                Ok(()) => {
+                    self.jobs.queued.notify_waiters();
+                    return;
+                }
+                Err(full) => job = full,
+            }
+            debug!("Import queue is full, waiting");
+            space.await;
+        }
+    }
+
+    /// Starts workers that run queued jobs
+    pub fn start(&self, workers: usize) -> Vec<JoinHandle<()>> {
+        (0..workers)
+            .map(|_| tokio::spawn(self.clone().work()))
+            .collect()
+    }
+
+    async fn work(self) {
+        loop {
+            let queued = self.jobs.queued.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+
+            let next = self.jobs.queue.lock().unwrap().take();
+            let (id, job) = match next {
+                Some(next) => next,
+                None => {
+                    queued.await;
+                    continue;
+                }
+            };
+            self.jobs.space.notify_waiters();
+
+            if let Err(err) = self.run(job).await {
+                error!("Error running import job: {}", err);
+            }
+            self.jobs.queue.lock().unwrap().finish(id);
+        }
+    }
+
+    pub async fn run(&self, job: ImportJob) -> Result<(), Error> {
+        match job {
+            ImportJob::Referenced(referenced) => self.import_referenced(&referenced).await,
+            ImportJob::Backfill(authors) => self.backfill(&authors).await,
+            ImportJob::Publish(events) => {
+                self.client()
+                    .await
+                    .broadcast_events(&self.config.current().settings.info.relay, Arc::new(events))
+                    .await
+            }
+            ImportJob::Sync(relays) => self.sync(&relays).await,
+            ImportJob::Mirrored(events) => self.import(events).await,
+        }
+    }
+
+    /// Queues the jobs saved at the last shutdown
+    pub async fn resume(&self) -> Result<(), Error> {
+        let jobs = self.repo.lock().await.take_import_jobs()?;
+        if !jobs.is_empty() {
+            info!("Resuming {} import jobs", jobs.len());
+        }
+        for job in jobs {
+            self.queue(job).await;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for queued and running jobs to finish then saves those that
+    /// have not. Returns the number of jobs saved.
+    pub async fn drain(&self, timeout: Duration) -> Result<usize, Error> {
+        let deadline = Instant::now() + timeout;
+        while !self.jobs.queue.lock().unwrap().is_idle() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let unfinished = self.jobs.queue.lock().unwrap().unfinished();
+        if !unfinished.is_empty() {
+            self.repo.lock().await.save_import_jobs(&unfinished)?;
+        }
+        Ok(unfinished.len())
+    }
+
+    /// Fetches and imports events referenced by an admitted event
+    pub async fn import_referenced(
+        &self,
+        referenced: &HashMap<EventId, Option<String>>,
+    ) -> Result<(), Error> {
+        // Ids are only allowed in the event table once fetched and passing the filters,
+        // otherwise a filtered event would be admitted when published directly
+        let mut referenced: HashMap<EventId, Option<String>> = self
+            .repo
+            .lock()
+            .await
+            .importable_events(referenced)?
+            .into_iter()
+            .filter(|(id, _)| !self.config.current().filter.is_denied(&id.to_hex()))
+            .collect();
+        if referenced.is_empty() {
+            return Ok(());
+        }
+
+        // Only events the home relay does not have are fetched
+        let relay = self.config.current().settings.info.relay.clone();
+        let ids = referenced.keys().copied().collect();
+        match self.client().await.stored_on_home_relay(&relay, &ids).await {
+            Ok(stored) if !stored.is_empty() => {
+                debug!("{} referenced events are on the home relay", stored.len());
+                Metrics::add(&METRICS.events_stored, stored.len() as u64);
+                referenced.retain(|id, _| !stored.contains(id));
+                if referenced.is_empty() {
+                    return Ok(());
+                }
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Could not query home relay, fetching all events: {}", err),
+        }
+
+        let nostr = self.client().await;
+        let hints = referenced
+            .values()
+            .flatten()
+            .filter_map(|relay| Url::parse(relay).ok());
+        let relays = self.repo.lock().await.ranked_relays(
+            nostr
+                .relays
+                .iter()
+                .cloned()
+                .chain(hints)
+                .collect::<HashSet<Url>>(),
+        )?;
+        let fetched = nostr
+            .fetch_events(&referenced, &relays)
+            .await
+            .map_err(|_| Error::FetchError)?;
+
+        self.record(&fetched.relays).await?;
+        self.import(fetched.events).await
+    }
+
+    /// Fetches and imports past events of authors
+    pub async fn backfill(&self, authors: &[String]) -> Result<(), Error> {
+        let nostr = self.client().await;
+        let relays = self
+            .repo
+            .lock()
+            .await
+            .ranked_relays(nostr.relays.iter().cloned())?;
+        let fetched = nostr
+            .fetch_authors_events(authors, &relays)
+            .await
+            .map_err(|_| Error::FetchError)?;
+
+        self.record(&fetched.relays).await?;
+        debug!("Backfilling {} events", fetched.events.len());
+        self.import(fetched.events).await
+    }
+
+    /// Syncs the events of admitted pubkeys with relays, importing those the home relay is
+    /// missing and, if `upload` is set, sending the relays those they are missing. The home
+    /// relay is asked for its events once for all the relays.
+    pub async fn sync(&self, relays: &[String]) -> Result<(), Error> {
+        let config = self.config.current();
+        let upload = match &config.settings.sync {
+            Some(sync) => sync.upload,
+            None => return Ok(()),
+        };
+        let batch_size = config.settings.imports.batch_size;
+        let home = Url::parse(&config.settings.info.relay).map_err(|_| Error::RelayError)?;
+        let relays: Vec<Url> = relays.iter().flat_map(|r| Url::parse(r)).collect();
+        let nostr = self.client().await;
+        let keys = nostr.client.keys();
+
+        let authors = self.repo.lock().await.get_admitted_pubkeys()?;
+        let mut synced_counts: HashMap<&Url, (usize, usize)> = HashMap::new();
+        let mut failed = HashSet::new();
+        for authors in authors.chunks(SYNC_AUTHORS) {
+            let authors = authors
+                .iter()
+                .flat_map(|a| XOnlyPublicKey::from_str(a))
+                .collect();
+            let stored = sync::HomeSet::fetch(&home, &keys, Filter::new().authors(authors)).await?;
+
+            for relay in &relays {
+                // A relay that fails is not synced with again until the next sync
+                if failed.contains(relay) {
+                    continue;
+                }
+                let synced = match stored.sync(relay, upload).await {
+                    Ok(synced) => synced,
+                    Err(err) => {
+                        warn!("Could not sync with {relay}: {err}");
+                        failed.insert(relay);
+                        continue;
+                    }
+                };
+                let (fetched, sent) = synced_counts.entry(relay).or_default();
+                *fetched += synced.missing.len();
+                for events in synced.missing.chunks(batch_size) {
+                    self.import(events.to_vec()).await?;
+                }
+                *sent += synced.extra.len();
+                for events in synced.extra.chunks(batch_size) {
+                    nostr
+                        .broadcast_events(relay.as_str(), Arc::new(events.to_vec()))
+                        .await?;
+                }
+            }
+        }
+
+        for (relay, (fetched, sent)) in synced_counts {
+            Metrics::add(&METRICS.events_sent, sent as u64);
+            info!("Synced with {relay}, fetched {fetched} events and sent {sent}");
+        }
+        Ok(())
+    }
+
+    /// Queues a sync with each relay in `[sync]`, every `interval`
+    pub async fn schedule_syncs(self, shutdown: Shutdown) {
+        loop {
+            let sync = self.config.current().settings.sync.clone();
+            let wait = match sync {
+                Some(sync) => {
+                    if !sync.relays.is_empty() {
+                        self.queue(ImportJob::Sync(sync.relays)).await;
+                    }
+                    Duration::from_secs(sync.interval)
+                }
+                None => SYNC_DISABLED_CHECK,
+            };
+            tokio::select! {
+                _ = shutdown.clone().wait() => return,
+                _ = tokio::time::sleep(wait) => (),
+            }
+        }
+    }
+
+    /// The client, cloned out of the lock so jobs can use it at the same time
+    /// and a slow relay does not hold up the others
+    async fn client(&self) -> NostrClient {
+        self.nostr.lock().await.clone()
+    }
+
+    /// Records how each relay did in its score
+    async fn record(&self, fetches: &[RelayFetch]) -> Result<(), Error> {
+        let penalise = self.config.current().settings.imports.penalise_bad_relays;
+        self.repo
+            .lock()
+            .await
+            .record_relay_fetches(fetches, penalise)
+    }
+
+    /// Admits fetched events that pass the filters and broadcasts them to the home relay
+    pub async fn import(&self, events: Vec<Event>) -> Result<(), Error> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        Metrics::add(&METRICS.events_fetched, events.len() as u64);
+
+        let events = self.admit(events).await?;
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(events.iter().map(|e| e.id.to_hex()));
+        self.client()
+            .await
+            .broadcast_events(&self.config.current().settings.info.relay, Arc::new(events))
+            .await
+    }
+
+    /// Admits the events that pass the filters and are not from denied authors,
+    /// denied, deleted or expired. Returns the admitted events.
+    async fn admit(&self, events: Vec<Event>) -> Result<Vec<Event>, Error> {
+        let repo = self.repo.lock().await;
+        let config = self.config.current();
+
+        let fetched = events.len();
+        let mut passed = Vec::with_capacity(events.len());
+        for event in events {
+            if let Err(reason) = config.filter.check(&event) {
+                debug!("Not importing event {}: {}", event.id, reason);
+                continue;
+            }
+            if repo.is_denied(&event.pubkey.to_string())? {
+                debug!("Not importing event {} from denied author", event.id);
+                continue;
+            }
+            if repo.deny_if_deleted(&event.id.to_hex(), &event.pubkey.to_string())? {
+                debug!("Not importing event {} deleted by its author", event.id);
+                continue;
+            }
+            passed.push(event);
+        }
+        Metrics::add(&METRICS.events_filtered, (fetched - passed.len()) as u64);
+
+        // Expired events are denied by the sweep so they are not fetched again
+        repo.set_event_expiries(&passed)?;
+        let now = utils::unix_time();
+        let (expired, passed): (Vec<Event>, Vec<Event>) = passed
+            .into_iter()
+            .partition(|e| utils::expiration(e).is_some_and(|expires_at| expires_at <= now));
+        if !expired.is_empty() {
+            debug!("Skipping {} expired events", expired.len());
+            repo.sweep_expired_events()?;
+        }
+
+        let ids: HashMap<EventId, Option<String>> = passed.iter().map(|e| (e.id, None)).collect();
+        let admitted = repo.admit_events(&ids)?;
+        Ok(passed
+            .into_iter()
+            .filter(|e| admitted.contains_key(&e.id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_imports() {
+        let mut imports = PendingImports::default();
+        imports.insert(vec!["a".to_string(), "b".to_string()]);
+        assert!(imports.take("a"));
+        assert!(!imports.take("a"));
+        assert_eq!(imports.len(), 1);
+
+        let mut imports = PendingImports::new(Duration::ZERO);
+        imports.insert(vec!["a".to_string()]);
+        assert!(!imports.take("a"));
+    }
+
+    #[test]
+    fn test_import_queue() {
+        let id = |byte: u8| EventId::from_slice(&[byte; 32]).unwrap();
+        let referenced =
+            |ids: &[u8]| ImportJob::Referenced(ids.iter().map(|b| (id(*b), None)).collect());
+
+        let mut queue = ImportQueue::new(&Imports {
+            workers: 1,
+            queue_size: 3,
+            batch_size: 3,
+            ..Default::default()
+        });
+        queue.push(referenced(&[1, 2])).unwrap();
+        // Ids already queued are not queued again
+        queue.push(referenced(&[2])).unwrap();
+        assert_eq!(queue.len(), 1);
+        queue
+            .push(ImportJob::Backfill(vec!["a".to_string()]))
+            .unwrap();
+        queue.push(referenced(&[2, 3])).unwrap();
+        assert!(queue.push(referenced(&[4])).is_err());
+
+        // Referenced jobs are merged up to the batch size
+        let (running, job) = queue.take().unwrap();
+        assert_eq!(job, referenced(&[1, 2, 3]));
+        assert_eq!(queue.len(), 1);
+        queue.push(referenced(&[3])).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.unfinished().len(), 2);
+
+        // Ids can be queued again once fetched
+        queue.finish(running);
+        queue.push(referenced(&[3])).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.is_idle());
+
+        // Relays are not synced with twice at once
+        let sync = || ImportJob::Sync(vec!["wss://relay.example.com".to_string()]);
+        queue.push(sync()).unwrap();
+        while !matches!(queue.take(), Some((_, ImportJob::Sync(_)))) {}
+        queue.push(sync()).unwrap();
+        assert!(queue.is_empty());
+    }
+}
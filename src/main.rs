@@ -1,7 +1,7 @@
-use axum::http::HeaderMap;
-use db::Status;
+use db::Role;
 use error::Error;
-use nostr_sdk::{EventId, Tag};
+use nostr_sdk::prelude::XOnlyPublicKey;
+use nostr_sdk::{EventId, Keys, Tag};
 use tokio::sync::Mutex;
 use tonic::{transport::Server, Request, Response};
 
@@ -9,39 +9,135 @@ use nauthz_grpc::authorization_server::{Authorization, AuthorizationServer};
 use nauthz_grpc::{Decision, Event, EventReply, EventRequest};
 
 use crate::client::NostrClient;
-use crate::config::Settings;
-use crate::repo::Repo;
-
-use serde::{Deserialize, Serialize};
-
-use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    routing::{get, post},
-    Router,
-};
+use crate::imports::{ImportJob, ImportJobs, Importer, PendingImports};
+use crate::metrics::{Metrics, METRICS};
+use crate::mirror::Mirror;
+use crate::payment::Payments;
+use crate::reload::{LiveConfig, Reloader, SharedConfig};
+use crate::repo::{Admission, Repo};
+use crate::shutdown::Shutdown;
+use crate::zap::ZapAdmission;
 
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::task;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 pub mod nauthz_grpc {
     tonic::include_proto!("nauthz");
 }
 
+pub mod api;
+pub mod bolt11;
 pub mod client;
+pub mod command;
 pub mod config;
 pub mod db;
+pub mod dm;
 pub mod error;
+pub mod filter;
+pub mod imports;
+pub mod metrics;
+pub mod mirror;
+pub mod negentropy;
+pub mod nip44;
+pub mod nip98;
+pub mod payment;
+pub mod pow;
+pub mod relays;
+pub mod reload;
 pub mod repo;
+pub mod shutdown;
+pub mod sync;
 pub mod utils;
+pub mod zap;
+
+/// Config file read on start and watched for changes
+const CONFIG_FILE: &str = "config.toml";
+/// How long running imports are waited for on shutdown before they are saved
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often expired events are swept from the event table
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
 
 pub struct EventAuthz {
     pub repo: Arc<Mutex<Repo>>,
     pub nostr_client: Arc<Mutex<NostrClient>>,
-    pub settings: Settings,
+    /// Settings and policies, replaced when the config is reloaded
+    pub config: SharedConfig,
+    /// Keys of the service
+    pub service_keys: Keys,
+    /// Fetches events and imports them to the home relay
+    pub importer: Importer,
+    /// Admission of senders of zaps to admins
+    pub zaps: Arc<ZapAdmission>,
+}
+
+impl EventAuthz {
+    /// Applies admin commands, then queues any backfills and the publishing of the response,
+    /// encrypted if the commands were
+    async fn apply_commands(&self, event: Event, role: Role, encryption: Option<dm::Encryption>) {
+        // Signed commands can be resent by anyone, so each is applied once and only while recent
+        let repo = self.repo.lock().await;
+        match repo.record_command(&hex::encode(&event.id), event.created_at) {
+            Ok(true) => (),
+            Ok(false) => {
+                warn!(
+                    "Ignoring replayed or stale admin command {}",
+                    hex::encode(&event.id)
+                );
+                return;
+            }
+            Err(err) => {
+                error!("Error recording admin command: {}", err);
+                return;
+            }
+        }
+        let report = repo.handle_admission_update(event.clone(), role).await;
+        drop(repo);
+
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                error!("Error handling admission update: {}", err);
+                return;
+            }
+        };
+
+        let (command_id, admin) = match (
+            EventId::from_slice(&event.id),
+            XOnlyPublicKey::from_slice(&event.pubkey),
+        ) {
+            (Ok(id), Ok(admin)) => (id, admin),
+            _ => {
+                error!("Admin command has invalid id or pubkey");
+                return;
+            }
+        };
+
+        if !report.backfill.is_empty() {
+            self.importer
+                .queue(ImportJob::Backfill(report.backfill.clone()))
+                .await;
+        }
+
+        let response = match encryption {
+            Some(encryption) => {
+                dm::encrypted_response(&self.service_keys, command_id, admin, &report, encryption)
+            }
+            None => report.to_event(&self.service_keys, command_id, admin),
+        };
+        match response {
+            Ok(response) => {
+                self.importer
+                    .queue(ImportJob::Publish(vec![response]))
+                    .await
+            }
+            Err(err) => error!("Error responding to admin command: {}", err),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -63,20 +159,34 @@ impl Authorization for EventAuthz {
 
         let author = hex::encode(author);
 
+        // The service is a trusted importer, its sessions and its own events are always admitted
+        let service_pubkey = self.service_keys.public_key().to_string();
+        if author.eq(&service_pubkey) {
+            debug!("Admitting event from service");
+            return Ok(Response::new(nauthz_grpc::EventReply {
+                decision: Decision::Permit as i32,
+                message: Some("Ok".to_string()),
+            }));
+        }
+
+        // Events the service is importing are admitted without checking the db
+        let event_id = hex::encode(&event.id);
+        if self.importer.pending.lock().unwrap().take(&event_id) {
+            info!("Admitting imported event {}", event_id);
+            Metrics::inc(&METRICS.events_imported);
+            return Ok(Response::new(nauthz_grpc::EventReply {
+                decision: Decision::Permit as i32,
+                message: Some("Ok".to_string()),
+            }));
+        }
+
         // I just picked this kind number should maybe put more thought into it, NIP?
-        if event.kind == 4242 {
-            // If author is trusted pubkey decode event and update account(s)
-            if self.settings.info.admin_keys.contains(&author) {
-                // TODO: Spawn this to not block
-                self.repo
-                    .lock()
-                    .await
-                    .handle_admission_update(event)
-                    .await
-                    .unwrap();
+        if event.kind == command::COMMAND_KIND {
+            // If author has a role decode event and apply the commands it is permitted to
+            let role = self.repo.lock().await.get_role(&author).unwrap_or(None);
+            if let Some(role) = role {
+                self.apply_commands(event, role, None).await;
 
-                // TODO: This is testing comment out
-                // self.repo.lock().await.get_all_accounts().unwrap();
                 // admit event
                 return Ok(Response::new(nauthz_grpc::EventReply {
                     decision: Decision::Permit as i32,
@@ -85,56 +195,135 @@ impl Authorization for EventAuthz {
             }
         }
 
+        // Commands can also be sent as encrypted direct messages to the service
+        if dm::is_addressed_to(&event, &service_pubkey) {
+            let role = self
+                .repo
+                .lock()
+                .await
+                .get_role(&hex::encode(&event.pubkey))
+                .unwrap_or(None);
+            if let Some(role) = role {
+                match dm::decrypt_commands(&self.service_keys, &event) {
+                    Ok((tags, encryption)) => {
+                        let command = Event {
+                            tags,
+                            ..event.clone()
+                        };
+                        self.apply_commands(command, role, Some(encryption)).await;
+                    }
+                    Err(err) => warn!("Could not decrypt admin command: {}", err),
+                }
+            }
+        }
+
+        // Events on the denylist are not admitted even if allowed in the event table
+        let config = self.config.current();
+        if config.filter.is_denied(&event_id) {
+            Metrics::inc(&METRICS.events_denied);
+            return Ok(Response::new(nauthz_grpc::EventReply {
+                decision: Decision::Deny as i32,
+                message: Some("Event is denied".to_string()),
+            }));
+        }
+
+        // NIP-40 expired events are not admitted
+        if event
+            .expiration()
+            .is_some_and(|expires_at| expires_at <= utils::unix_time())
+        {
+            Metrics::inc(&METRICS.events_denied);
+            return Ok(Response::new(nauthz_grpc::EventReply {
+                decision: Decision::Deny as i32,
+                message: Some("Event has expired".to_string()),
+            }));
+        }
+
+        // Valid zap receipts to admins admit the sender and are permitted
+        if event.kind == zap::ZAP_RECEIPT_KIND && config.settings.zaps.is_some() {
+            match self.zaps.handle_receipt(&(&event).into()).await {
+                Ok(_) => {
+                    return Ok(Response::new(nauthz_grpc::EventReply {
+                        decision: Decision::Permit as i32,
+                        message: Some("Ok".to_string()),
+                    }));
+                }
+                Err(err) => debug!("Zap receipt not counted: {}", err),
+            }
+        }
+
         let event_status = self.repo.lock().await.event_admitted(&author, &event);
 
         // Check author OR event is admitted
         let reply = match event_status {
-            Ok(Status::Allow) => {
-                let repo = self.repo.clone();
-                let nostr = self.nostr_client.clone();
-                let relay = self.settings.info.relay.clone();
-
-                // Spawn task to admit and fetch events
-                task::spawn(async move {
-                    // Check if there are referenced events
-                    if let Ok(referenced) = event.referenced_events() {
-                        if !referenced.is_empty() {
-                            debug!(
-                                "Referenced events: {:?}",
-                                referenced.keys().map(|k| k.to_hex()).collect::<Vec<_>>()
-                            );
-
-                            if let Err(err) = repo.lock().await.admit_events(&referenced) {
-                                error!("Error admitting events: {}", err);
-                                return;
-                            }
-                            let events = match nostr.lock().await.fetch_events(&referenced).await {
-                                Ok(events) => Arc::new(events),
-                                Err(err) => {
-                                    error!("Error fetching events: {}", err);
-                                    return;
-                                }
-                            };
-                            if !events.is_empty() {
-                                if let Err(err) =
-                                    nostr.lock().await.broadcast_events(&relay, events).await
-                                {
-                                    error!("Error broadcasting events: {}", err);
-                                }
-                            }
-                        }
+            Ok(Admission::Admitted) if event.kind == repo::DELETION_KIND => {
+                Metrics::inc(&METRICS.events_admitted);
+                // Deleted events are denied instead of imported once they are seen to be by
+                // the same author, the deletion itself is permitted so the home relay removes them
+                match self.repo.lock().await.handle_deletion(&event) {
+                    Ok(ids) => info!("Recorded events deleted by {}: {:?}", author, ids),
+                    Err(err) => error!("Error handling deletion: {}", err),
+                }
+
+                nauthz_grpc::EventReply {
+                    decision: Decision::Permit as i32,
+                    message: Some("Ok".to_string()),
+                }
+            }
+            Ok(Admission::Admitted) => {
+                Metrics::inc(&METRICS.events_admitted);
+
+                // Fetch and import referenced events in the background
+                if let Ok(referenced) = event.referenced_events() {
+                    if !referenced.is_empty() {
+                        debug!(
+                            "Referenced events: {:?}",
+                            referenced.keys().map(|k| k.to_hex()).collect::<Vec<_>>()
+                        );
+                        self.importer.queue(ImportJob::Referenced(referenced)).await;
                     }
-                });
+                }
 
                 nauthz_grpc::EventReply {
                     decision: Decision::Permit as i32,
                     message: Some("Ok".to_string()),
                 }
             }
-            _ => nauthz_grpc::EventReply {
-                decision: Decision::Deny as i32,
-                message: Some("Not allowed to publish".to_string()),
-            },
+            // Events addressed to admitted pubkeys, their referenced events are not imported
+            Ok(Admission::Inbox) => {
+                Metrics::inc(&METRICS.events_inbox);
+                nauthz_grpc::EventReply {
+                    decision: Decision::Permit as i32,
+                    message: Some("Ok".to_string()),
+                }
+            }
+            // Authors that are not admitted, but not denied, can publish with proof of work
+            // of kinds with a policy. Referenced events of these are not imported.
+            Ok(Admission::Denied) if !self.repo.lock().await.is_denied(&author).unwrap_or(true) => {
+                match config.pow.check(&event) {
+                    Ok(()) => {
+                        Metrics::inc(&METRICS.events_pow);
+                        nauthz_grpc::EventReply {
+                            decision: Decision::Permit as i32,
+                            message: Some("Ok".to_string()),
+                        }
+                    }
+                    Err(message) => {
+                        Metrics::inc(&METRICS.events_denied);
+                        nauthz_grpc::EventReply {
+                            decision: Decision::Deny as i32,
+                            message: Some(message),
+                        }
+                    }
+                }
+            }
+            _ => {
+                Metrics::inc(&METRICS.events_denied);
+                nauthz_grpc::EventReply {
+                    decision: Decision::Deny as i32,
+                    message: Some("Not allowed to publish".to_string()),
+                }
+            }
         };
 
         Ok(Response::new(reply))
@@ -147,42 +336,204 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing_subscriber::fmt::try_init().unwrap();
 
-    let settings = config::Settings::new(&None);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let settings = config::Settings::load(CONFIG_FILE)?;
+    let problems = settings.validate();
+
+    // Print the resolved config and any problems without starting
+    if args.first().map(String::as_str) == Some("check-config") {
+        println!("{}", serde_json::to_string_pretty(&settings.redacted())?);
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    for problem in &problems {
+        error!("Invalid config: {problem}");
+    }
+    if !problems.is_empty() && !args.iter().any(|arg| arg == "--force") {
+        return Err(error::Error::InvalidConfig(
+            "Fix the config or start with --force to run anyway".to_string(),
+        )
+        .into());
+    }
 
-    let repo = Repo::new();
+    let mut repo = Repo::new();
+    repo.set_inbox(settings.inbox.clone());
 
     repo.admit_pubkeys(&settings.info.admin_keys).await?;
+    repo.set_owners(&settings.info.admin_keys)?;
 
     repo.get_all_accounts()?;
 
+    let keys = utils::load_or_create_keys(
+        settings.info.secret_key.as_deref(),
+        Path::new(
+            settings
+                .info
+                .secret_key_file
+                .as_deref()
+                .unwrap_or(utils::DEFAULT_KEY_FILE),
+        ),
+    )?;
+    info!("Service pubkey: {}", keys.public_key());
+
     let nostr_client = Arc::new(Mutex::new(
-        NostrClient::new(&settings.info.default_relays).await?,
+        NostrClient::new(&settings.info.default_relays, &keys).await?,
     ));
 
+    let shutdown = Shutdown::listen();
+    // Tasks that use the db, joined before it is closed
+    let mut tasks = Vec::new();
+
     let repo = Arc::new(Mutex::new(repo));
+    tasks.push(task::spawn(sweep_expired_events(
+        repo.clone(),
+        shutdown.clone(),
+    )));
+    let config = SharedConfig::new(LiveConfig::new(settings.clone())?);
+    tasks.push(task::spawn(
+        Reloader {
+            path: PathBuf::from(CONFIG_FILE),
+            config: config.clone(),
+            repo: repo.clone(),
+            nostr: nostr_client.clone(),
+        }
+        .watch(shutdown.clone()),
+    ));
+
+    let importer = Importer {
+        repo: repo.clone(),
+        nostr: nostr_client.clone(),
+        pending: Arc::new(std::sync::Mutex::new(PendingImports::default())),
+        jobs: Arc::new(ImportJobs::new(&settings.imports)),
+        config: config.clone(),
+    };
+    let workers = importer.start(settings.imports.workers);
+    importer.resume().await?;
+    tasks.push(task::spawn(
+        importer.clone().schedule_syncs(shutdown.clone()),
+    ));
+    if settings.imports.mirror {
+        tasks.push(task::spawn(
+            Mirror {
+                importer: importer.clone(),
+                keys: keys.clone(),
+            }
+            .run(shutdown.clone()),
+        ));
+    }
+    let zaps = Arc::new(ZapAdmission::new(
+        repo.clone(),
+        nostr_client.clone(),
+        config.clone(),
+    ));
+    tasks.push(task::spawn(zaps.clone().poll(shutdown.clone())));
+
     let checker = EventAuthz {
         repo: repo.clone(),
-        settings: settings.clone(),
         nostr_client,
+        config: config.clone(),
+        service_keys: keys,
+        importer: importer.clone(),
+        zaps,
+    };
+
+    let payments = match &settings.payment {
+        Some(payment) => {
+            let payments = Arc::new(Payments::from_settings(payment, repo.clone())?);
+            tasks.push(task::spawn(
+                payments.clone().check_pending(shutdown.clone()),
+            ));
+            Some(payments)
+        }
+        None => None,
     };
 
     // Start HTTP server in new thread if enabled
-    if let Some(api_key) = settings.info.api_key {
-        info!("Starting HTTP server");
-        let _handle = task::spawn(start_server(api_key, repo));
-    }
+    let http =
+        if settings.info.api_key.is_some() || settings.info.api_url.is_some() || payments.is_some()
+        {
+            info!("Starting HTTP server");
+            Some(task::spawn(api::start_server(
+                config,
+                repo.clone(),
+                payments,
+                shutdown.clone(),
+            )))
+        } else {
+            None
+        };
 
     info!("EventAuthz Server listening on {addr}");
-    // Start serving
+    // Start serving, on shutdown new requests are refused and in flight requests finish
     Server::builder()
         .add_service(AuthorizationServer::new(checker))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown.wait())
         .await?;
 
+    if let Some(http) = http {
+        if let Ok(Err(err)) = http.await {
+            error!("HTTP server error: {}", err);
+        }
+    }
+
+    // Tasks stop at their next wait, those still busy after the timeout are stopped
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, futures_util::future::join_all(&mut tasks))
+        .await
+        .is_err()
+    {
+        warn!("Stopping tasks that did not finish in time");
+        tasks.iter().for_each(|task| task.abort());
+        // Joined tasks cannot be polled again
+        futures_util::future::join_all(tasks.into_iter().filter(|task| !task.is_finished())).await;
+    }
+
+    match importer.drain(SHUTDOWN_TIMEOUT).await {
+        Ok(0) => (),
+        Ok(saved) => info!("Saved {} unfinished import jobs", saved),
+        Err(err) => error!("Could not save unfinished import jobs: {}", err),
+    }
+    // Jobs still running were saved and are run again on start
+    workers.iter().for_each(|worker| worker.abort());
+    futures_util::future::join_all(workers).await;
+    repo.lock().await.close();
+    info!("Shut down");
+
     Ok(())
 }
 
+/// Periodically denies imported events that have expired
+async fn sweep_expired_events(repo: Arc<Mutex<Repo>>, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.clone().wait() => return,
+            _ = interval.tick() => (),
+        }
+        match repo.lock().await.sweep_expired_events() {
+            Ok(expired) if !expired.is_empty() => info!("Denied {} expired events", expired.len()),
+            Ok(_) => (),
+            Err(err) => error!("Error sweeping expired events: {}", err),
+        }
+    }
+}
+
 impl Event {
+    /// Unix time of the NIP-40 `expiration` tag
+    pub fn expiration(&self) -> Option<u64> {
+        self.tags
+            .iter()
+            .find_map(|tag| match tag.values.as_slice() {
+                [name, expires_at, ..] if name == "expiration" => expires_at.parse().ok(),
+                _ => None,
+            })
+    }
+
     pub fn referenced_events(&self) -> Result<HashMap<EventId, Option<String>>, Error> {
         let event: nostr_sdk::Event = self.into();
         let event_ids: HashMap<EventId, Option<String>> = event
@@ -198,78 +549,110 @@ impl Event {
     }
 }
 
-#[derive(Clone)]
-struct AppState {
-    api_key: String,
-    repo: Arc<Mutex<Repo>>,
-}
-
-async fn start_server(api_key: String, repo: Arc<Mutex<Repo>>) -> Result<(), Error> {
-    let shared_state = AppState {
-        api_key: api_key.to_string(),
-        repo,
-    };
-
-    // build our application with a single route
-    let app = Router::new()
-        .route("/update", post(update_users))
-        .route("/users", get(get_users))
-        .with_state(shared_state);
-
-    // run it with hyper on localhost:3000
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-
-    Ok(())
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Users {
-    allow: Option<Vec<String>>,
-    deny: Option<Vec<String>>,
-}
-
-async fn update_users(
-    headers: HeaderMap,
-    State(state): State<AppState>,
-    Json(payload): Json<Users>,
-) -> Result<(), (StatusCode, String)> {
-    debug!("Users: {payload:?}");
-    if let Some(key) = headers.get("X-Api-Key") {
-        debug!("Sent key: {key:?}");
-        if key.eq(&state.api_key) {
-            // Admit pubkeys
-            if let Some(pubkeys) = &payload.allow {
-                debug!("Pubkeys to allow: {pubkeys:?}");
-                state.repo.lock().await.admit_pubkeys(pubkeys).await.ok();
-            }
-
-            // Deny pubkeys
-            if let Some(pubkeys) = &payload.deny {
-                debug!("Pubkeys to deny: {pubkeys:?}");
-                state.repo.lock().await.deny_pubkeys(pubkeys).await.ok();
-            }
-            return Ok(());
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use std::collections::HashSet;
+
+    use crate::config::{Imports, Pow, Settings};
+    use crate::nauthz_grpc::event::TagEntry;
+
+    use super::*;
+
+    async fn authz(settings: Settings) -> EventAuthz {
+        let keys = Keys::generate();
+        let repo = Arc::new(Mutex::new(Repo::new()));
+        let nostr_client = Arc::new(Mutex::new(
+            NostrClient::new(&HashSet::new(), &keys).await.unwrap(),
+        ));
+        let config = SharedConfig::new(LiveConfig::new(settings).unwrap());
+        let importer = Importer {
+            repo: repo.clone(),
+            nostr: nostr_client.clone(),
+            pending: Arc::new(std::sync::Mutex::new(PendingImports::default())),
+            jobs: Arc::new(ImportJobs::new(&Imports::default())),
+            config: config.clone(),
+        };
+        let zaps = Arc::new(ZapAdmission::new(
+            repo.clone(),
+            nostr_client.clone(),
+            config.clone(),
+        ));
+        EventAuthz {
+            repo,
+            nostr_client,
+            config,
+            service_keys: keys,
+            importer,
+            zaps,
         }
     }
 
-    Err((StatusCode::UNAUTHORIZED, "".to_string()))
-}
-
-async fn get_users(
-    headers: HeaderMap,
-    State(state): State<AppState>,
-) -> Result<Json<Users>, (StatusCode, String)> {
-    debug!("{}", state.api_key);
-    if let Some(key) = headers.get("X-Api-Key") {
-        if key.eq(&state.api_key) {
-            let users = state.repo.lock().await.get_accounts().unwrap();
-            return Ok(Json(users));
+    /// Event with an id of `zeros` leading zero bytes committing to `target`
+    fn pow_event(author: &str, kind: u64, zeros: usize, target: &str) -> Event {
+        let mut id = vec![0; zeros];
+        id.extend_from_slice(&nostr_sdk::secp256k1::rand::random::<[u8; 32]>()[zeros..]);
+        id[zeros] |= 0x80;
+        Event {
+            id,
+            pubkey: hex::decode(author).unwrap(),
+            created_at: utils::unix_time(),
+            kind,
+            content: String::new(),
+            tags: vec![TagEntry {
+                values: vec!["nonce".to_string(), "1".to_string(), target.to_string()],
+            }],
+            sig: vec![],
         }
-        return Err((StatusCode::UNAUTHORIZED, "Invalid API Key".to_string()));
     }
 
-    Err((StatusCode::UNAUTHORIZED, "No Api Key".to_string()))
+    async fn admit(authz: &EventAuthz, event: Event) -> EventReply {
+        authz
+            .event_admit(Request::new(EventRequest {
+                event: Some(event),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_event_admit_pow() {
+        let authz = authz(Settings {
+            pow: Pow {
+                difficulty: HashMap::from([("1".to_string(), 16)]),
+                default_difficulty: None,
+            },
+            ..Default::default()
+        })
+        .await;
+        let author = Keys::generate().public_key().to_string();
+
+        let reply = admit(&authz, pow_event(&author, 1, 2, "16")).await;
+        assert_eq!(reply.decision, Decision::Permit as i32);
+
+        let reply = admit(&authz, pow_event(&author, 1, 1, "16")).await;
+        assert_eq!(reply.decision, Decision::Deny as i32);
+        assert_eq!(reply.message.unwrap(), "pow: difficulty 16 is required");
+
+        // Kinds without a policy cannot be published with work
+        let reply = admit(&authz, pow_event(&author, 7, 2, "16")).await;
+        assert_eq!(reply.decision, Decision::Deny as i32);
+        assert_eq!(reply.message.unwrap(), "Not allowed to publish");
+
+        // Nor can denied authors
+        authz
+            .repo
+            .lock()
+            .await
+            .deny_pubkeys(std::slice::from_ref(&author), Role::Owner)
+            .await
+            .unwrap();
+        let reply = admit(&authz, pow_event(&author, 1, 2, "16")).await;
+        assert_eq!(reply.decision, Decision::Deny as i32);
+        assert_eq!(reply.message.unwrap(), "Not allowed to publish");
+    }
 }
@@ -0,0 +1,93 @@
+//! Counters and gauges exposed in the Prometheus text format at `/metrics`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    /// Events permitted because the author or event is admitted
+    pub events_admitted: AtomicU64,
+    /// Events permitted because the service is importing them
+    pub events_imported: AtomicU64,
+    /// Events permitted because they are addressed to admitted pubkeys
+    pub events_inbox: AtomicU64,
+    /// Events permitted from authors that are not admitted because of proof of work
+    pub events_pow: AtomicU64,
+    /// Events denied
+    pub events_denied: AtomicU64,
+    /// Events fetched from remote relays
+    pub events_fetched: AtomicU64,
+    /// Fetched events not imported because of the content filters or a denied author
+    pub events_filtered: AtomicU64,
+    /// Fetched events dropped because they were not requested or the id or signature is invalid
+    pub events_invalid: AtomicU64,
+    /// Referenced events not fetched because the home relay has them
+    pub events_stored: AtomicU64,
+    /// Events sent to relays that were missing them by sync
+    pub events_sent: AtomicU64,
+    /// New events of admitted pubkeys received from default relays
+    pub events_mirrored: AtomicU64,
+    /// Import jobs waiting for a worker
+    pub import_queue: AtomicU64,
+    /// Import jobs being run by workers
+    pub imports_running: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            events_admitted: AtomicU64::new(0),
+            events_imported: AtomicU64::new(0),
+            events_inbox: AtomicU64::new(0),
+            events_pow: AtomicU64::new(0),
+            events_denied: AtomicU64::new(0),
+            events_fetched: AtomicU64::new(0),
+            events_filtered: AtomicU64::new(0),
+            events_invalid: AtomicU64::new(0),
+            events_stored: AtomicU64::new(0),
+            events_sent: AtomicU64::new(0),
+            events_mirrored: AtomicU64::new(0),
+            import_queue: AtomicU64::new(0),
+            imports_running: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Sets a gauge
+    pub fn set(gauge: &AtomicU64, value: u64) {
+        gauge.store(value, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        [
+            ("events_admitted", "Events permitted from admitted authors or ids", "counter", &self.events_admitted),
+            ("events_imported", "Events permitted as imports by the service", "counter", &self.events_imported),
+            ("events_inbox", "Events permitted as addressed to admitted pubkeys", "counter", &self.events_inbox),
+            ("events_pow", "Events permitted because of proof of work", "counter", &self.events_pow),
+            ("events_denied", "Events denied", "counter", &self.events_denied),
+            ("events_fetched", "Events fetched from remote relays", "counter", &self.events_fetched),
+            ("events_filtered", "Fetched events not imported because of the filters", "counter", &self.events_filtered),
+            ("events_invalid", "Fetched events dropped as not requested or with an invalid id or signature", "counter", &self.events_invalid),
+            ("events_stored", "Referenced events not fetched because the home relay has them", "counter", &self.events_stored),
+            ("events_sent", "Events sent to relays that were missing them by sync", "counter", &self.events_sent),
+            ("events_mirrored", "New events of admitted pubkeys received from default relays", "counter", &self.events_mirrored),
+            ("import_queue", "Import jobs waiting for a worker", "gauge", &self.import_queue),
+            ("imports_running", "Import jobs being run", "gauge", &self.imports_running),
+        ]
+        .iter()
+        .map(|(name, help, kind, value)| {
+            format!(
+                "# HELP my_local_relay_{name} {help}\n# TYPE my_local_relay_{name} {kind}\nmy_local_relay_{name} {}\n",
+                value.load(Ordering::Relaxed)
+            )
+        })
+        .collect()
+    }
+}
@@ -0,0 +1,411 @@
+//! Live mirroring of admitted authors from the default relays
+//!
+//! A subscription for events by admitted pubkeys is kept open on each default relay, and
+//! new events are imported to the home relay as they arrive. The subscription is replaced
+//! when accounts are allowed, denied or expire. Quiet relays are pinged, and relays that
+//! drop the connection or do not answer are reconnected with backoff, asking for the events
+//! published since the last message received from them.
+
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::imports::{ImportJob, Importer};
+use crate::metrics::{Metrics, METRICS};
+use crate::shutdown::Shutdown;
+use crate::sync::{Received, RelaySocket};
+use crate::utils;
+
+/// Subscription id of the mirror on each relay
+const SUBSCRIPTION_ID: &str = "mirror";
+/// Authors in one filter of the subscription
+const FILTER_AUTHORS: usize = 500;
+/// How often admitted pubkeys are read for expired admissions and default relays for changes
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// A relay that sends nothing for this long is pinged
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// A pinged relay that does not answer within this long is reconnected
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for more events before importing those received
+const BATCH_WAIT: Duration = Duration::from_millis(500);
+/// Wait before the first reconnect, doubled after each failed attempt
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Seconds before the last message from a relay events are asked for again on reconnect,
+/// for clock skew
+const RESUBSCRIBE_MARGIN: u64 = 60;
+/// How long ids are remembered so events received from several relays are imported once
+const SEEN_TTL: Duration = Duration::from_secs(600);
+
+/// Ids of events received recently
+#[derive(Debug, Default)]
+struct Seen {
+    ids: HashMap<EventId, Instant>,
+}
+
+impl Seen {
+    /// Returns true if the id has not been seen within the TTL
+    fn insert(&mut self, id: EventId) -> bool {
+        let now = Instant::now();
+        self.ids
+            .retain(|_, seen| now.duration_since(*seen) < SEEN_TTL);
+        self.ids.insert(id, now).is_none()
+    }
+}
+
+pub struct Mirror {
+    /// Imports mirrored events, default relays are read from its config
+    pub importer: Importer,
+    /// Keys of the service, for relays that require NIP-42
+    pub keys: Keys,
+}
+
+impl Mirror {
+    /// Keeps a mirroring task running for each default relay, updating their authors as
+    /// accounts change
+    pub async fn run(self, shutdown: Shutdown) {
+        let repo = self.importer.repo.clone();
+        let mut accounts = repo.lock().await.watch_accounts();
+        let (authors_tx, mut authors) = watch::channel(HashSet::new());
+        let seen = Arc::new(std::sync::Mutex::new(Seen::default()));
+        let mut tasks: HashMap<Url, JoinHandle<()>> = HashMap::new();
+        let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+
+        loop {
+            match repo.lock().await.get_admitted_pubkeys() {
+                Ok(pubkeys) => {
+                    let pubkeys: HashSet<XOnlyPublicKey> = pubkeys
+                        .iter()
+                        .flat_map(|p| XOnlyPublicKey::from_str(p))
+                        .collect();
+                    authors_tx.send_if_modified(|authors| {
+                        let changed = *authors != pubkeys;
+                        if changed {
+                            debug!("Mirroring {} authors", pubkeys.len());
+                            *authors = pubkeys;
+                        }
+                        changed
+                    });
+                }
+                Err(err) => error!("Error reading admitted pubkeys: {}", err),
+            }
+
+            let relays = self
+                .importer
+                .config
+                .current()
+                .settings
+                .info
+                .default_relays
+                .clone();
+            tasks.retain(|relay, task| {
+                if !relays.contains(relay) {
+                    info!("Stopped mirroring {relay}");
+                    task.abort();
+                }
+                relays.contains(relay)
+            });
+            // New tasks subscribe to the current authors without seeing them as a change
+            authors.borrow_and_update();
+            for relay in relays {
+                tasks.entry(relay.clone()).or_insert_with(|| {
+                    info!("Mirroring {relay}");
+                    tokio::spawn(
+                        MirroredRelay {
+                            relay,
+                            keys: self.keys.clone(),
+                            importer: self.importer.clone(),
+                            authors: authors.clone(),
+                            seen: seen.clone(),
+                            last_received: Timestamp::now(),
+                        }
+                        .run(),
+                    )
+                });
+            }
+
+            tokio::select! {
+                _ = shutdown.clone().wait() => break,
+                _ = accounts.changed() => (),
+                _ = refresh.tick() => (),
+            }
+        }
+
+        // Relay tasks are stopped and waited for so none is left importing
+        for (_, task) in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+/// Subscription to one relay
+struct MirroredRelay {
+    relay: Url,
+    keys: Keys,
+    importer: Importer,
+    authors: watch::Receiver<HashSet<XOnlyPublicKey>>,
+    seen: Arc<std::sync::Mutex<Seen>>,
+    /// When the relay last sent anything, events after it may have been missed
+    last_received: Timestamp,
+}
+
+impl MirroredRelay {
+    /// Mirrors the relay, reconnecting when the connection is lost
+    async fn run(mut self) {
+        let mut since = Timestamp::now();
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            let connected = Instant::now();
+            match self.mirror(since).await {
+                Ok(()) => return,
+                Err(err) => warn!("Mirroring {} stopped: {}", self.relay, err),
+            }
+            since = self.last_received - RESUBSCRIBE_MARGIN;
+
+            // A connection that lasted resets the backoff
+            if connected.elapsed() > MAX_RECONNECT_BACKOFF {
+                backoff = RECONNECT_BACKOFF;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    }
+
+    /// Subscribes to events by the authors since `since` and queues them for import until
+    /// the connection is lost. Returns once the mirror is dropped.
+    async fn mirror(&mut self, since: Timestamp) -> Result<(), Error> {
+        let mut socket = RelaySocket::connect(&self.relay, &self.keys).await?;
+        self.subscribe(&mut socket, since).await?;
+
+        let mut events = Vec::new();
+        let mut pinged = false;
+        loop {
+            let timeout = match (events.is_empty(), pinged) {
+                (false, _) => BATCH_WAIT,
+                (true, false) => IDLE_TIMEOUT,
+                (true, true) => PING_TIMEOUT,
+            };
+            let mut flush = false;
+            tokio::select! {
+                changed = self.authors.changed() => {
+                    if changed.is_err() {
+                        socket.close().await;
+                        return Ok(());
+                    }
+                    let since = Timestamp::now() - RESUBSCRIBE_MARGIN;
+                    self.subscribe(&mut socket, since).await?;
+                }
+                received = socket.recv(timeout) => {
+                    if received.is_ok() {
+                        self.last_received = Timestamp::now();
+                        pinged = false;
+                    }
+                    match received {
+                        Ok(Received::Message(msg)) => {
+                            if let Some(event) = self.received(&socket, msg)? {
+                                events.push(event);
+                            }
+                        }
+                        Ok(Received::Authenticated) => self.subscribe(&mut socket, since).await?,
+                        Ok(Received::Pong) => (),
+                        // Events are imported once the relay has sent no more for a moment
+                        Err(_) if !events.is_empty() => flush = true,
+                        Err(_) if !pinged => {
+                            socket.ping().await?;
+                            pinged = true;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+
+            let batch_size = self.importer.config.current().settings.imports.batch_size;
+            if flush || events.len() >= batch_size {
+                Metrics::add(&METRICS.events_mirrored, events.len() as u64);
+                debug!("Mirrored {} events from {}", events.len(), self.relay);
+                self.importer
+                    .queue(ImportJob::Mirrored(std::mem::take(&mut events)))
+                    .await;
+            }
+        }
+    }
+
+    /// Replaces the subscription with one for the current authors
+    async fn subscribe(&self, socket: &mut RelaySocket, since: Timestamp) -> Result<(), Error> {
+        let authors: Vec<XOnlyPublicKey> = self.authors.borrow().iter().copied().collect();
+        if authors.is_empty() {
+            return socket
+                .send(json!(["CLOSE", SUBSCRIPTION_ID]).to_string())
+                .await;
+        }
+        let filters = authors
+            .chunks(FILTER_AUTHORS)
+            .map(|authors| Filter::new().authors(authors.to_vec()).since(since))
+            .collect();
+        socket
+            .send(ClientMessage::new_req(SubscriptionId::new(SUBSCRIPTION_ID), filters).as_json())
+            .await
+    }
+
+    /// Returns a received event if it is valid, by a mirrored author and not seen before
+    fn received(&self, socket: &RelaySocket, msg: Vec<Value>) -> Result<Option<Event>, Error> {
+        if msg.get(1).and_then(Value::as_str) != Some(SUBSCRIPTION_ID) {
+            return Ok(None);
+        }
+        match msg.first().and_then(Value::as_str) {
+            Some("EVENT") => (),
+            Some("CLOSED") if !socket.authenticating() => {
+                let reason = msg.get(2).and_then(Value::as_str).unwrap_or_default();
+                return Err(Error::SyncError(format!(
+                    "{} closed the subscription: {reason}",
+                    self.relay
+                )));
+            }
+            _ => return Ok(None),
+        }
+
+        let event: Event = match msg.get(2).cloned().map(serde_json::from_value) {
+            Some(Ok(event)) => event,
+            _ => return Ok(None),
+        };
+        if !self.authors.borrow().contains(&event.pubkey) {
+            return Ok(None);
+        }
+        if let Err(reason) = utils::verify_event(&event) {
+            debug!("Dropping event {} from {}: {reason}", event.id, self.relay);
+            Metrics::inc(&METRICS.events_invalid);
+            return Ok(None);
+        }
+        match self.seen.lock().unwrap().insert(event.id) {
+            true => Ok(Some(event)),
+            false => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serial_test::serial;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::client::NostrClient;
+    use crate::config::{Imports, Settings};
+    use crate::imports::{ImportJobs, PendingImports};
+    use crate::reload::{LiveConfig, SharedConfig};
+    use crate::repo::Repo;
+
+    /// Relay that reports the authors of each REQ, or `None` for a CLOSE, and answers each
+    /// REQ with `events`
+    async fn mock_relay(events: Vec<Event>) -> (Url, mpsc::UnboundedReceiver<Option<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(WsMessage::Text(msg))) = socket.next().await {
+                match ClientMessage::from_json(msg) {
+                    Ok(ClientMessage::Req {
+                        subscription_id,
+                        filters,
+                    }) => {
+                        let authors = filters.iter().flat_map(|f| f.authors.clone()).flatten();
+                        requests.send(Some(authors.count())).unwrap();
+                        for event in &events {
+                            let msg =
+                                RelayMessage::new_event(subscription_id.clone(), event.clone());
+                            socket.send(WsMessage::Text(msg.as_json())).await.unwrap();
+                        }
+                    }
+                    Ok(ClientMessage::Close(_)) => requests.send(None).unwrap(),
+                    _ => (),
+                }
+            }
+        });
+        (url, received)
+    }
+
+    /// Waits for the mirrored events counter to reach `count`
+    async fn mirrored(count: u64) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while METRICS.events_mirrored.load(Ordering::Relaxed) < count {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_mirror_relay() {
+        let (admitted, other) = (Keys::generate(), Keys::generate());
+        let note = |keys: &Keys| {
+            EventBuilder::new_text_note("hello", &[])
+                .to_event(keys)
+                .unwrap()
+        };
+        let mut forged = note(&admitted);
+        forged.content = "changed".to_string();
+        let events = vec![note(&admitted), note(&other), forged];
+        let (url, mut requests) = mock_relay(events).await;
+
+        let keys = Keys::generate();
+        let importer = Importer {
+            repo: Arc::new(Mutex::new(Repo::new())),
+            nostr: Arc::new(Mutex::new(
+                NostrClient::new(&HashSet::new(), &keys).await.unwrap(),
+            )),
+            pending: Arc::new(std::sync::Mutex::new(PendingImports::default())),
+            jobs: Arc::new(ImportJobs::new(&Imports::default())),
+            config: SharedConfig::new(LiveConfig::new(Settings::default()).unwrap()),
+        };
+        let (authors_tx, authors) = watch::channel(HashSet::from([admitted.public_key()]));
+        let mirrored_before = METRICS.events_mirrored.load(Ordering::Relaxed);
+        let mut relay = MirroredRelay {
+            relay: url,
+            keys,
+            importer,
+            authors,
+            seen: Arc::new(std::sync::Mutex::new(Seen::default())),
+            last_received: Timestamp::now(),
+        };
+        let mirror = tokio::spawn(async move { relay.mirror(Timestamp::now()).await });
+
+        // Only the valid event by an admitted author is mirrored
+        assert_eq!(requests.recv().await.unwrap(), Some(1));
+        mirrored(mirrored_before + 1).await;
+
+        // The subscription is replaced when authors are admitted, events already
+        // mirrored are not mirrored again
+        authors_tx.send_replace(HashSet::from([admitted.public_key(), other.public_key()]));
+        assert_eq!(requests.recv().await.unwrap(), Some(2));
+        mirrored(mirrored_before + 2).await;
+
+        // and closed when there are none
+        authors_tx.send_replace(HashSet::new());
+        assert_eq!(requests.recv().await.unwrap(), None);
+
+        drop(authors_tx);
+        assert!(mirror.await.unwrap().is_ok());
+        assert_eq!(
+            METRICS.events_mirrored.load(Ordering::Relaxed),
+            mirrored_before + 2
+        );
+    }
+}
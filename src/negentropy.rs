@@ -0,0 +1,507 @@
+//! Negentropy set reconciliation, as used by NIP-77
+//!
+//! Both sides hold items sorted by timestamp then id. The initiator sends fingerprints of
+//! ranges of its items and the other side answers ranges whose fingerprints differ with
+//! fingerprints of smaller ranges or, once they are small, with their ids. The ids only one
+//! side has are found without sending the ids of ranges both sides have.
+//! <https://github.com/hoytech/negentropy/blob/master/docs/negentropy-protocol-v1.md>
+
+use nostr_sdk::nostr::hashes::{sha256, Hash};
+
+use std::collections::HashSet;
+
+const PROTOCOL_VERSION: u8 = 0x61;
+const ID_SIZE: usize = 32;
+const FINGERPRINT_SIZE: usize = 16;
+/// Ranges a range with differing fingerprints is split into
+const BUCKETS: usize = 16;
+
+const MODE_SKIP: u64 = 0;
+const MODE_FINGERPRINT: u64 = 1;
+const MODE_ID_LIST: u64 = 2;
+
+/// An event in a set, ordered by timestamp then id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Item {
+    pub timestamp: u64,
+    pub id: [u8; ID_SIZE],
+}
+
+/// End of a range, with the shortest id prefix that tells it from the item before
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Bound {
+    timestamp: u64,
+    prefix: Vec<u8>,
+}
+
+impl Bound {
+    fn infinity() -> Self {
+        Self {
+            timestamp: u64::MAX,
+            prefix: vec![],
+        }
+    }
+
+    /// Lowest bound between two items
+    fn between(prev: &Item, curr: &Item) -> Self {
+        if curr.timestamp != prev.timestamp {
+            return Self {
+                timestamp: curr.timestamp,
+                prefix: vec![],
+            };
+        }
+        let shared = prev
+            .id
+            .iter()
+            .zip(curr.id.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        Self {
+            timestamp: curr.timestamp,
+            prefix: curr.id[..(shared + 1).min(ID_SIZE)].to_vec(),
+        }
+    }
+
+    /// Lowest item at the bound
+    fn item(&self) -> Item {
+        let mut id = [0; ID_SIZE];
+        id[..self.prefix.len()].copy_from_slice(&self.prefix);
+        Item {
+            timestamp: self.timestamp,
+            id,
+        }
+    }
+}
+
+/// Ids found on only one side
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Ids only this side has
+    pub have: Vec<[u8; ID_SIZE]>,
+    /// Ids only the other side has
+    pub need: Vec<[u8; ID_SIZE]>,
+}
+
+pub struct Negentropy {
+    items: Vec<Item>,
+    initiator: bool,
+}
+
+impl Negentropy {
+    pub fn new(mut items: Vec<Item>) -> Self {
+        items.sort();
+        items.dedup();
+        Self {
+            items,
+            initiator: false,
+        }
+    }
+
+    /// First message, sent by the side that wants to find the differences
+    pub fn initiate(&mut self) -> Vec<u8> {
+        self.initiator = true;
+        let mut out = Writer::default();
+        out.bytes(&[PROTOCOL_VERSION]);
+        self.split_range(0, self.items.len(), &Bound::infinity(), &mut out);
+        out.buf
+    }
+
+    /// Processes a message from the other side, adding the ids found on only one side to
+    /// `diff` if this side initiated. Returns the next message, or `None` once the
+    /// initiator has found all differences.
+    pub fn reconcile(&self, msg: &[u8], diff: &mut Diff) -> Result<Option<Vec<u8>>, &'static str> {
+        let mut msg = Reader::new(msg);
+        if msg.bytes(1)? != [PROTOCOL_VERSION] {
+            return Err("unsupported negentropy protocol version");
+        }
+
+        let mut out = Writer::default();
+        out.bytes(&[PROTOCOL_VERSION]);
+        let mut prev_bound = Bound::default();
+        let mut prev_index = 0;
+        // Skips are only written before a range that is not skipped
+        let mut skip = false;
+
+        while !msg.is_empty() {
+            let bound = msg.bound()?;
+            let mode = msg.varint()?;
+            let lower = prev_index;
+            let upper =
+                prev_index + self.items[prev_index..].partition_point(|item| *item < bound.item());
+
+            match mode {
+                MODE_SKIP => skip = true,
+                MODE_FINGERPRINT => {
+                    if msg.bytes(FINGERPRINT_SIZE)? == self.fingerprint(lower, upper) {
+                        skip = true;
+                    } else {
+                        if skip {
+                            skip = false;
+                            out.bound(&prev_bound);
+                            out.varint(MODE_SKIP);
+                        }
+                        self.split_range(lower, upper, &bound, &mut out);
+                    }
+                }
+                MODE_ID_LIST => {
+                    let count = msg.varint()?;
+                    let mut theirs = HashSet::new();
+                    for _ in 0..count {
+                        theirs.insert(<[u8; ID_SIZE]>::try_from(msg.bytes(ID_SIZE)?).unwrap());
+                    }
+
+                    if self.initiator {
+                        for item in &self.items[lower..upper] {
+                            if !theirs.remove(&item.id) {
+                                diff.have.push(item.id);
+                            }
+                        }
+                        diff.need.extend(theirs);
+                        skip = true;
+                    } else {
+                        if skip {
+                            skip = false;
+                            out.bound(&prev_bound);
+                            out.varint(MODE_SKIP);
+                        }
+                        self.write_ids(lower, upper, &bound, &mut out);
+                    }
+                }
+                _ => return Err("unexpected negentropy mode"),
+            }
+
+            prev_index = upper;
+            prev_bound = bound;
+        }
+
+        match self.initiator && out.buf.len() == 1 {
+            true => Ok(None),
+            false => Ok(Some(out.buf)),
+        }
+    }
+
+    /// Writes the items between `lower` and `upper` as their ids if there are few, otherwise
+    /// as fingerprints of buckets
+    fn split_range(&self, lower: usize, upper: usize, upper_bound: &Bound, out: &mut Writer) {
+        let count = upper - lower;
+        if count < BUCKETS * 2 {
+            self.write_ids(lower, upper, upper_bound, out);
+            return;
+        }
+
+        let per_bucket = count / BUCKETS;
+        let with_extra = count % BUCKETS;
+        let mut curr = lower;
+        for bucket in 0..BUCKETS {
+            let size = per_bucket + usize::from(bucket < with_extra);
+            let fingerprint = self.fingerprint(curr, curr + size);
+            curr += size;
+
+            let bound = match curr == upper {
+                true => upper_bound.clone(),
+                false => Bound::between(&self.items[curr - 1], &self.items[curr]),
+            };
+            out.bound(&bound);
+            out.varint(MODE_FINGERPRINT);
+            out.bytes(&fingerprint);
+        }
+    }
+
+    fn write_ids(&self, lower: usize, upper: usize, upper_bound: &Bound, out: &mut Writer) {
+        out.bound(upper_bound);
+        out.varint(MODE_ID_LIST);
+        out.varint((upper - lower) as u64);
+        for item in &self.items[lower..upper] {
+            out.bytes(&item.id);
+        }
+    }
+
+    /// Hash of the sum of the ids, as 256 bit little endian numbers, and their count
+    fn fingerprint(&self, lower: usize, upper: usize) -> [u8; FINGERPRINT_SIZE] {
+        let mut sum = [0u8; ID_SIZE];
+        for item in &self.items[lower..upper] {
+            let mut carry = 0u16;
+            for (byte, add) in sum.iter_mut().zip(item.id.iter()) {
+                let total = *byte as u16 + *add as u16 + carry;
+                *byte = total as u8;
+                carry = total >> 8;
+            }
+        }
+
+        let mut input = Writer::default();
+        input.bytes(&sum);
+        input.varint((upper - lower) as u64);
+        let hash = sha256::Hash::hash(&input.buf).into_inner();
+        hash[..FINGERPRINT_SIZE].try_into().unwrap()
+    }
+}
+
+/// Encodes a message, timestamps are written as the difference to the previous one
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    last_timestamp: u64,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Base 128, most significant digit first, with the high bit set on all but the last
+    fn varint(&mut self, mut n: u64) {
+        let mut digits = vec![(n & 0x7f) as u8];
+        n >>= 7;
+        while n > 0 {
+            digits.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        digits.reverse();
+        self.bytes(&digits);
+    }
+
+    fn bound(&mut self, bound: &Bound) {
+        if bound.timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+            self.varint(0);
+        } else {
+            let delta = bound.timestamp.saturating_sub(self.last_timestamp);
+            self.last_timestamp = bound.timestamp;
+            self.varint(delta + 1);
+        }
+        self.varint(bound.prefix.len() as u64);
+        self.bytes(&bound.prefix);
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    last_timestamp: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            last_timestamp: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.buf.len() < len {
+            return Err("negentropy message ended early");
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, &'static str> {
+        let mut n: u64 = 0;
+        loop {
+            let byte = self.bytes(1)?[0];
+            if n > u64::MAX >> 7 {
+                return Err("negentropy varint is too long");
+            }
+            n = (n << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn bound(&mut self) -> Result<Bound, &'static str> {
+        let timestamp = match self.varint()? {
+            0 => u64::MAX,
+            _ if self.last_timestamp == u64::MAX => u64::MAX,
+            delta => self.last_timestamp.saturating_add(delta - 1),
+        };
+        self.last_timestamp = timestamp;
+        let len = self.varint()? as usize;
+        if len > ID_SIZE {
+            return Err("negentropy bound is too long");
+        }
+        Ok(Bound {
+            timestamp,
+            prefix: self.bytes(len)?.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(range: std::ops::Range<u64>) -> Vec<Item> {
+        range
+            .map(|n| Item {
+                // Several items share each timestamp
+                timestamp: 1_700_000_000 + n / 3,
+                id: sha256::Hash::hash(&n.to_be_bytes()).into_inner(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_varint() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = Writer::default();
+            out.varint(n);
+            assert_eq!(Reader::new(&out.buf).varint(), Ok(n));
+        }
+        assert_eq!(
+            Reader::new(&[0x81]).varint(),
+            Err("negentropy message ended early")
+        );
+    }
+
+    #[test]
+    fn test_reconcile() {
+        // Both have 0..1000, only the initiator has 1000..1040, only the other has 1040..1100
+        let mut ours = Negentropy::new([items(0..1000), items(1000..1040)].concat());
+        let theirs = Negentropy::new([items(0..1000), items(1040..1100)].concat());
+
+        let mut diff = Diff::default();
+        let mut msg = ours.initiate();
+        let mut rounds = 0;
+        loop {
+            let reply = theirs
+                .reconcile(&msg, &mut Diff::default())
+                .unwrap()
+                .unwrap();
+            match ours.reconcile(&reply, &mut diff).unwrap() {
+                Some(next) => msg = next,
+                None => break,
+            }
+            rounds += 1;
+            assert!(rounds < 10);
+        }
+
+        let ids = |items: Vec<Item>| items.into_iter().map(|i| i.id).collect::<HashSet<_>>();
+        assert_eq!(
+            diff.have.into_iter().collect::<HashSet<_>>(),
+            ids(items(1000..1040))
+        );
+        assert_eq!(
+            diff.need.into_iter().collect::<HashSet<_>>(),
+            ids(items(1040..1100))
+        );
+
+        // Nothing to find between equal sets
+        let mut ours = Negentropy::new(items(0..100));
+        let msg = ours.initiate();
+        let reply = Negentropy::new(items(0..100))
+            .reconcile(&msg, &mut Diff::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ours.reconcile(&reply, &mut Diff::default()), Ok(None));
+    }
+
+    /// Runs the reference implementation (the rust-nostr port of hoytech's) on the other side
+    fn reference(
+        items: &[Item],
+    ) -> ::negentropy::Negentropy<'static, ::negentropy::NegentropyStorageVector> {
+        let mut storage = ::negentropy::NegentropyStorageVector::new();
+        for item in items {
+            storage
+                .insert(item.timestamp, ::negentropy::Id::from_byte_array(item.id))
+                .unwrap();
+        }
+        storage.seal().unwrap();
+        ::negentropy::Negentropy::owned(storage, 0).unwrap()
+    }
+
+    #[test]
+    fn test_reference_interop() {
+        let item = |timestamp, byte| Item {
+            timestamp,
+            id: [byte; ID_SIZE],
+        };
+        let client = vec![item(0, 0xaa), item(1, 0xbb)];
+        let relay = vec![
+            item(0, 0xaa),
+            item(2, 0xcc),
+            item(3, 0x11),
+            item(5, 0x22),
+            item(10, 0x33),
+        ];
+        let sorted = |mut ids: Vec<[u8; ID_SIZE]>| {
+            ids.sort();
+            ids
+        };
+
+        for (client, relay) in [
+            (client, relay),
+            (
+                [items(0..1000), items(1000..1040)].concat(),
+                [items(0..1000), items(1040..1100)].concat(),
+            ),
+        ] {
+            let expected = Diff {
+                have: sorted(
+                    client
+                        .iter()
+                        .filter(|i| !relay.contains(i))
+                        .map(|i| i.id)
+                        .collect(),
+                ),
+                need: sorted(
+                    relay
+                        .iter()
+                        .filter(|i| !client.contains(i))
+                        .map(|i| i.id)
+                        .collect(),
+                ),
+            };
+
+            // Both initiators start with the same message
+            let mut ours = Negentropy::new(client.clone());
+            let mut msg = ours.initiate();
+            assert_eq!(msg, reference(&client).initiate().unwrap());
+
+            // Our initiator against the reference responder
+            let mut relay_side = reference(&relay);
+            let mut diff = Diff::default();
+            for _ in 0..10 {
+                let reply = relay_side.reconcile(&msg).unwrap();
+                match ours.reconcile(&reply, &mut diff).unwrap() {
+                    Some(next) => msg = next,
+                    None => break,
+                }
+            }
+            let diff = Diff {
+                have: sorted(diff.have),
+                need: sorted(diff.need),
+            };
+            assert_eq!(diff, expected);
+
+            // The reference initiator against our responder
+            let relay_side = Negentropy::new(relay.clone());
+            let mut theirs = reference(&client);
+            let mut msg = theirs.initiate().unwrap();
+            let (mut have, mut need) = (Vec::new(), Vec::new());
+            for _ in 0..10 {
+                let reply = relay_side
+                    .reconcile(&msg, &mut Diff::default())
+                    .unwrap()
+                    .unwrap();
+                match theirs
+                    .reconcile_with_ids(&reply, &mut have, &mut need)
+                    .unwrap()
+                {
+                    Some(next) => msg = next,
+                    None => break,
+                }
+            }
+            let ids = |ids: Vec<::negentropy::Id>| {
+                sorted(ids.into_iter().map(|id| id.to_bytes()).collect())
+            };
+            assert_eq!(ids(have), expected.have);
+            assert_eq!(ids(need), expected.need);
+        }
+    }
+}
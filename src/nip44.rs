@@ -0,0 +1,269 @@
+//! NIP-44 versioned encryption, version 2
+//!
+//! secp256k1 ECDH, HKDF and padding, ChaCha20 and HMAC-SHA256,
+//! payloads are base64 of `version || nonce || ciphertext || mac`.
+//! The primitives are the RustCrypto implementations, only the message format is here.
+
+use std::str::FromStr;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use nostr_sdk::nostr::secp256k1::{ecdh, rand, PublicKey, SecretKey, XOnlyPublicKey};
+use sha2::Sha256;
+
+use crate::error::Error;
+
+const VERSION: u8 = 2;
+const SALT: &[u8] = b"nip44-v2";
+const MIN_PLAINTEXT_SIZE: usize = 1;
+const MAX_PLAINTEXT_SIZE: usize = 65535;
+
+/// Key shared by two pubkeys, the same in both directions
+pub fn conversation_key(
+    secret_key: &SecretKey,
+    pubkey: &XOnlyPublicKey,
+) -> Result<[u8; 32], Error> {
+    let pubkey = PublicKey::from_str(&format!("02{pubkey}")).map_err(|_| Error::InvalidKey)?;
+    let point = ecdh::shared_secret_point(&pubkey, secret_key);
+    let (key, _) = Hkdf::<Sha256>::extract(Some(SALT), &point[..32]);
+    Ok(key.into())
+}
+
+/// Encrypts `plaintext` with a random nonce
+pub fn encrypt(conversation_key: &[u8; 32], plaintext: &str) -> Result<String, Error> {
+    encrypt_with_nonce(conversation_key, plaintext, &rand::random())
+}
+
+fn encrypt_with_nonce(
+    conversation_key: &[u8; 32],
+    plaintext: &str,
+    nonce: &[u8; 32],
+) -> Result<String, Error> {
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, nonce);
+
+    let mut ciphertext = pad(plaintext)?;
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+    let mac = hmac(&hmac_key, nonce, &ciphertext).finalize().into_bytes();
+
+    let mut payload = vec![VERSION];
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(&ciphertext);
+    payload.extend_from_slice(&mac);
+    Ok(base64::encode(payload))
+}
+
+/// Decrypts a payload, checking its mac and padding
+pub fn decrypt(conversation_key: &[u8; 32], payload: &str) -> Result<String, Error> {
+    if !(132..=87472).contains(&payload.len()) || payload.starts_with('#') {
+        return Err(Error::DecryptionError);
+    }
+    let data = base64::decode(payload).map_err(|_| Error::DecryptionError)?;
+    if !(99..=65603).contains(&data.len()) || data[0] != VERSION {
+        return Err(Error::DecryptionError);
+    }
+
+    let nonce: [u8; 32] = data[1..33].try_into().map_err(|_| Error::DecryptionError)?;
+    let (ciphertext, mac) = data[33..].split_at(data.len() - 33 - 32);
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, &nonce);
+    hmac(&hmac_key, &nonce, ciphertext)
+        .verify_slice(mac)
+        .map_err(|_| Error::DecryptionError)?;
+
+    let mut padded = ciphertext.to_vec();
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+    unpad(&padded)
+}
+
+/// ChaCha20 key and nonce and the HMAC key for a message, expanded from its nonce
+fn message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let mut keys = [0; 76];
+    Hkdf::<Sha256>::from_prk(conversation_key)
+        .expect("conversation keys are 32 bytes")
+        .expand(nonce, &mut keys)
+        .expect("76 bytes are within the HKDF output limit");
+
+    let mut chacha_key = [0; 32];
+    let mut chacha_nonce = [0; 12];
+    let mut hmac_key = [0; 32];
+    chacha_key.copy_from_slice(&keys[..32]);
+    chacha_nonce.copy_from_slice(&keys[32..44]);
+    hmac_key.copy_from_slice(&keys[44..76]);
+    (chacha_key, chacha_nonce, hmac_key)
+}
+
+/// HMAC-SHA256 of the ciphertext, with the nonce as associated data
+fn hmac(key: &[u8; 32], nonce: &[u8; 32], ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac
+}
+
+fn padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+    let next_power = 1 << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+/// Prefixes the plaintext with its u16 length and zero pads it
+fn pad(plaintext: &str) -> Result<Vec<u8>, Error> {
+    let len = plaintext.len();
+    if !(MIN_PLAINTEXT_SIZE..=MAX_PLAINTEXT_SIZE).contains(&len) {
+        return Err(Error::EncryptionError);
+    }
+    let mut padded = (len as u16).to_be_bytes().to_vec();
+    padded.extend_from_slice(plaintext.as_bytes());
+    padded.resize(2 + padded_len(len), 0);
+    Ok(padded)
+}
+
+fn unpad(padded: &[u8]) -> Result<String, Error> {
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len < MIN_PLAINTEXT_SIZE || padded.len() != 2 + padded_len(len) {
+        return Err(Error::DecryptionError);
+    }
+    String::from_utf8(padded[2..2 + len].to_vec()).map_err(|_| Error::DecryptionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_key(hex: &str) -> SecretKey {
+        SecretKey::from_str(hex).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_vectors() {
+        // From the NIP-44 test vectors
+        let sec1 = secret_key("0000000000000000000000000000000000000000000000000000000000000001");
+        let sec2 = secret_key("0000000000000000000000000000000000000000000000000000000000000002");
+        let pub1 = sec1.x_only_public_key(nostr_sdk::SECP256K1).0;
+        let pub2 = sec2.x_only_public_key(nostr_sdk::SECP256K1).0;
+
+        let key = conversation_key(&sec1, &pub2).unwrap();
+        assert_eq!(
+            ::hex::encode(key),
+            "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d"
+        );
+        assert_eq!(conversation_key(&sec2, &pub1).unwrap(), key);
+
+        let mut nonce = [0; 32];
+        nonce[31] = 1;
+        let payload = "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb";
+        assert_eq!(encrypt_with_nonce(&key, "a", &nonce).unwrap(), payload);
+        assert_eq!(decrypt(&key, payload).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = [7; 32];
+        for len in [1, 32, 33, 300, 65535] {
+            let plaintext = "x".repeat(len);
+            let payload = encrypt(&key, &plaintext).unwrap();
+            assert_eq!(decrypt(&key, &payload).unwrap(), plaintext);
+        }
+        assert!(encrypt(&key, "").is_err());
+
+        // Tampering is detected by the mac
+        let payload = encrypt(&key, "hello").unwrap();
+        let mut data = base64::decode(&payload).unwrap();
+        data[40] ^= 1;
+        assert!(decrypt(&key, &base64::encode(data)).is_err());
+        assert!(decrypt(&[8; 32], &payload).is_err());
+    }
+
+    /// Payload of `padded` encrypted with a valid mac, for padding the spec forbids
+    fn payload(conversation_key: &[u8; 32], padded: &[u8]) -> String {
+        let nonce = [1; 32];
+        let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, &nonce);
+        let mut ciphertext = padded.to_vec();
+        ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+        let mac = hmac(&hmac_key, &nonce, &ciphertext).finalize().into_bytes();
+        base64::encode([&[VERSION], &nonce[..], &ciphertext, &mac].concat())
+    }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let key = [7; 32];
+        let valid = encrypt(&key, "hello").unwrap();
+        assert_eq!(decrypt(&key, &valid).unwrap(), "hello");
+        let data = base64::decode(&valid).unwrap();
+        let modified = |at: usize| {
+            let mut data = data.clone();
+            data[at] ^= 1;
+            base64::encode(data)
+        };
+
+        let mut padded = vec![0, 5];
+        padded.extend_from_slice(b"hello");
+        padded.resize(34, 0);
+        let long = "x".repeat(400);
+        for payload in [
+            // Unknown version
+            modified(0),
+            // Invalid mac, a changed nonce, ciphertext or mac
+            modified(1),
+            modified(40),
+            modified(data.len() - 1),
+            // Future versions are flagged with #
+            format!("#{}", &valid[1..]),
+            // Invalid base64
+            format!("{}!", &valid[..valid.len() - 1]),
+            // Too short and too long
+            valid[..131].to_string(),
+            "A".repeat(87473),
+            // Padding too short or long for the length, and an empty plaintext
+            payload(&key, &padded[..33]),
+            payload(&key, &[&padded[..], &[0; 32]].concat()),
+            payload(&key, &[0; 34]),
+            // Length larger than the padded plaintext
+            payload(&key, &[&[1, 144][..], long.as_bytes()].concat()),
+        ] {
+            assert!(decrypt(&key, &payload).is_err(), "{payload}");
+        }
+        assert_eq!(decrypt(&key, &payload(&key, &padded)).unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_padded_len() {
+        // From the NIP-44 padding vectors
+        for (len, padded) in [
+            (16, 32),
+            (32, 32),
+            (33, 64),
+            (37, 64),
+            (45, 64),
+            (49, 64),
+            (64, 64),
+            (65, 96),
+            (100, 128),
+            (111, 128),
+            (200, 224),
+            (250, 256),
+            (320, 320),
+            (383, 384),
+            (384, 384),
+            (400, 448),
+            (500, 512),
+            (512, 512),
+            (515, 640),
+            (700, 768),
+            (800, 896),
+            (900, 1024),
+            (1020, 1024),
+            (65536, 65536),
+        ] {
+            assert_eq!(padded_len(len), padded, "{len}");
+        }
+    }
+}
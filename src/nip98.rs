@@ -0,0 +1,118 @@
+//! NIP-98 HTTP Auth
+//!
+//! Verifies `Authorization: Nostr <base64 event>` headers sent to the HTTP API
+
+use nostr_sdk::nostr::hashes::{sha256, Hash};
+use nostr_sdk::Event;
+
+use crate::error::Error;
+use crate::utils;
+
+/// Kind of the event used for HTTP auth
+pub const HTTP_AUTH_KIND: u64 = 27235;
+/// Max seconds the auth event `created_at` can differ from now
+pub const TIME_WINDOW: u64 = 60;
+
+/// Verifies a NIP-98 `Authorization` header value against the request.
+/// Returns the hex pubkey of the signer.
+pub fn verify_auth_header(
+    header: &str,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> Result<String, Error> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or(Error::AuthError("Expected Nostr auth scheme"))?;
+
+    let decoded = base64::decode(encoded.trim()).map_err(|_| Error::AuthError("Invalid base64"))?;
+    let event: Event =
+        serde_json::from_slice(&decoded).map_err(|_| Error::AuthError("Invalid event"))?;
+
+    verify_auth_event(&event, url, method, body, utils::unix_time())?;
+
+    Ok(event.pubkey.to_string())
+}
+
+/// Checks the auth event is signed and matches the request
+pub fn verify_auth_event(
+    event: &Event,
+    url: &str,
+    method: &str,
+    body: &[u8],
+    now: u64,
+) -> Result<(), Error> {
+    if event.kind.as_u64() != HTTP_AUTH_KIND {
+        return Err(Error::AuthError("Invalid kind"));
+    }
+
+    if utils::verify_event(event).is_err() {
+        return Err(Error::AuthError("Invalid signature"));
+    }
+
+    if event.created_at.as_u64().abs_diff(now) > TIME_WINDOW {
+        return Err(Error::AuthError("Auth event expired"));
+    }
+
+    if utils::tag_value(event, "u").as_deref() != Some(url) {
+        return Err(Error::AuthError("Url does not match"));
+    }
+
+    match utils::tag_value(event, "method") {
+        Some(m) if m.eq_ignore_ascii_case(method) => (),
+        _ => return Err(Error::AuthError("Method does not match")),
+    }
+
+    if !body.is_empty() {
+        let hash = sha256::Hash::hash(body).to_string();
+        if utils::tag_value(event, "payload").as_deref() != Some(hash.as_str()) {
+            return Err(Error::AuthError("Payload does not match"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::*;
+
+    use super::*;
+
+    fn auth_event(keys: &Keys, url: &str, method: &str, body: Option<&[u8]>) -> Event {
+        let mut tags = vec![
+            Tag::parse(vec!["u", url]).unwrap(),
+            Tag::parse(vec!["method", method]).unwrap(),
+        ];
+        if let Some(body) = body {
+            let hash = sha256::Hash::hash(body).to_string();
+            tags.push(Tag::parse(vec!["payload".to_string(), hash]).unwrap());
+        }
+        EventBuilder::new(Kind::from(HTTP_AUTH_KIND), "", &tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_auth_event() {
+        let keys = Keys::generate();
+        let url = "http://localhost:3000/users";
+        let body = br#"{"status":"allow"}"#;
+        let now = utils::unix_time();
+
+        let event = auth_event(&keys, url, "PUT", Some(body));
+        assert!(verify_auth_event(&event, url, "PUT", body, now).is_ok());
+        assert!(verify_auth_event(&event, url, "DELETE", body, now).is_err());
+        assert!(
+            verify_auth_event(&event, "http://localhost:3000/events", "PUT", body, now).is_err()
+        );
+        assert!(verify_auth_event(&event, url, "PUT", b"{}", now).is_err());
+        assert!(verify_auth_event(&event, url, "PUT", body, now + 2 * TIME_WINDOW).is_err());
+
+        let header = format!("Nostr {}", base64::encode(event.as_json()));
+        assert_eq!(
+            verify_auth_header(&header, url, "PUT", body).unwrap(),
+            keys.public_key().to_string()
+        );
+    }
+}
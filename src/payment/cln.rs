@@ -0,0 +1,102 @@
+//! Core Lightning `clnrest` api, `api_key` is a rune permitted to create and list invoices
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{http_client, BackendInvoice, PaymentBackend};
+use crate::config::Payment;
+use crate::error::Error;
+use crate::utils;
+
+/// Makes labels of invoices created in the same second unique
+static LABEL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct Cln {
+    client: reqwest::Client,
+    url: String,
+    rune: String,
+}
+
+#[derive(Deserialize)]
+struct CreatedInvoice {
+    payment_hash: String,
+    bolt11: String,
+}
+
+#[derive(Deserialize)]
+struct Invoices {
+    invoices: Vec<InvoiceStatus>,
+}
+
+#[derive(Deserialize)]
+struct InvoiceStatus {
+    status: String,
+}
+
+impl Cln {
+    pub fn new(settings: &Payment) -> Result<Self, Error> {
+        Ok(Self {
+            client: http_client(settings)?,
+            url: settings.url.trim_end_matches('/').to_string(),
+            rune: settings.api_key.clone().ok_or(Error::InvoiceError)?,
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for Cln {
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description: &str,
+    ) -> Result<BackendInvoice, Error> {
+        // Labels must be unique
+        let label = format!(
+            "my-local-relay-{}-{}",
+            utils::unix_time(),
+            LABEL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let invoice: CreatedInvoice = self
+            .client
+            .post(format!("{}/v1/invoice", self.url))
+            .header("Rune", &self.rune)
+            .json(&json!({
+                "amount_msat": amount_msat,
+                "label": label,
+                "description": description,
+                "expiry": super::INVOICE_EXPIRY,
+            }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        Ok(BackendInvoice {
+            payment_hash: invoice.payment_hash,
+            bolt11: invoice.bolt11,
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool, Error> {
+        let invoices: Invoices = self
+            .client
+            .post(format!("{}/v1/listinvoices", self.url))
+            .header("Rune", &self.rune)
+            .json(&json!({ "payment_hash": payment_hash }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        Ok(invoices.invoices.iter().any(|i| i.status == "paid"))
+    }
+}
@@ -0,0 +1,53 @@
+//! Backend for tests that keeps invoices in memory
+
+use async_trait::async_trait;
+use nostr_sdk::nostr::hashes::{sha256, Hash};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{BackendInvoice, PaymentBackend};
+use crate::error::Error;
+
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+    /// Payment hash and if it is paid
+    invoices: Mutex<HashMap<String, bool>>,
+}
+
+impl FakeBackend {
+    /// Marks an invoice paid
+    pub fn pay(&self, payment_hash: &str) {
+        if let Some(paid) = self.invoices.lock().unwrap().get_mut(payment_hash) {
+            *paid = true;
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for FakeBackend {
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description: &str,
+    ) -> Result<BackendInvoice, Error> {
+        let mut invoices = self.invoices.lock().unwrap();
+        let preimage = format!("{}:{}:{}", invoices.len(), amount_msat, description);
+        let payment_hash = sha256::Hash::hash(preimage.as_bytes()).to_string();
+        invoices.insert(payment_hash.clone(), false);
+
+        Ok(BackendInvoice {
+            bolt11: format!("lnfake{amount_msat}{payment_hash}"),
+            payment_hash,
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool, Error> {
+        self.invoices
+            .lock()
+            .unwrap()
+            .get(payment_hash)
+            .copied()
+            .ok_or(Error::InvoiceError)
+    }
+}
@@ -0,0 +1,83 @@
+//! LNbits wallet, `api_key` is the invoice key of the wallet
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{http_client, BackendInvoice, PaymentBackend};
+use crate::config::Payment;
+use crate::error::Error;
+
+pub struct LnBits {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct CreatedInvoice {
+    payment_hash: String,
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct PaymentStatus {
+    paid: bool,
+}
+
+impl LnBits {
+    pub fn new(settings: &Payment) -> Result<Self, Error> {
+        Ok(Self {
+            client: http_client(settings)?,
+            url: settings.url.trim_end_matches('/').to_string(),
+            api_key: settings.api_key.clone().ok_or(Error::InvoiceError)?,
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for LnBits {
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description: &str,
+    ) -> Result<BackendInvoice, Error> {
+        let invoice: CreatedInvoice = self
+            .client
+            .post(format!("{}/api/v1/payments", self.url))
+            .header("X-Api-Key", &self.api_key)
+            .json(&json!({
+                "out": false,
+                "amount": amount_msat / 1000,
+                "memo": description,
+            }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        Ok(BackendInvoice {
+            payment_hash: invoice.payment_hash,
+            bolt11: invoice.payment_request,
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool, Error> {
+        let status: PaymentStatus = self
+            .client
+            .get(format!("{}/api/v1/payments/{}", self.url, payment_hash))
+            .header("X-Api-Key", &self.api_key)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        Ok(status.paid)
+    }
+}
@@ -0,0 +1,85 @@
+//! LND REST api, `api_key` is the hex invoice macaroon
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{http_client, BackendInvoice, PaymentBackend};
+use crate::config::Payment;
+use crate::error::Error;
+
+pub struct Lnd {
+    client: reqwest::Client,
+    url: String,
+    macaroon: String,
+}
+
+#[derive(Deserialize)]
+struct AddInvoiceResponse {
+    /// Base64 payment hash
+    r_hash: String,
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct Invoice {
+    state: String,
+}
+
+impl Lnd {
+    pub fn new(settings: &Payment) -> Result<Self, Error> {
+        Ok(Self {
+            client: http_client(settings)?,
+            url: settings.url.trim_end_matches('/').to_string(),
+            macaroon: settings.api_key.clone().ok_or(Error::InvoiceError)?,
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for Lnd {
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description: &str,
+    ) -> Result<BackendInvoice, Error> {
+        let invoice: AddInvoiceResponse = self
+            .client
+            .post(format!("{}/v1/invoices", self.url))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .json(&json!({
+                "value_msat": amount_msat.to_string(),
+                "memo": description,
+                "expiry": super::INVOICE_EXPIRY.to_string(),
+            }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        let payment_hash = base64::decode(invoice.r_hash).map_err(|_| Error::InvoiceError)?;
+        Ok(BackendInvoice {
+            payment_hash: hex::encode(payment_hash),
+            bolt11: invoice.payment_request,
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool, Error> {
+        let invoice: Invoice = self
+            .client
+            .get(format!("{}/v1/invoice/{}", self.url, payment_hash))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::InvoiceError)?
+            .json()
+            .await
+            .map_err(|_| Error::InvoiceError)?;
+
+        Ok(invoice.state == "SETTLED")
+    }
+}
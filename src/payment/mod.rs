@@ -0,0 +1,358 @@
+//! Paid admission with Lightning invoices
+//!
+//! An invoice is created for a pubkey through a [`PaymentBackend`] and tracked in the db
+//! until it is paid or expires. When it is paid the pubkey is admitted for as long as the
+//! amount pays for.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Payment;
+use crate::db::Account;
+use crate::error::Error;
+use crate::repo::Repo;
+use crate::shutdown::Shutdown;
+use crate::utils;
+
+pub mod cln;
+#[cfg(any(test, feature = "fake-payments"))]
+pub mod fake;
+pub mod lnbits;
+pub mod lnd;
+
+/// Seconds an unpaid invoice is kept
+pub const INVOICE_EXPIRY: u64 = 3600;
+/// How often pending invoices are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Most unpaid invoices a pubkey can have
+const MAX_PENDING_INVOICES: usize = 3;
+
+/// Invoice created by a backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendInvoice {
+    /// Hex payment hash
+    pub payment_hash: String,
+    pub bolt11: String,
+}
+
+/// Lightning node or wallet that creates invoices
+#[async_trait]
+pub trait PaymentBackend: Send + Sync {
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description: &str,
+    ) -> Result<BackendInvoice, Error>;
+
+    /// Checks an invoice has been paid
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool, Error>;
+}
+
+/// Invoice tracked until it is paid or expires.
+/// Paid invoices are kept until they would have expired so clients can see they were paid.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingInvoice {
+    pub payment_hash: String,
+    pub pubkey: String,
+    pub amount_msat: u64,
+    pub bolt11: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub paid: bool,
+}
+
+impl PendingInvoice {
+    fn is_expired(&self) -> bool {
+        self.created_at + INVOICE_EXPIRY < utils::unix_time()
+    }
+}
+
+/// Creates invoices and admits pubkeys that pay them
+pub struct Payments {
+    backend: Arc<dyn PaymentBackend>,
+    repo: Arc<Mutex<Repo>>,
+    sats_per_day: u64,
+    /// Most invoices created in an hour
+    invoices_per_hour: u64,
+}
+
+impl Payments {
+    pub fn new(
+        backend: Arc<dyn PaymentBackend>,
+        repo: Arc<Mutex<Repo>>,
+        sats_per_day: u64,
+        invoices_per_hour: u64,
+    ) -> Self {
+        Self {
+            backend,
+            repo,
+            sats_per_day: sats_per_day.max(1),
+            invoices_per_hour,
+        }
+    }
+
+    /// Creates payments with the backend set in the config
+    pub fn from_settings(settings: &Payment, repo: Arc<Mutex<Repo>>) -> Result<Self, Error> {
+        let backend: Arc<dyn PaymentBackend> = match settings.backend.as_str() {
+            "lnbits" => Arc::new(lnbits::LnBits::new(settings)?),
+            "lnd" => Arc::new(lnd::Lnd::new(settings)?),
+            "cln" => Arc::new(cln::Cln::new(settings)?),
+            #[cfg(any(test, feature = "fake-payments"))]
+            "fake" => Arc::new(fake::FakeBackend::default()),
+            _ => return Err(Error::InvoiceError),
+        };
+        Ok(Self::new(
+            backend,
+            repo,
+            settings.sats_per_day,
+            settings.invoices_per_hour,
+        ))
+    }
+
+    /// Creates an invoice admitting `pubkey` for `days`.
+    /// Anyone can create invoices so they are limited per pubkey and per hour.
+    pub async fn create_invoice(&self, pubkey: &str, days: u64) -> Result<PendingInvoice, Error> {
+        let amount_msat = days
+            .checked_mul(self.sats_per_day)
+            .and_then(|sats| sats.checked_mul(1000))
+            .ok_or(Error::InvoiceError)?;
+        {
+            let repo = self.repo.lock().await;
+            let pending = repo
+                .get_invoices()?
+                .iter()
+                .filter(|invoice| invoice.pubkey == pubkey && !invoice.paid)
+                .filter(|invoice| !invoice.is_expired())
+                .count();
+            if pending >= MAX_PENDING_INVOICES || !repo.count_invoice(self.invoices_per_hour)? {
+                return Err(Error::TooManyInvoices);
+            }
+        }
+        let description = format!("Admission of {pubkey} for {days} days");
+        let invoice = self
+            .backend
+            .create_invoice(amount_msat, &description)
+            .await?;
+
+        let pending = PendingInvoice {
+            payment_hash: invoice.payment_hash,
+            pubkey: pubkey.to_string(),
+            amount_msat,
+            bolt11: invoice.bolt11,
+            created_at: utils::unix_time(),
+            paid: false,
+        };
+        self.repo.lock().await.add_invoice(&pending)?;
+        Ok(pending)
+    }
+
+    /// Checks a pending invoice and admits its pubkey if paid.
+    /// Returns the admitted account if it was paid.
+    pub async fn check_invoice(&self, payment_hash: &str) -> Result<Option<Account>, Error> {
+        let invoice = match self.repo.lock().await.get_invoice(payment_hash)? {
+            Some(invoice) => invoice,
+            None => return Err(Error::NotFound),
+        };
+        if invoice.paid {
+            return self.repo.lock().await.get_account(&invoice.pubkey);
+        }
+
+        if !self.backend.is_paid(payment_hash).await? {
+            if invoice.is_expired() {
+                debug!("Invoice {} expired", payment_hash);
+                self.repo.lock().await.remove_invoice(payment_hash)?;
+            }
+            return Ok(None);
+        }
+
+        // The invoice is marked paid in the same transaction as the admission is extended,
+        // it may have been counted by another check while the backend was asked
+        let seconds = invoice.amount_msat / 1000 * 86_400 / self.sats_per_day;
+        let repo = self.repo.lock().await;
+        match repo.pay_invoice(payment_hash, seconds)? {
+            Some(account) if !account.is_admitted() => {
+                info!(
+                    "Not admitting {} after payment, it is denied",
+                    invoice.pubkey
+                );
+                Ok(Some(account))
+            }
+            Some(account) => {
+                info!(
+                    "Admitted {} until {:?} after payment",
+                    invoice.pubkey, account.expires_at
+                );
+                Ok(Some(account))
+            }
+            None => repo.get_account(&invoice.pubkey),
+        }
+    }
+
+    /// Periodically checks pending invoices so pubkeys are admitted without polling the api
+    pub async fn check_pending(self: Arc<Self>, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone().wait() => return,
+                _ = interval.tick() => (),
+            }
+            let pending = match self.repo.lock().await.get_invoices() {
+                Ok(pending) => pending,
+                Err(err) => {
+                    error!("Error reading pending invoices: {}", err);
+                    continue;
+                }
+            };
+            for invoice in pending {
+                if invoice.paid {
+                    if invoice.is_expired() {
+                        if let Err(err) =
+                            self.repo.lock().await.remove_invoice(&invoice.payment_hash)
+                        {
+                            error!("Error removing invoice {}: {}", invoice.payment_hash, err);
+                        }
+                    }
+                    continue;
+                }
+                if let Err(err) = self.check_invoice(&invoice.payment_hash).await {
+                    error!("Error checking invoice {}: {}", invoice.payment_hash, err);
+                }
+            }
+        }
+    }
+}
+
+/// Http client for backends, trusting `tls_cert` if set for nodes with self signed certificates
+fn http_client(settings: &Payment) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    if let Some(path) = &settings.tls_cert {
+        let cert = std::fs::read(path)?;
+        let cert = reqwest::Certificate::from_pem(&cert).map_err(|_| Error::InvoiceError)?;
+        builder = builder.add_root_certificate(cert);
+    }
+    builder.build().map_err(|_| Error::InvoiceError)
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::*;
+    use crate::db::{Role, Status};
+
+    #[tokio::test]
+    #[serial]
+    async fn test_paid_admission() {
+        let repo = Arc::new(Mutex::new(Repo::new()));
+        let backend = Arc::new(fake::FakeBackend::default());
+        let payments = Payments::new(backend.clone(), repo.clone(), 10, 100);
+        let pubkey = nostr_sdk::Keys::generate().public_key().to_string();
+
+        let invoice = payments.create_invoice(&pubkey, 30).await.unwrap();
+        assert_eq!(invoice.amount_msat, 300_000);
+        assert_eq!(
+            payments.check_invoice(&invoice.payment_hash).await.unwrap(),
+            None
+        );
+        assert!(repo.lock().await.get_account(&pubkey).unwrap().is_none());
+
+        backend.pay(&invoice.payment_hash);
+        let account = payments
+            .check_invoice(&invoice.payment_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(account.expires_at.unwrap() >= utils::unix_time() + 30 * 86_400 - 5);
+        assert_eq!(
+            repo.lock().await.get_account(&pubkey).unwrap().as_ref(),
+            Some(&account)
+        );
+        // Paying is only counted once
+        assert_eq!(
+            payments.check_invoice(&invoice.payment_hash).await.unwrap(),
+            Some(account)
+        );
+        assert!(matches!(
+            payments.check_invoice("unknown").await,
+            Err(Error::NotFound)
+        ));
+        // Nor when a check had already counted it
+        assert_eq!(
+            repo.lock()
+                .await
+                .pay_invoice(&invoice.payment_hash, 86_400)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_invoice_limits() {
+        let repo = Arc::new(Mutex::new(Repo::new()));
+        let backend = Arc::new(fake::FakeBackend::default());
+        let payments = Payments::new(backend.clone(), repo.clone(), u64::MAX / 1000, 1_000_000);
+        let pubkey = nostr_sdk::Keys::generate().public_key().to_string();
+
+        assert!(matches!(
+            payments.create_invoice(&pubkey, 2).await,
+            Err(Error::InvoiceError)
+        ));
+
+        let payments = Payments::new(backend.clone(), repo.clone(), 10, 1_000_000);
+        for _ in 0..MAX_PENDING_INVOICES {
+            payments.create_invoice(&pubkey, 1).await.unwrap();
+        }
+        assert!(matches!(
+            payments.create_invoice(&pubkey, 1).await,
+            Err(Error::TooManyInvoices)
+        ));
+
+        // Every pubkey counts towards the hourly limit
+        let payments = Payments::new(backend, repo.clone(), 10, 0);
+        let other = nostr_sdk::Keys::generate().public_key().to_string();
+        assert!(matches!(
+            payments.create_invoice(&other, 1).await,
+            Err(Error::TooManyInvoices)
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_denied_payment() {
+        let repo = Arc::new(Mutex::new(Repo::new()));
+        let backend = Arc::new(fake::FakeBackend::default());
+        let payments = Payments::new(backend.clone(), repo.clone(), 10, 100);
+        let pubkey = nostr_sdk::Keys::generate().public_key().to_string();
+
+        // Denied after the invoice was created, paying does not lift the ban
+        let invoice = payments.create_invoice(&pubkey, 30).await.unwrap();
+        repo.lock()
+            .await
+            .deny_pubkeys(std::slice::from_ref(&pubkey), Role::Owner)
+            .await
+            .unwrap();
+        backend.pay(&invoice.payment_hash);
+        let account = payments
+            .check_invoice(&invoice.payment_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.status, Status::Deny);
+        assert!(!account.is_admitted());
+        assert!(repo.lock().await.is_denied(&pubkey).unwrap());
+        assert!(
+            repo.lock()
+                .await
+                .get_invoice(&invoice.payment_hash)
+                .unwrap()
+                .unwrap()
+                .paid
+        );
+    }
+}
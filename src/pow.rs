@@ -0,0 +1,127 @@
+//! NIP-13 proof of work policy for authors that are not admitted
+
+use std::collections::HashMap;
+
+use crate::config::Pow;
+use crate::nauthz_grpc::Event;
+
+#[derive(Debug, Clone, Default)]
+pub struct PowPolicy {
+    /// Leading zero bits required by kind
+    difficulty: HashMap<u64, u8>,
+    /// Leading zero bits required of kinds not in `difficulty`
+    default_difficulty: Option<u8>,
+}
+
+impl PowPolicy {
+    pub fn new(pow: &Pow) -> Self {
+        Self {
+            difficulty: pow
+                .difficulty
+                .iter()
+                .filter_map(|(kind, difficulty)| Some((kind.parse().ok()?, *difficulty)))
+                .collect(),
+            default_difficulty: pow.default_difficulty,
+        }
+    }
+
+    /// Difficulty required for a kind, `None` if events of the kind cannot be admitted with work
+    pub fn required(&self, kind: u64) -> Option<u8> {
+        self.difficulty
+            .get(&kind)
+            .copied()
+            .or(self.default_difficulty)
+    }
+
+    /// Checks the event has the proof of work required for its kind.
+    /// Returns a message telling the client the required difficulty if it does not.
+    pub fn check(&self, event: &Event) -> Result<(), String> {
+        let required = match self.required(event.kind) {
+            Some(required) => required,
+            None => return Err("Not allowed to publish".to_string()),
+        };
+
+        if has_difficulty(event, required) {
+            Ok(())
+        } else {
+            Err(format!("pow: difficulty {required} is required"))
+        }
+    }
+}
+
+/// Checks the event id has `required` leading zero bits and the `nonce` tag
+/// commits to a target at least as high
+pub fn has_difficulty(event: &Event, required: u8) -> bool {
+    let committed = event
+        .tags
+        .iter()
+        .find_map(|tag| match tag.values.as_slice() {
+            [name, _nonce, target, ..] if name == "nonce" => target.parse::<u8>().ok(),
+            _ => None,
+        });
+
+    leading_zero_bits(&event.id) >= required && committed.is_some_and(|t| t >= required)
+}
+
+/// Counts the leading zero bits of an event id
+pub fn leading_zero_bits(id: &[u8]) -> u8 {
+    let mut bits = 0;
+    for byte in id {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros() as u8;
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nauthz_grpc::event::TagEntry;
+
+    use super::*;
+
+    fn event(id: Vec<u8>, kind: u64, target: Option<&str>) -> Event {
+        Event {
+            id,
+            pubkey: vec![],
+            created_at: 0,
+            kind,
+            content: "".to_string(),
+            tags: target
+                .map(|t| TagEntry {
+                    values: vec!["nonce".to_string(), "1".to_string(), t.to_string()],
+                })
+                .into_iter()
+                .collect(),
+            sig: vec![],
+        }
+    }
+
+    #[test]
+    fn test_pow_policy() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0x0f, 0xff]), 20);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+
+        let policy = PowPolicy::new(&Pow {
+            difficulty: HashMap::from([("1".to_string(), 20)]),
+            default_difficulty: None,
+        });
+        let id = vec![0, 0, 0x0f, 0xff];
+
+        assert!(policy.check(&event(id.clone(), 1, Some("20"))).is_ok());
+        // Committed target too low
+        assert!(policy.check(&event(id.clone(), 1, Some("16"))).is_err());
+        // No commitment
+        assert!(policy.check(&event(id.clone(), 1, None)).is_err());
+        // Not enough work
+        assert_eq!(
+            policy.check(&event(vec![0, 0xff], 1, Some("20"))),
+            Err("pow: difficulty 20 is required".to_string())
+        );
+        // Kind without a policy
+        assert!(policy.check(&event(id, 7, Some("20"))).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Health and scores of relays events are fetched from
+//!
+//! Every fetch from a relay is recorded: whether it succeeded, how long it took and, for
+//! referenced events, how many of the requested ids the relay had. Relays are fetched
+//! from in order of score, and relays that keep failing are not fetched from for a time
+//! that doubles with each failure.
+
+use nostr_sdk::Url;
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+/// Consecutive failures before a relay is disabled
+const FAILURES_BEFORE_BACKOFF: u32 = 3;
+/// Seconds a relay is first disabled for, doubled with each further failure
+const BACKOFF_SECONDS: u64 = 60;
+/// Longest a failing relay is disabled for
+const MAX_BACKOFF_SECONDS: u64 = 6 * 60 * 60;
+/// Seconds a relay that returned invalid events is disabled for, if penalised
+const INVALID_EVENTS_PENALTY_SECONDS: u64 = 60 * 60;
+/// Latency at which a relay gets none of the latency part of its score
+const SLOW_LATENCY_MS: f64 = 10_000.0;
+
+/// Outcome of fetching from one relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayFetch {
+    pub url: Url,
+    /// False if the relay could not be sent the request or did not finish in time
+    pub ok: bool,
+    pub latency: Duration,
+    /// Referenced ids requested, 0 for fetches by author
+    pub requested: u64,
+    /// Valid events returned
+    pub found: u64,
+    /// Events dropped as not requested or invalid
+    pub invalid: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayStats {
+    pub url: String,
+    /// Fetches made
+    pub requests: u64,
+    /// Fetches that failed or timed out
+    pub failures: u64,
+    /// Sum of the latency of successful fetches
+    pub latency_ms: u64,
+    /// Referenced ids requested
+    pub lookups: u64,
+    /// Referenced ids found
+    pub hits: u64,
+    /// Invalid events returned
+    pub invalid: u64,
+    /// Failures since the last success
+    pub consecutive_failures: u32,
+    /// Unix time until which the relay is not fetched from
+    pub disabled_until: Option<u64>,
+}
+
+impl RelayStats {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Share of fetches that succeeded, relays that have not been fetched from get 1
+    pub fn success_rate(&self) -> f64 {
+        match self.requests {
+            0 => 1.0,
+            requests => (requests - self.failures) as f64 / requests as f64,
+        }
+    }
+
+    /// Share of requested referenced ids found, relays without lookups get 0.5
+    pub fn hit_rate(&self) -> f64 {
+        match self.lookups {
+            0 => 0.5,
+            lookups => self.hits.min(lookups) as f64 / lookups as f64,
+        }
+    }
+
+    pub fn average_latency_ms(&self) -> Option<u64> {
+        match self.requests - self.failures {
+            0 => None,
+            succeeded => Some(self.latency_ms / succeeded),
+        }
+    }
+
+    /// Score between 0 and 1, higher relays are fetched from first
+    pub fn score(&self) -> f64 {
+        let latency = self
+            .average_latency_ms()
+            .map(|ms| (ms as f64 / SLOW_LATENCY_MS).min(1.0))
+            .unwrap_or(0.0);
+        0.4 * self.success_rate() + 0.4 * self.hit_rate() + 0.2 * (1.0 - latency)
+    }
+
+    pub fn is_disabled(&self, now: u64) -> bool {
+        self.disabled_until.is_some_and(|until| until > now)
+    }
+
+    /// Records a fetch, disabling the relay if it keeps failing or, if `penalise` is set,
+    /// returned invalid events
+    pub fn record(&mut self, fetch: &RelayFetch, now: u64, penalise: bool) {
+        self.requests += 1;
+        self.lookups += fetch.requested;
+        self.hits += fetch.found.min(fetch.requested);
+        self.invalid += fetch.invalid;
+
+        if fetch.ok {
+            self.latency_ms += fetch.latency.as_millis() as u64;
+            self.consecutive_failures = 0;
+            self.disabled_until = None;
+        } else {
+            self.failures += 1;
+            self.consecutive_failures += 1;
+            if self.consecutive_failures >= FAILURES_BEFORE_BACKOFF {
+                let doublings = (self.consecutive_failures - FAILURES_BEFORE_BACKOFF).min(16);
+                let backoff = (BACKOFF_SECONDS << doublings).min(MAX_BACKOFF_SECONDS);
+                self.disabled_until = Some(now + backoff);
+            }
+        }
+
+        if fetch.invalid > 0 && penalise {
+            let until = now + INVALID_EVENTS_PENALTY_SECONDS;
+            self.disabled_until = Some(self.disabled_until.map_or(until, |d| d.max(until)));
+        }
+    }
+}
+
+/// Stats of a relay with its computed rates, returned by the HTTP API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RelayScore {
+    #[serde(flatten)]
+    pub stats: RelayStats,
+    pub score: f64,
+    pub success_rate: f64,
+    pub hit_rate: f64,
+    pub average_latency_ms: Option<u64>,
+    pub disabled: bool,
+}
+
+impl RelayScore {
+    pub fn new(stats: RelayStats, now: u64) -> Self {
+        Self {
+            score: stats.score(),
+            success_rate: stats.success_rate(),
+            hit_rate: stats.hit_rate(),
+            average_latency_ms: stats.average_latency_ms(),
+            disabled: stats.is_disabled(now),
+            stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(ok: bool, requested: u64, found: u64, invalid: u64) -> RelayFetch {
+        RelayFetch {
+            url: Url::parse("wss://relay.example.com").unwrap(),
+            ok,
+            latency: Duration::from_millis(500),
+            requested,
+            found,
+            invalid,
+        }
+    }
+
+    #[test]
+    fn test_relay_stats() {
+        let mut stats = RelayStats::new("wss://relay.example.com");
+        let unknown = stats.score();
+
+        stats.record(&fetch(true, 4, 4, 0), 0, true);
+        assert_eq!(stats.hit_rate(), 1.0);
+        assert_eq!(stats.average_latency_ms(), Some(500));
+        assert!(stats.score() > unknown);
+
+        // Disabled after repeated failures, for longer each time
+        stats.record(&fetch(false, 4, 0, 0), 0, true);
+        stats.record(&fetch(false, 4, 0, 0), 0, true);
+        assert!(!stats.is_disabled(0));
+        stats.record(&fetch(false, 4, 0, 0), 0, true);
+        assert_eq!(stats.disabled_until, Some(BACKOFF_SECONDS));
+        stats.record(&fetch(false, 4, 0, 0), 0, true);
+        assert_eq!(stats.disabled_until, Some(2 * BACKOFF_SECONDS));
+        assert_eq!(stats.success_rate(), 0.2);
+
+        // A success enables the relay again
+        stats.record(&fetch(true, 4, 2, 0), 0, true);
+        assert!(!stats.is_disabled(0));
+
+        // Invalid events only disable the relay if penalised
+        stats.record(&fetch(true, 4, 2, 1), 0, false);
+        assert!(!stats.is_disabled(0));
+        stats.record(&fetch(true, 4, 2, 1), 0, true);
+        assert!(stats.is_disabled(INVALID_EVENTS_PENALTY_SECONDS - 1));
+    }
+}
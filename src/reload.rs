@@ -0,0 +1,200 @@
+//! Reloading config.toml without restarting
+//!
+//! The settings, and the policies built from them, are replaced as a whole when the
+//! config file changes or the service receives SIGHUP. A config that fails to load or
+//! validate is logged and the running settings are kept.
+
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::client::NostrClient;
+use crate::config::Settings;
+use crate::error::Error;
+use crate::filter::ContentFilter;
+use crate::pow::PowPolicy;
+use crate::repo::Repo;
+use crate::shutdown::Shutdown;
+
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Settings and the policies built from them
+#[derive(Debug)]
+pub struct LiveConfig {
+    pub settings: Settings,
+    pub filter: ContentFilter,
+    pub pow: PowPolicy,
+}
+
+impl LiveConfig {
+    pub fn new(settings: Settings) -> Result<Self, Error> {
+        Ok(Self {
+            filter: ContentFilter::new(&settings.filters)?,
+            pow: PowPolicy::new(&settings.pow),
+            settings,
+        })
+    }
+}
+
+/// Handle to the running config, shared by everything that reads settings
+#[derive(Debug, Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<LiveConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: LiveConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The current config, unchanged for as long as it is held
+    pub fn current(&self) -> Arc<LiveConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, config: LiveConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+pub struct Reloader {
+    pub path: PathBuf,
+    pub config: SharedConfig,
+    pub repo: Arc<Mutex<Repo>>,
+    pub nostr: Arc<Mutex<NostrClient>>,
+}
+
+impl Reloader {
+    /// Loads the config file and swaps it in, applying changes to the repo and relays
+    pub async fn reload(&self) -> Result<(), Error> {
+        let settings = Settings::load(&self.path.to_string_lossy())?;
+        let problems = settings.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidConfig(problems.join(", ")));
+        }
+        let new = LiveConfig::new(settings)?;
+        let old = self.config.current();
+
+        {
+            let mut repo = self.repo.lock().await;
+            if new.settings.info.admin_keys != old.settings.info.admin_keys {
+                repo.admit_pubkeys(&new.settings.info.admin_keys).await?;
+                repo.set_owners(&new.settings.info.admin_keys)?;
+            }
+            repo.set_inbox(new.settings.inbox.clone());
+        }
+
+        if new.settings.info.default_relays != old.settings.info.default_relays {
+            self.nostr
+                .lock()
+                .await
+                .set_relays(&new.settings.info.default_relays)
+                .await?;
+        }
+
+        for (name, changed) in [
+            (
+                "payment",
+                format!("{:?}", new.settings.payment) != format!("{:?}", old.settings.payment),
+            ),
+            (
+                "imports",
+                format!("{:?}", new.settings.imports) != format!("{:?}", old.settings.imports),
+            ),
+            (
+                "secret_key",
+                new.settings.info.secret_key != old.settings.info.secret_key
+                    || new.settings.info.secret_key_file != old.settings.info.secret_key_file,
+            ),
+        ] {
+            if changed {
+                warn!("Changes to {} take effect after a restart", name);
+            }
+        }
+
+        self.config.replace(new);
+        info!("Reloaded config from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Reloads when the config file is modified or on SIGHUP
+    pub async fn watch(self, shutdown: Shutdown) {
+        let mut modified = modified_time(&self.path);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(hangup) => Some(hangup),
+            Err(err) => {
+                warn!("Could not listen for SIGHUP: {}", err);
+                None
+            }
+        };
+
+        loop {
+            #[cfg(unix)]
+            let hangup_recv = async {
+                match hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_recv = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = shutdown.clone().wait() => return,
+                _ = interval.tick() => {
+                    let current = modified_time(&self.path);
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                }
+                _ = hangup_recv => info!("Received SIGHUP"),
+            }
+
+            if let Err(err) = self.reload().await {
+                error!("Could not reload config, keeping running config: {}", err);
+            }
+        }
+    }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Filters;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_config_replace() {
+        let config = SharedConfig::new(LiveConfig::new(Settings::default()).unwrap());
+        let held = config.current();
+
+        let mut settings = Settings::default();
+        settings.info.relay = "ws://localhost:8081".to_string();
+        config.replace(LiveConfig::new(settings).unwrap());
+
+        // A config held across a reload is unchanged
+        assert!(held.settings.info.relay.is_empty());
+        assert_eq!(config.current().settings.info.relay, "ws://localhost:8081");
+
+        // Invalid filters are rejected before anything is replaced
+        let settings = Settings {
+            filters: Filters {
+                content_patterns: vec!["(".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(LiveConfig::new(settings).is_err());
+    }
+}
@@ -1,19 +1,42 @@
-use nostr_sdk::EventId;
+use nostr_sdk::{EventId, Url};
 
+use crate::api::Users;
+use crate::command::{self, Command, CommandReport};
+use crate::config::Inbox;
 use crate::db::Account;
 use crate::db::Status;
-use crate::db::{self, Db};
+use crate::db::{self, Admin, Db, Role};
 use crate::error::Error;
+use crate::imports::ImportJob;
 use crate::nauthz_grpc::Event;
-use crate::Users;
-use tracing::debug;
+use crate::payment::PendingInvoice;
+use crate::relays::{RelayFetch, RelayScore, RelayStats};
+use crate::{pow, utils};
+use tokio::sync::watch;
+use tracing::{debug, warn};
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+/// Kind of NIP-09 event deletions
+pub const DELETION_KIND: u64 = 5;
+
+/// Why an event is admitted or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The author or the event is admitted
+    Admitted,
+    /// The event is addressed to an admitted pubkey
+    Inbox,
+    Denied,
+}
+
 #[derive(Clone)]
 pub struct Repo {
     db: Arc<Mutex<Db>>,
+    inbox: Inbox,
+    /// Notified when an account is written or removed
+    accounts: Arc<watch::Sender<()>>,
 }
 
 impl Default for Repo {
@@ -26,24 +49,47 @@ impl Repo {
     pub fn new() -> Self {
         Repo {
             db: Arc::new(Mutex::new(Db::new())),
+            inbox: Inbox::default(),
+            accounts: Arc::new(watch::channel(()).0),
         }
     }
 
+    /// Receiver that sees a change whenever an account is written or removed
+    pub fn watch_accounts(&self) -> watch::Receiver<()> {
+        self.accounts.subscribe()
+    }
+
+    /// Sets the policy for events addressed to admitted pubkeys
+    pub fn set_inbox(&mut self, inbox: Inbox) {
+        self.inbox = inbox;
+    }
+
     pub fn add_account(&self, account: &Account) -> Result<(), Error> {
-        self.db.lock().unwrap().write_account(account)
+        self.db.lock().unwrap().write_account(account)?;
+        self.accounts.send_replace(());
+        Ok(())
     }
 
     pub fn get_account(&self, pubkey: &str) -> Result<Option<Account>, Error> {
         self.db.lock().unwrap().read_account(pubkey)
     }
 
+    /// Sets the status of an account, keeping its expiry unless the expiry has passed
+    /// so allowing an account again does not lift an admission that is still running
     pub async fn update_account(&self, pubkey: &str, status: Status) -> Result<Account, Error> {
+        let db = self.db.lock().unwrap();
+        let expires_at = db
+            .read_account(pubkey)?
+            .and_then(|account| account.expires_at)
+            .filter(|expires_at| *expires_at > utils::unix_time());
         let account = Account {
             pubkey: pubkey.to_string(),
             status,
+            expires_at,
         };
 
-        self.db.lock().unwrap().write_account(&account)?;
+        db.write_account(&account)?;
+        self.accounts.send_replace(());
 
         Ok(account)
     }
@@ -56,6 +102,45 @@ impl Repo {
         self.db.lock().unwrap().read_accounts()
     }
 
+    pub fn get_accounts_page(
+        &self,
+        status: Option<Status>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<Account>, Option<String>), Error> {
+        self.db
+            .lock()
+            .unwrap()
+            .read_accounts_page(status, cursor, limit)
+    }
+
+    /// Pubkeys that are allowed and whose admission has not expired
+    pub fn get_admitted_pubkeys(&self) -> Result<Vec<String>, Error> {
+        let db = self.db.lock().unwrap();
+        let mut pubkeys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (accounts, next) =
+                db.read_accounts_page(Some(Status::Allow), cursor.as_deref(), 1000)?;
+            pubkeys.extend(
+                accounts
+                    .into_iter()
+                    .filter(|a| a.is_admitted())
+                    .map(|a| a.pubkey),
+            );
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(pubkeys),
+            }
+        }
+    }
+
+    pub fn remove_account(&self, pubkey: &str) -> Result<bool, Error> {
+        let removed = self.db.lock().unwrap().delete_account(pubkey)?;
+        self.accounts.send_replace(());
+        Ok(removed)
+    }
+
     pub async fn admit_pubkeys(&self, pubkeys: &[String]) -> Result<(), Error> {
         for pubkey in pubkeys {
             // Really basic check that its a key
@@ -67,38 +152,382 @@ impl Repo {
         Ok(())
     }
 
-    pub async fn deny_pubkeys(&self, pubkeys: &[String]) -> Result<(), Error> {
+    /// Whether `by` can deny, expire or remove a pubkey, keys with a role can only be
+    /// managed by a higher role
+    pub fn can_moderate(&self, pubkey: &str, by: Role) -> Result<bool, Error> {
+        Ok(self
+            .get_role(pubkey)?
+            .is_none_or(|role| by.can_manage(role)))
+    }
+
+    /// Denies the pubkeys `by` can moderate, returning them
+    pub async fn deny_pubkeys(&self, pubkeys: &[String], by: Role) -> Result<Vec<String>, Error> {
+        let mut denied = Vec::new();
+        for pubkey in pubkeys {
+            if self.can_moderate(pubkey, by)? {
+                self.update_account(pubkey, Status::Deny).await.ok();
+                denied.push(pubkey.clone());
+            }
+        }
+        Ok(denied)
+    }
+
+    /// Sets the expiry of existing accounts `by` can moderate, returning the pubkeys it can
+    /// An expiry can only be brought forward, remove and re-allow an account to lift it
+    pub async fn expire_pubkeys(
+        &self,
+        pubkeys: &[String],
+        expires_at: u64,
+        by: Role,
+    ) -> Result<Vec<String>, Error> {
+        let mut expired = Vec::new();
         for pubkey in pubkeys {
-            self.update_account(pubkey, Status::Deny).await.ok();
+            if !self.can_moderate(pubkey, by)? {
+                continue;
+            }
+            if let Some(mut account) = self.get_account(pubkey)? {
+                if account.expires_at.is_none_or(|e| expires_at < e) {
+                    account.expires_at = Some(expires_at);
+                    self.add_account(&account)?;
+                }
+            }
+            expired.push(pubkey.clone());
+        }
+        Ok(expired)
+    }
+
+    pub fn get_role(&self, pubkey: &str) -> Result<Option<Role>, Error> {
+        self.db.lock().unwrap().read_role(pubkey)
+    }
+
+    pub fn get_admins(&self) -> Result<Vec<Admin>, Error> {
+        self.db.lock().unwrap().read_roles()
+    }
+
+    /// Makes the config `admin_keys` the only owners
+    pub fn set_owners(&self, pubkeys: &[String]) -> Result<(), Error> {
+        let db = self.db.lock().unwrap();
+        for admin in db.read_roles()? {
+            if admin.role == Role::Owner && !pubkeys.contains(&admin.pubkey) {
+                db.delete_role(&admin.pubkey)?;
+            }
+        }
+        for pubkey in pubkeys {
+            db.write_role(pubkey, Role::Owner)?;
         }
         Ok(())
     }
 
-    pub async fn handle_admission_update(&self, event: Event) -> Result<(), Error> {
+    /// Gives `role` to a pubkey, `by` must outrank both `role` and any current role of the key
+    pub fn set_role(&self, pubkey: &str, role: Role, by: Role) -> Result<bool, Error> {
+        if !by.can_manage(role) {
+            return Ok(false);
+        }
+        if let Some(current) = self.get_role(pubkey)? {
+            if !by.can_manage(current) {
+                return Ok(false);
+            }
+        }
+        self.db.lock().unwrap().write_role(pubkey, role)?;
+        Ok(true)
+    }
+
+    /// Removes `role` from a pubkey that has it, `by` must outrank `role`
+    pub fn remove_role(&self, pubkey: &str, role: Role, by: Role) -> Result<bool, Error> {
+        match self.get_role(pubkey)? {
+            Some(current) if current == role && by.can_manage(current) => {
+                self.db.lock().unwrap().delete_role(pubkey)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Applies the commands of an admin event that `role` is permitted to use
+    pub async fn handle_admission_update(
+        &self,
+        event: Event,
+        role: Role,
+    ) -> Result<CommandReport, Error> {
+        let mut report = CommandReport::default();
+
         for tag in event.tags {
-            match tag.values.get(0) {
-                Some(value) if value.as_str() == "allow" => {
-                    self.admit_pubkeys(
-                        &tag.values
-                            .split_first()
-                            .map(|(_, rest)| rest.to_vec())
-                            .unwrap_or(Vec::new()),
-                    )
-                    .await?
+            let (command, invalid) = match Command::parse(&tag.values) {
+                Ok(parsed) => parsed,
+                Err(reason) => {
+                    warn!("Rejected admin tag {:?}: {reason}", tag.values);
+                    report.reject(tag.values, &reason);
+                    continue;
+                }
+            };
+
+            if !command.permitted(role) {
+                warn!("{role:?} cannot use {}", command.name());
+                report.reject(tag.values, &format!("Not permitted for {role:?}"));
+                continue;
+            }
+
+            if !invalid.is_empty() {
+                let mut rejected = vec![command.name().to_string()];
+                rejected.extend(invalid);
+                report.reject(rejected, "Invalid key");
+            }
+
+            match &command {
+                Command::Allow(pubkeys) => {
+                    self.admit_pubkeys(pubkeys).await?;
+                    report.apply(command.name(), pubkeys);
+                }
+                Command::Deny(pubkeys) => {
+                    let denied = self.deny_pubkeys(pubkeys, role).await?;
+                    report.reject_outranked(command.name(), pubkeys, &denied);
+                    report.apply(command.name(), &denied);
+                }
+                Command::Remove(pubkeys) => {
+                    let mut removed = Vec::new();
+                    for pubkey in pubkeys {
+                        if self.can_moderate(pubkey, role)? {
+                            self.remove_account(pubkey)?;
+                            removed.push(pubkey.clone());
+                        }
+                    }
+                    report.reject_outranked(command.name(), pubkeys, &removed);
+                    report.apply(command.name(), &removed);
+                }
+                Command::Expire {
+                    expires_at,
+                    pubkeys,
+                } => {
+                    let expired = self.expire_pubkeys(pubkeys, *expires_at, role).await?;
+                    report.reject_outranked(command.name(), pubkeys, &expired);
+                    if !expired.is_empty() {
+                        let mut values = vec![expires_at.to_string()];
+                        values.extend(expired);
+                        report.apply(command.name(), &values);
+                    }
+                }
+                Command::SetQuota { quota, pubkeys } => {
+                    let quota = (*quota > 0).then_some(*quota);
+                    for pubkey in pubkeys {
+                        self.set_quota(pubkey, quota)?;
+                    }
+                    let mut values = vec![quota.unwrap_or(0).to_string()];
+                    values.extend_from_slice(pubkeys);
+                    report.apply(command.name(), &values);
+                }
+                Command::AddRole(target, pubkeys) => {
+                    let mut applied = Vec::new();
+                    for pubkey in pubkeys {
+                        match self.set_role(pubkey, *target, role)? {
+                            true => applied.push(pubkey.clone()),
+                            false => report.reject(
+                                vec![command.name().to_string(), pubkey.clone()],
+                                "Key has an equal or higher role",
+                            ),
+                        }
+                    }
+                    report.apply(command.name(), &applied);
                 }
-                Some(value) if value.as_str() == "deny" => {
-                    self.deny_pubkeys(
-                        &tag.values
-                            .split_first()
-                            .map(|(_, rest)| rest.to_vec())
-                            .unwrap_or(Vec::new()),
-                    )
-                    .await?
+                Command::RemoveRole(target, pubkeys) => {
+                    let mut applied = Vec::new();
+                    for pubkey in pubkeys {
+                        match self.remove_role(pubkey, *target, role)? {
+                            true => applied.push(pubkey.clone()),
+                            false => report.reject(
+                                vec![command.name().to_string(), pubkey.clone()],
+                                &format!("Key is not {target:?}"),
+                            ),
+                        }
+                    }
+                    report.apply(command.name(), &applied);
+                }
+                Command::AllowEvent(ids) | Command::DenyEvent(ids) => {
+                    let status = match command {
+                        Command::AllowEvent(_) => Status::Allow,
+                        _ => Status::Deny,
+                    };
+                    let events: Vec<db::Event> = ids
+                        .iter()
+                        .map(|id| db::Event {
+                            id: id.clone(),
+                            status,
+                        })
+                        .collect();
+                    self.db.lock().unwrap().write_events(&events)?;
+                    report.apply(command.name(), ids);
+                }
+                Command::Backfill(pubkeys) => {
+                    report.backfill.extend_from_slice(pubkeys);
+                    report.apply(command.name(), pubkeys);
                 }
-                _ => continue,
             }
         }
-        Ok(())
+
+        Ok(report)
+    }
+
+    /// Sets the events per day a pubkey can publish, `None` removes the quota
+    pub fn set_quota(&self, pubkey: &str, quota: Option<u64>) -> Result<(), Error> {
+        self.db.lock().unwrap().write_quota(pubkey, quota)
+    }
+
+    /// Counts an event against the pubkey's quota, returns false if the quota is used
+    pub fn use_quota(&self, pubkey: &str) -> Result<bool, Error> {
+        let db = self.db.lock().unwrap();
+        match db.read_quota(pubkey)? {
+            Some(quota) => {
+                let day = utils::unix_time() / 86_400;
+                Ok(db.increment_usage(pubkey, day)? <= quota)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Admits a pubkey for `seconds` more, from now or from when its admission expires
+    /// if later. Pubkeys admitted without an expiry are left unchanged.
+    pub fn extend_admission(&self, pubkey: &str, seconds: u64) -> Result<Account, Error> {
+        let db = self.db.lock().unwrap();
+        let current = db.read_account(pubkey)?;
+        let permanent = current
+            .as_ref()
+            .is_some_and(|account| account.is_admitted() && account.expires_at.is_none());
+        let account = Account::extended(current, pubkey, seconds, utils::unix_time());
+        if !permanent {
+            db.write_account(&account)?;
+            self.accounts.send_replace(());
+        }
+        Ok(account)
+    }
+
+    /// Marks an invoice paid and admits its pubkey for `seconds` more, unless it is denied.
+    /// Returns `None` if the invoice was already paid.
+    pub fn pay_invoice(&self, payment_hash: &str, seconds: u64) -> Result<Option<Account>, Error> {
+        let account = self.db.lock().unwrap().pay_invoice(payment_hash, seconds)?;
+        if account.is_some() {
+            self.accounts.send_replace(());
+        }
+        Ok(account)
+    }
+
+    /// Records an admin command so it is only applied once, returning false if it was
+    /// already applied or was not created within `COMMAND_WINDOW` of now
+    pub fn record_command(&self, command_id: &str, created_at: u64) -> Result<bool, Error> {
+        let now = utils::unix_time();
+        if now.abs_diff(created_at) > command::COMMAND_WINDOW {
+            return Ok(false);
+        }
+        // Commands older than the window are rejected anyway so are only kept until then
+        self.db.lock().unwrap().write_command(
+            command_id,
+            now,
+            now.saturating_sub(2 * command::COMMAND_WINDOW),
+        )
+    }
+
+    /// Records a zap receipt so it is only counted once
+    pub fn record_zap(&self, receipt_id: &str) -> Result<bool, Error> {
+        self.db
+            .lock()
+            .unwrap()
+            .write_zap(receipt_id, utils::unix_time())
+    }
+
+    pub fn add_invoice(&self, invoice: &PendingInvoice) -> Result<(), Error> {
+        self.db.lock().unwrap().write_invoice(invoice)
+    }
+
+    pub fn get_invoice(&self, payment_hash: &str) -> Result<Option<PendingInvoice>, Error> {
+        self.db.lock().unwrap().read_invoice(payment_hash)
+    }
+
+    /// Counts an invoice created this hour, returning false once more than `limit` were
+    pub fn count_invoice(&self, limit: u64) -> Result<bool, Error> {
+        let hour = utils::unix_time() / 3600;
+        let count = self.db.lock().unwrap().increment_usage("invoices", hour)?;
+        Ok(count <= limit)
+    }
+
+    pub fn get_invoices(&self) -> Result<Vec<PendingInvoice>, Error> {
+        self.db.lock().unwrap().read_invoices()
+    }
+
+    pub fn remove_invoice(&self, payment_hash: &str) -> Result<bool, Error> {
+        self.db.lock().unwrap().delete_invoice(payment_hash)
+    }
+
+    /// Orders relays by score, best first, leaving out disabled relays
+    pub fn ranked_relays<I>(&self, urls: I) -> Result<Vec<Url>, Error>
+    where
+        I: IntoIterator<Item = Url>,
+    {
+        let db = self.db.lock().unwrap();
+        let now = utils::unix_time();
+        let mut ranked = Vec::new();
+        for url in urls {
+            let stats = db
+                .read_relay_stats(url.as_str())?
+                .unwrap_or_else(|| RelayStats::new(url.as_str()));
+            if !stats.is_disabled(now) {
+                ranked.push((stats.score(), url));
+            }
+        }
+        ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        Ok(ranked.into_iter().map(|(_, url)| url).collect())
+    }
+
+    /// Records fetches in the stats of each relay
+    pub fn record_relay_fetches(
+        &self,
+        fetches: &[RelayFetch],
+        penalise: bool,
+    ) -> Result<(), Error> {
+        let db = self.db.lock().unwrap();
+        let now = utils::unix_time();
+        let mut updated = Vec::with_capacity(fetches.len());
+        for fetch in fetches {
+            let mut stats = db
+                .read_relay_stats(fetch.url.as_str())?
+                .unwrap_or_else(|| RelayStats::new(fetch.url.as_str()));
+            let was_disabled = stats.is_disabled(now);
+            stats.record(fetch, now, penalise);
+            if !was_disabled && stats.is_disabled(now) {
+                warn!(
+                    "Not fetching from {} until {}",
+                    stats.url,
+                    stats.disabled_until.unwrap_or_default()
+                );
+            }
+            updated.push(stats);
+        }
+        db.write_relay_stats(&updated)
+    }
+
+    /// Scores of every relay that has been fetched from, best first
+    pub fn get_relay_scores(&self) -> Result<Vec<RelayScore>, Error> {
+        let now = utils::unix_time();
+        let mut scores: Vec<RelayScore> = self
+            .db
+            .lock()
+            .unwrap()
+            .read_all_relay_stats()?
+            .into_iter()
+            .map(|stats| RelayScore::new(stats, now))
+            .collect();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(scores)
+    }
+
+    pub fn save_import_jobs(&self, jobs: &[ImportJob]) -> Result<(), Error> {
+        self.db.lock().unwrap().write_import_jobs(jobs)
+    }
+
+    /// Removes and returns imports saved at the last shutdown
+    pub fn take_import_jobs(&self) -> Result<Vec<ImportJob>, Error> {
+        self.db.lock().unwrap().take_import_jobs()
+    }
+
+    /// Closes the db, called last on shutdown
+    pub fn close(&self) {
+        self.db.lock().unwrap().close()
     }
 
     pub fn add_event(&self, event: &db::Event) -> Result<(), Error> {
@@ -109,38 +538,185 @@ impl Repo {
         self.db.lock().unwrap().read_event(id)
     }
 
+    pub fn remove_event(&self, id: &str) -> Result<bool, Error> {
+        self.db.lock().unwrap().delete_event(id)
+    }
+
     pub fn get_all_events(&self) -> Result<(), Error> {
         self.db.lock().unwrap().read_all_events()
     }
 
-    pub fn admit_events(&self, event_ids: &HashMap<EventId, Option<String>>) -> Result<(), Error> {
+    /// Removes ids that have been denied or deleted
+    pub fn importable_events(
+        &self,
+        event_ids: &HashMap<EventId, Option<String>>,
+    ) -> Result<HashMap<EventId, Option<String>>, Error> {
+        let db = self.db.lock().unwrap();
+        let mut importable = HashMap::new();
+        for (id, relay) in event_ids {
+            match db.read_event(&id.to_hex())? {
+                Some(event) if event.status == Status::Deny => {
+                    debug!("Not importing denied event {}", id);
+                }
+                _ => {
+                    importable.insert(*id, relay.clone());
+                }
+            }
+        }
+        Ok(importable)
+    }
+
+    /// Admits referenced events, skipping ids that have been denied or deleted.
+    /// Returns the ids that were admitted.
+    pub fn admit_events(
+        &self,
+        event_ids: &HashMap<EventId, Option<String>>,
+    ) -> Result<HashMap<EventId, Option<String>>, Error> {
         debug!("Admitting events");
-        let events: Vec<db::Event> = event_ids
-            .iter()
-            .map(|e| db::Event {
-                id: e.0.to_hex(),
+        let admitted = self.importable_events(event_ids)?;
+
+        let events: Vec<db::Event> = admitted
+            .keys()
+            .map(|id| db::Event {
+                id: id.to_hex(),
                 status: Status::Allow,
             })
             .collect();
 
         debug!("DB events: {:?}", events);
 
-        self.db.lock().unwrap().write_events(&events)
+        self.db.lock().unwrap().write_events(&events)?;
+        Ok(admitted)
+    }
+
+    /// Checks a pubkey has been denied
+    pub fn is_denied(&self, pubkey: &str) -> Result<bool, Error> {
+        Ok(self
+            .get_account(pubkey)?
+            .is_some_and(|account| account.status == Status::Deny))
+    }
+
+    /// Records the events referenced by the `e` tags of a NIP-09 deletion. Authors can only
+    /// delete their own events, so an id is denied once an event with it by the author of the
+    /// deletion is seen. `a` tags are left to the home relay, as events are only fetched by id.
+    /// Returns the ids.
+    pub fn handle_deletion(&self, event: &Event) -> Result<Vec<String>, Error> {
+        let ids: Vec<String> = event
+            .tags
+            .iter()
+            .filter_map(|tag| match tag.values.as_slice() {
+                [kind, id, ..] if kind == "e" && utils::is_hex_key(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+
+        self.db.lock().unwrap().write_deletions(
+            &hex::encode(&event.pubkey),
+            &ids,
+            event.created_at,
+        )?;
+        Ok(ids)
+    }
+
+    /// Checks if an event has been deleted by its author, denying it so it is not fetched again
+    pub fn deny_if_deleted(&self, event_id: &str, author: &str) -> Result<bool, Error> {
+        let db = self.db.lock().unwrap();
+        if !db.read_deletion(event_id, author)? {
+            return Ok(false);
+        }
+        db.write_events(&[db::Event {
+            id: event_id.to_string(),
+            status: Status::Deny,
+        }])?;
+        Ok(true)
+    }
+
+    /// Records the NIP-40 expiration of imported events so they can be swept
+    pub fn set_event_expiries(&self, events: &[nostr_sdk::Event]) -> Result<(), Error> {
+        let expiries: Vec<(String, u64)> = events
+            .iter()
+            .filter_map(|e| utils::expiration(e).map(|expires_at| (e.id.to_hex(), expires_at)))
+            .collect();
+        if expiries.is_empty() {
+            return Ok(());
+        }
+        self.db.lock().unwrap().write_event_expiries(&expiries)
+    }
+
+    /// Denies expired events so they are not imported again
+    pub fn sweep_expired_events(&self) -> Result<Vec<String>, Error> {
+        self.db
+            .lock()
+            .unwrap()
+            .deny_expired_events(utils::unix_time())
     }
 
-    pub fn event_admitted(&self, author: &str, event: &Event) -> Result<Status, Error> {
+    pub fn event_admitted(&self, author: &str, event: &Event) -> Result<Admission, Error> {
+        if self.deny_if_deleted(&hex::encode(&event.id), &hex::encode(&event.pubkey))? {
+            return Ok(Admission::Denied);
+        }
+
         if let Some(event) = self.get_event(&hex::encode(&event.id))? {
             if event.is_admitted() {
-                return Ok(Status::Allow);
+                return Ok(Admission::Admitted);
             }
         }
 
         if let Some(account) = self.get_account(author)? {
-            if account.is_admitted() {
-                return Ok(Status::Allow);
+            if account.is_admitted() && self.use_quota(author)? {
+                return Ok(Admission::Admitted);
+            }
+            if account.status == Status::Deny {
+                return Ok(Admission::Denied);
             }
         }
-        Ok(Status::Deny)
+
+        if self.inbox_admitted(author, event)? {
+            return Ok(Admission::Inbox);
+        }
+        Ok(Admission::Denied)
+    }
+
+    /// Checks an event is of an inbox kind, tags an admitted pubkey, has the required
+    /// proof of work and the author is within the daily limit
+    fn inbox_admitted(&self, author: &str, event: &Event) -> Result<bool, Error> {
+        if !self.inbox.kinds.contains(&event.kind) {
+            return Ok(false);
+        }
+
+        let mut addressed = false;
+        for tag in &event.tags {
+            if let [name, pubkey, ..] = tag.values.as_slice() {
+                if name == "p" && self.get_account(pubkey)?.is_some_and(|a| a.is_admitted()) {
+                    addressed = true;
+                    break;
+                }
+            }
+        }
+        if !addressed {
+            return Ok(false);
+        }
+
+        if !self
+            .inbox
+            .difficulty
+            .is_none_or(|required| pow::has_difficulty(event, required))
+        {
+            return Ok(false);
+        }
+
+        match self.inbox.daily_limit {
+            Some(limit) => {
+                let day = utils::unix_time() / 86_400;
+                let usage = self
+                    .db
+                    .lock()
+                    .unwrap()
+                    .increment_usage(&format!("inbox:{author}"), day)?;
+                Ok(usage <= limit)
+            }
+            None => Ok(true),
+        }
     }
 }
 
@@ -149,6 +725,8 @@ mod tests {
 
     use serial_test::serial;
 
+    use std::collections::HashSet;
+
     use crate::nauthz_grpc::event::TagEntry;
 
     use super::*;
@@ -188,38 +766,403 @@ mod tests {
             sig: vec![],
         };
 
-        repo.handle_admission_update(event).await.unwrap();
+        repo.handle_admission_update(event, Role::Owner)
+            .await
+            .unwrap();
+
+        assert!(repo
+            .get_account(&allowed_keys[1])
+            .unwrap()
+            .unwrap()
+            .is_admitted());
+
+        assert!(repo
+            .get_account(&allowed_keys[2])
+            .unwrap()
+            .unwrap()
+            .is_admitted());
+
+        assert!(!repo
+            .get_account(&denied_keys[1])
+            .unwrap()
+            .unwrap()
+            .is_admitted());
+
+        assert!(!repo
+            .get_account(&denied_keys[2])
+            .unwrap()
+            .unwrap()
+            .is_admitted());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_accounts_page() {
+        let keys = vec![
+            "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_string(),
+            "82341f882b6eabcd2ba7f1ef90aad961cf074af15b9ef44a09f9d2a8fbfbe6a2".to_string(),
+            "fa984bd7dbb282f07e16e7ae87b26a2a7b9b90b7246a44771f0cf5ae58018f52".to_string(),
+        ];
+
+        let repo = Repo::new();
+        repo.admit_pubkeys(&keys).await.unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = repo
+                .get_accounts_page(Some(Status::Allow), cursor.as_deref(), 1)
+                .unwrap();
+            assert!(page.len() <= 1);
+            assert!(page.iter().all(|a| a.is_admitted()));
+            seen.extend(page.into_iter().map(|a| a.pubkey));
+            if next_cursor.is_none() {
+                break;
+            }
+            cursor = next_cursor;
+        }
+
+        assert!(keys.iter().all(|k| seen.contains(k)));
+    }
+
+    fn random_key() -> String {
+        nostr_sdk::Keys::generate().public_key().to_string()
+    }
+
+    fn admin_event(tags: Vec<Vec<String>>) -> Event {
+        Event {
+            id: vec![],
+            pubkey: vec![],
+            created_at: 172782,
+            kind: 4242,
+            content: "".to_string(),
+            tags: tags.into_iter().map(|values| TagEntry { values }).collect(),
+            sig: vec![],
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_admission_update_roles() {
+        let repo = Repo::new();
+        let (to_allow, to_deny, new_admin, new_moderator) =
+            (random_key(), random_key(), random_key(), random_key());
+
+        // Moderators can only deny
+        let event = admin_event(vec![
+            vec!["allow".to_string(), to_allow.clone()],
+            vec!["deny".to_string(), to_deny.clone()],
+            vec!["add-admin".to_string(), new_admin.clone()],
+        ]);
+        let report = repo
+            .handle_admission_update(event, Role::Moderator)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.applied,
+            vec![vec!["deny".to_string(), to_deny.clone()]]
+        );
+        assert_eq!(report.rejected.len(), 2);
+        assert!(repo.get_account(&to_allow).unwrap().is_none());
+        assert!(!repo.get_account(&to_deny).unwrap().unwrap().is_admitted());
+        assert!(repo.get_role(&new_admin).unwrap().is_none());
+
+        // Admins can manage moderators but not admins
+        let event = admin_event(vec![
+            vec!["add-admin".to_string(), new_admin.clone()],
+            vec!["add-moderator".to_string(), new_moderator.clone()],
+        ]);
+        repo.handle_admission_update(event, Role::Admin)
+            .await
+            .unwrap();
+        assert!(repo.get_role(&new_admin).unwrap().is_none());
+        assert_eq!(
+            repo.get_role(&new_moderator).unwrap(),
+            Some(Role::Moderator)
+        );
+
+        // Owners can manage admins
+        let event = admin_event(vec![
+            vec!["add-admin".to_string(), new_admin.clone()],
+            vec!["remove-moderator".to_string(), new_moderator.clone()],
+        ]);
+        repo.handle_admission_update(event, Role::Owner)
+            .await
+            .unwrap();
+        assert_eq!(repo.get_role(&new_admin).unwrap(), Some(Role::Admin));
+        assert!(repo.get_role(&new_moderator).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_moderation_limited_by_role() {
+        let repo = Repo::new();
+        let (owner, admin, user) = (random_key(), random_key(), random_key());
+        repo.admit_pubkeys(&[owner.clone(), admin.clone(), user.clone()])
+            .await
+            .unwrap();
+        repo.set_owners(std::slice::from_ref(&owner)).unwrap();
+        repo.set_role(&admin, Role::Admin, Role::Owner).unwrap();
+
+        // Moderators and admins cannot deny, expire or remove keys with an equal or higher role
+        let event = admin_event(vec![
+            vec!["deny".to_string(), owner.clone(), admin.clone()],
+            vec!["expire".to_string(), "1".to_string(), owner.clone()],
+            vec!["remove".to_string(), owner.clone()],
+        ]);
+        let report = repo
+            .handle_admission_update(event, Role::Admin)
+            .await
+            .unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.rejected.len(), 4);
+        for pubkey in [&owner, &admin] {
+            assert!(repo.get_account(pubkey).unwrap().unwrap().is_admitted());
+        }
+
+        let denied = repo
+            .deny_pubkeys(&[admin.clone(), user.clone()], Role::Moderator)
+            .await
+            .unwrap();
+        assert_eq!(denied, vec![user.clone()]);
+        assert!(repo.get_account(&admin).unwrap().unwrap().is_admitted());
+
+        let event = admin_event(vec![vec!["deny".to_string(), admin.clone()]]);
+        repo.handle_admission_update(event, Role::Owner)
+            .await
+            .unwrap();
+        assert!(!repo.get_account(&admin).unwrap().unwrap().is_admitted());
+    }
+
+    #[test]
+    #[serial]
+    fn test_commands_applied_once() {
+        let repo = Repo::new();
+        let now = utils::unix_time();
+        let window = command::COMMAND_WINDOW;
+
+        let id = random_key();
+        assert!(repo.record_command(&id, now).unwrap());
+        assert!(!repo.record_command(&id, now).unwrap());
+
+        assert!(repo
+            .record_command(&random_key(), now - window + 5)
+            .unwrap());
+        assert!(repo
+            .record_command(&random_key(), now + window - 5)
+            .unwrap());
+        assert!(!repo
+            .record_command(&random_key(), now - window - 5)
+            .unwrap());
+        assert!(!repo
+            .record_command(&random_key(), now + window + 5)
+            .unwrap());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_status_change_keeps_expiry() {
+        let repo = Repo::new();
+        let pubkey = random_key();
+        let expires_at = repo.extend_admission(&pubkey, 3600).unwrap().expires_at;
+        assert!(expires_at.is_some());
 
+        let account = repo.update_account(&pubkey, Status::Deny).await.unwrap();
+        assert_eq!(account.expires_at, expires_at);
+        let account = repo.update_account(&pubkey, Status::Allow).await.unwrap();
+        assert_eq!(account.expires_at, expires_at);
+
+        // Allowing again after the admission has ended starts a new one
+        repo.expire_pubkeys(std::slice::from_ref(&pubkey), 1, Role::Owner)
+            .await
+            .unwrap();
+        let account = repo.update_account(&pubkey, Status::Allow).await.unwrap();
+        assert_eq!(account.expires_at, None);
+        assert!(account.is_admitted());
+    }
+
+    /// Event with a random id by `author`
+    fn authored_event(author: &str, kind: u64, tags: Vec<Vec<String>>) -> Event {
+        Event {
+            id: ::hex::decode(random_key()).unwrap(),
+            pubkey: ::hex::decode(author).unwrap(),
+            kind,
+            ..admin_event(tags)
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_deleted_events_not_admitted() {
+        let repo = Repo::new();
+        let author = random_key();
+        repo.admit_pubkeys(std::slice::from_ref(&author))
+            .await
+            .unwrap();
+        let deleted = authored_event(&author, 1, vec![]);
+        let deleted_id = ::hex::encode(&deleted.id);
+
+        let deletion = authored_event(
+            &author,
+            DELETION_KIND,
+            vec![vec!["e".to_string(), deleted_id.clone()]],
+        );
         assert_eq!(
-            true,
-            repo.get_account(&allowed_keys[1])
-                .unwrap()
-                .unwrap()
-                .is_admitted()
+            repo.handle_deletion(&deletion).unwrap(),
+            vec![deleted_id.clone()]
         );
 
         assert_eq!(
-            true,
-            repo.get_account(&allowed_keys[2])
-                .unwrap()
-                .unwrap()
-                .is_admitted()
+            repo.event_admitted(&author, &deleted).unwrap(),
+            Admission::Denied
+        );
+        assert_eq!(
+            repo.get_event(&deleted_id).unwrap().unwrap().status,
+            Status::Deny
         );
 
+        // And it is not fetched again as a referenced event
+        let id = EventId::from_hex(&deleted_id).unwrap();
+        let other = EventId::from_hex(random_key()).unwrap();
+        let referenced = HashMap::from([(id, None), (other, None)]);
+        let admitted = repo.admit_events(&referenced).unwrap();
+        assert_eq!(admitted, HashMap::from([(other, None)]));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_deletion_of_other_authors_events_ignored() {
+        let repo = Repo::new();
+        let (author, other) = (random_key(), random_key());
+        repo.admit_pubkeys(&[author.clone(), other.clone()])
+            .await
+            .unwrap();
+        let event = authored_event(&author, 1, vec![]);
+        let event_id = ::hex::encode(&event.id);
+
+        let deletion = authored_event(
+            &other,
+            DELETION_KIND,
+            vec![vec!["e".to_string(), event_id.clone()]],
+        );
+        repo.handle_deletion(&deletion).unwrap();
+
+        assert_eq!(
+            repo.event_admitted(&author, &event).unwrap(),
+            Admission::Admitted
+        );
+        assert!(repo.get_event(&event_id).unwrap().is_none());
+        let id = EventId::from_hex(&event_id).unwrap();
+        let referenced = HashMap::from([(id, None)]);
+        assert_eq!(repo.admit_events(&referenced).unwrap(), referenced);
+    }
+
+    #[test]
+    #[serial]
+    fn test_sweep_expired_events() {
+        let repo = Repo::new();
+        let keys = nostr_sdk::Keys::generate();
+        let expiring = |expires_at: u64| {
+            nostr_sdk::EventBuilder::new(
+                nostr_sdk::Kind::TextNote,
+                "",
+                &[nostr_sdk::Tag::Expiration(expires_at.into())],
+            )
+            .to_event(&keys)
+            .unwrap()
+        };
+        let expired = expiring(utils::unix_time() - 10);
+        let live = expiring(utils::unix_time() + 3600);
+
+        let referenced = HashMap::from([(expired.id, None), (live.id, None)]);
+        repo.admit_events(&referenced).unwrap();
+        repo.set_event_expiries(&[expired.clone(), live.clone()])
+            .unwrap();
+
+        assert_eq!(
+            repo.sweep_expired_events().unwrap(),
+            vec![expired.id.to_hex()]
+        );
+        assert_eq!(repo.admit_events(&referenced).unwrap().len(), 1);
+        assert!(repo
+            .get_event(&live.id.to_hex())
+            .unwrap()
+            .unwrap()
+            .is_admitted());
+    }
+
+    #[test]
+    #[serial]
+    fn test_inbox_admitted() {
+        let mut repo = Repo::new();
+        repo.set_inbox(Inbox {
+            kinds: HashSet::from([1]),
+            difficulty: None,
+            daily_limit: Some(1),
+        });
+        let owner = random_key();
+        repo.add_account(&Account {
+            pubkey: owner.clone(),
+            status: Status::Allow,
+            expires_at: None,
+        })
+        .unwrap();
+
+        let stranger = random_key();
+        let mut reply = admin_event(vec![vec!["p".to_string(), owner]]);
+        reply.id = hex::decode(random_key()).unwrap();
+        reply.kind = 1;
+
+        assert_eq!(
+            repo.event_admitted(&stranger, &reply).unwrap(),
+            Admission::Inbox
+        );
+        // Over the daily limit
         assert_eq!(
-            false,
-            repo.get_account(&denied_keys[1])
-                .unwrap()
-                .unwrap()
-                .is_admitted()
+            repo.event_admitted(&stranger, &reply).unwrap(),
+            Admission::Denied
         );
 
+        // Not addressed to an admitted pubkey
+        let unrelated = admin_event(vec![vec!["p".to_string(), random_key()]]);
         assert_eq!(
-            false,
-            repo.get_account(&denied_keys[2])
-                .unwrap()
-                .unwrap()
-                .is_admitted()
+            repo.event_admitted(
+                &random_key(),
+                &Event {
+                    kind: 1,
+                    ..unrelated
+                }
+            )
+            .unwrap(),
+            Admission::Denied
         );
     }
+
+    #[test]
+    #[serial]
+    fn test_import_jobs_saved() {
+        let repo = Repo::new();
+        repo.take_import_jobs().unwrap();
+
+        let keys = nostr_sdk::Keys::generate();
+        let event = nostr_sdk::EventBuilder::new(nostr_sdk::Kind::TextNote, "", &[])
+            .to_event(&keys)
+            .unwrap();
+        let jobs = vec![
+            ImportJob::Referenced(HashMap::from([(
+                event.id,
+                Some("wss://relay.damus.io".to_string()),
+            )])),
+            ImportJob::Backfill(vec![keys.public_key().to_string()]),
+        ];
+        repo.save_import_jobs(&jobs[..1]).unwrap();
+        repo.save_import_jobs(&jobs[1..]).unwrap();
+        repo.close();
+        assert!(matches!(repo.take_import_jobs(), Err(Error::DbClosed)));
+
+        // Jobs are taken once, in the order they were saved
+        let repo = Repo::new();
+        assert_eq!(repo.take_import_jobs().unwrap(), jobs);
+        assert!(repo.take_import_jobs().unwrap().is_empty());
+    }
 }
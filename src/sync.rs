@@ -0,0 +1,929 @@
+//! Sync of events between the home relay and other relays
+//!
+//! The home relay is asked once for the ids and timestamps of its events matching a filter,
+//! then the events missing on either side of each relay are found with negentropy set
+//! reconciliation (NIP-77), so only the ids of ranges that differ are exchanged. Relays that
+//! do not support it are compared one time window at a time, going back from the newest:
+//! windows they count (NIP-45) as many events in as the home relay has are skipped, windows
+//! with more than a page of events are split, and the others are fetched and their ids
+//! compared. Only the events a side is missing are kept.
+
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use futures_util::{SinkExt, StreamExt};
+use tracing::{debug, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::metrics::{Metrics, METRICS};
+use crate::negentropy::{Diff, Item, Negentropy};
+use crate::utils;
+
+/// How long a relay is waited for to answer a message
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a relay is waited for to answer NEG-OPEN or COUNT before it is assumed not to
+/// support it
+const NEG_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events asked for in one request
+const PAGE_SIZE: usize = 500;
+/// Seconds a window can span for the same number of events on both sides to be taken as the
+/// same events. Longer windows are split so an event missing on each side rarely cancels out.
+const COUNTED_SPAN: u64 = 7 * 86_400;
+
+/// Events to transfer to sync the home relay with a relay
+#[derive(Debug, Default)]
+pub struct Synced {
+    /// Verified events the home relay is missing
+    pub missing: Vec<Event>,
+    /// Events of the home relay the relay is missing, only fetched to be uploaded
+    pub extra: Vec<Event>,
+    /// True if the relay supports NIP-77
+    pub negentropy: bool,
+}
+
+/// Event of the home relay, without its content
+#[derive(Debug, Clone, Copy)]
+struct Stored {
+    item: Item,
+    author: XOnlyPublicKey,
+}
+
+/// Events of the home relay matching a filter, by timestamp and id, to sync relays with
+pub struct HomeSet {
+    url: Url,
+    keys: Keys,
+    filter: Filter,
+    /// Sorted by timestamp then id
+    stored: Vec<Stored>,
+}
+
+impl HomeSet {
+    /// Asks the home relay for its events matching `filter`, keeping their ids and timestamps
+    pub async fn fetch(home: &Url, keys: &Keys, filter: Filter) -> Result<Self, Error> {
+        let mut socket = RelaySocket::connect(home, keys).await?;
+        let mut stored = socket.query_index(&filter).await?;
+        socket.close().await;
+        stored.sort_by_key(|stored| stored.item);
+        stored.dedup_by_key(|stored| stored.item);
+        debug!("{home} has {} events matching the filter", stored.len());
+        Ok(Self {
+            url: home.clone(),
+            keys: keys.clone(),
+            filter,
+            stored,
+        })
+    }
+
+    /// Finds the events matching the filter that only one of the home relay and `relay` has.
+    /// Those the relay is missing are fetched from the home relay if `upload` is set.
+    pub async fn sync(&self, relay: &Url, upload: bool) -> Result<Synced, Error> {
+        let mut socket = RelaySocket::connect(relay, &self.keys).await?;
+        let items = self.stored.iter().map(|stored| stored.item).collect();
+        let (mut missing, extra, negentropy) = match socket.reconcile(&self.filter, items).await? {
+            Some(diff) => (socket.query_ids(&diff.need).await?, diff.have, true),
+            None => {
+                debug!("{relay} does not support NIP-77, comparing time windows");
+                let (missing, extra) = self.compare_windows(&mut socket).await?;
+                (missing, extra, false)
+            }
+        };
+        socket.close().await;
+
+        // Only valid events matching the filter are imported
+        let fetched = missing.len();
+        missing.retain(|event| {
+            filter_matches(&self.filter, event) && utils::verify_event(event).is_ok()
+        });
+        let invalid = fetched - missing.len();
+        if invalid > 0 {
+            Metrics::add(&METRICS.events_invalid, invalid as u64);
+            warn!("{relay} returned {invalid} invalid events");
+        }
+
+        let mut extra = match upload && !extra.is_empty() {
+            true => {
+                let mut home = RelaySocket::connect(&self.url, &self.keys).await?;
+                let events = home.query_ids(&extra).await?;
+                home.close().await;
+                events
+            }
+            false => Vec::new(),
+        };
+        // NIP-70 protected events are only published by their authors
+        extra.retain(|event| !is_protected(event));
+        Ok(Synced {
+            missing,
+            extra,
+            negentropy,
+        })
+    }
+
+    /// Finds the events only one side has one time window at a time, going back from the
+    /// newest. Returns the events the home relay is missing and the ids the relay is missing.
+    async fn compare_windows(
+        &self,
+        socket: &mut RelaySocket,
+    ) -> Result<(Vec<Event>, Vec<[u8; 32]>), Error> {
+        let (mut missing, mut extra) = (Vec::new(), Vec::new());
+        let mut counts = true;
+        let mut windows = vec![self.filter.clone()];
+        while let Some(window) = windows.pop() {
+            let stored = self.window(&window).count() as u64;
+            let count = match counts {
+                true => socket.count(&window).await?,
+                false => None,
+            };
+            if counts && count.is_none() {
+                debug!(
+                    "{} does not support NIP-45, fetching every window",
+                    socket.url
+                );
+                counts = false;
+            }
+            match count {
+                Some(count) if count == stored && (count == 0 || span(&window) <= COUNTED_SPAN) => {
+                    continue
+                }
+                Some(0) => {
+                    extra.extend(self.window(&window).map(|stored| stored.item.id));
+                    continue;
+                }
+                Some(count) if count == stored || count > PAGE_SIZE as u64 => {
+                    if let Some((older, newer)) = split_window(&window) {
+                        windows.extend([older, newer]);
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+
+            let page = socket.query(window.clone().limit(PAGE_SIZE)).await?;
+            let complete = match count {
+                Some(count) => page.len() as u64 >= count,
+                None => page.len() < PAGE_SIZE,
+            };
+            // Relays send the newest events first, so an incomplete page only has all the
+            // events newer than its oldest
+            let oldest = page.iter().map(|event| event.created_at).min();
+            let covered = match oldest {
+                Some(oldest) if !complete => window.clone().since(oldest + 1_u64),
+                _ => window.clone(),
+            };
+            let page: Vec<Event> = page
+                .into_iter()
+                .filter(|event| in_window(&covered, event))
+                .collect();
+            let ids: HashSet<[u8; 32]> = page.iter().map(|event| item(event).id).collect();
+            extra.extend(
+                self.window(&covered)
+                    .map(|stored| stored.item.id)
+                    .filter(|id| !ids.contains(id)),
+            );
+            missing.extend(page.into_iter().filter(|event| !self.contains(event)));
+            if let Some(oldest) = oldest.filter(|_| !complete) {
+                next_windows(&mut windows, &window, oldest, &socket.url);
+            }
+        }
+        Ok((missing, extra))
+    }
+
+    /// Events stored by the authors of a window between its since and until
+    fn window<'a>(&'a self, window: &'a Filter) -> impl Iterator<Item = &'a Stored> {
+        let since = window.since.map_or(0, |since| since.as_u64());
+        let until = window.until.map_or(u64::MAX, |until| until.as_u64());
+        let start = self
+            .stored
+            .partition_point(|stored| stored.item.timestamp < since);
+        let end = self
+            .stored
+            .partition_point(|stored| stored.item.timestamp <= until);
+        self.stored[start..end.max(start)].iter().filter(|stored| {
+            window
+                .authors
+                .as_ref()
+                .is_none_or(|authors| authors.contains(&stored.author))
+        })
+    }
+
+    fn contains(&self, event: &Event) -> bool {
+        self.stored
+            .binary_search_by_key(&item(event), |stored| stored.item)
+            .is_ok()
+    }
+}
+
+fn item(event: &Event) -> Item {
+    Item {
+        timestamp: event.created_at.as_u64(),
+        id: event.id.inner().into_inner(),
+    }
+}
+
+/// Checks an event has a NIP-70 `["-"]` tag
+fn is_protected(event: &Event) -> bool {
+    event
+        .tags
+        .iter()
+        .any(|tag| tag.as_vec().first().map(String::as_str) == Some("-"))
+}
+
+/// Checks an event is by one of the authors of the filter, as sync filters are by author
+fn filter_matches(filter: &Filter, event: &Event) -> bool {
+    filter
+        .authors
+        .as_ref()
+        .is_none_or(|authors| authors.contains(&event.pubkey))
+}
+
+/// Checks an event is by the authors of a window and between its since and until
+fn in_window(window: &Filter, event: &Event) -> bool {
+    filter_matches(window, event)
+        && window.since.is_none_or(|since| event.created_at >= since)
+        && window.until.is_none_or(|until| event.created_at <= until)
+}
+
+/// Seconds between the since and until of a window
+fn span(window: &Filter) -> u64 {
+    let since = window.since.map_or(0, |since| since.as_u64());
+    let until = window.until.unwrap_or_else(Timestamp::now).as_u64();
+    until.saturating_sub(since)
+}
+
+/// Splits a window into its older and newer halves, or by authors if it is one second
+fn split_window(window: &Filter) -> Option<(Filter, Filter)> {
+    let since = window.since.map_or(0, |since| since.as_u64());
+    let until = window.until.unwrap_or_else(Timestamp::now).as_u64();
+    if since >= until {
+        return split_authors(window);
+    }
+    let middle = Timestamp::from(since + (until - since) / 2);
+    Some((
+        window.clone().until(middle),
+        window.clone().since(middle + 1_u64),
+    ))
+}
+
+/// Queues the windows left after a full page whose oldest event is at `oldest`: the window up
+/// to it, or if the page is all at one second that second by half the authors at a time and
+/// the window before it
+fn next_windows(windows: &mut Vec<Filter>, window: &Filter, oldest: Timestamp, url: &Url) {
+    if window.until != Some(oldest) {
+        windows.push(window.clone().until(oldest));
+        return;
+    }
+    match split_authors(&window.clone().since(oldest)) {
+        Some((first, second)) => windows.extend([first, second]),
+        None => warn!("{url} has more than {PAGE_SIZE} events at {oldest}, some are not synced"),
+    }
+    if oldest.as_u64() > 0 && window.since.is_none_or(|since| since < oldest) {
+        windows.push(window.clone().until(oldest - 1_u64));
+    }
+}
+
+/// Splits a filter into two asking for half of its authors each
+fn split_authors(filter: &Filter) -> Option<(Filter, Filter)> {
+    let authors = filter
+        .authors
+        .as_ref()
+        .filter(|authors| authors.len() > 1)?;
+    let (first, second) = authors.split_at(authors.len() / 2);
+    Some((
+        filter.clone().authors(first.to_vec()),
+        filter.clone().authors(second.to_vec()),
+    ))
+}
+
+/// What a relay sent
+pub enum Received {
+    Message(Vec<Value>),
+    /// The service authenticated, requests refused before should be sent again
+    Authenticated,
+    /// Answer to a ping
+    Pong,
+}
+
+/// Websocket connection to a relay for messages nostr-sdk does not support, answering NIP-42
+/// auth challenges with the service key
+pub struct RelaySocket {
+    url: Url,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    keys: Keys,
+    auth_id: Option<EventId>,
+    authenticated: bool,
+}
+
+impl RelaySocket {
+    pub async fn connect(url: &Url, keys: &Keys) -> Result<Self, Error> {
+        let (socket, _) = connect_async(url.as_str()).await.map_err(|err| {
+            warn!("Could not connect to {url}: {err}");
+            Error::RelayError
+        })?;
+        Ok(Self {
+            url: url.clone(),
+            socket,
+            keys: keys.clone(),
+            auth_id: None,
+            authenticated: false,
+        })
+    }
+
+    pub async fn send(&mut self, msg: String) -> Result<(), Error> {
+        self.socket
+            .send(WsMessage::Text(msg))
+            .await
+            .map_err(|_| Error::RelayError)
+    }
+
+    /// Asks the relay for a pong, to tell a quiet connection from a lost one
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.socket
+            .send(WsMessage::Ping(Vec::new()))
+            .await
+            .map_err(|_| Error::RelayError)
+    }
+
+    /// True if an auth challenge has been answered but not yet accepted
+    pub fn authenticating(&self) -> bool {
+        self.auth_id.is_some() && !self.authenticated
+    }
+
+    /// Waits for the next message, answering auth challenges
+    pub async fn recv(&mut self, timeout: Duration) -> Result<Received, Error> {
+        loop {
+            let msg = match tokio::time::timeout(timeout, self.socket.next()).await {
+                Ok(Some(Ok(WsMessage::Text(msg)))) => msg,
+                Ok(Some(Ok(WsMessage::Pong(_)))) => return Ok(Received::Pong),
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(err))) => {
+                    return Err(Error::SyncError(format!(
+                        "Error reading from {}: {err}",
+                        self.url
+                    )))
+                }
+                Ok(None) => {
+                    return Err(Error::SyncError(format!(
+                        "{} closed the connection",
+                        self.url
+                    )))
+                }
+                Err(_) => {
+                    return Err(Error::SyncError(format!(
+                        "{} did not answer in time",
+                        self.url
+                    )))
+                }
+            };
+            let msg: Vec<Value> = match serde_json::from_str(&msg) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+
+            match (msg.first().and_then(Value::as_str), msg.get(1)) {
+                (Some("AUTH"), Some(Value::String(challenge))) => {
+                    debug!("Auth challenge from {}", self.url);
+                    let auth = EventBuilder::auth(challenge, self.url.clone())
+                        .to_event(&self.keys)
+                        .map_err(|_| Error::SigningError)?;
+                    self.auth_id = Some(auth.id);
+                    self.authenticated = false;
+                    self.send(ClientMessage::new_auth(auth).as_json()).await?;
+                }
+                (Some("OK"), Some(Value::String(id)))
+                    if self.auth_id.is_some_and(|auth_id| auth_id.to_hex() == *id) =>
+                {
+                    if msg.get(2) != Some(&Value::Bool(true)) {
+                        return Err(Error::SyncError(format!(
+                            "Authentication to {} failed",
+                            self.url
+                        )));
+                    }
+                    self.authenticated = true;
+                    return Ok(Received::Authenticated);
+                }
+                _ => return Ok(Received::Message(msg)),
+            }
+        }
+    }
+
+    /// Asks for the events matching `filter` and collects them until EOSE
+    pub async fn query(&mut self, filter: Filter) -> Result<Vec<Event>, Error> {
+        let subscription_id = SubscriptionId::generate().to_string();
+        let req = json!(["REQ", subscription_id, filter]).to_string();
+        self.send(req.clone()).await?;
+
+        let mut events = Vec::new();
+        loop {
+            let msg = match self.recv(MESSAGE_TIMEOUT).await? {
+                Received::Authenticated => {
+                    events.clear();
+                    self.send(req.clone()).await?;
+                    continue;
+                }
+                Received::Pong => continue,
+                Received::Message(msg) => msg,
+            };
+            if msg.get(1).and_then(Value::as_str) != Some(&subscription_id) {
+                continue;
+            }
+            match msg.first().and_then(Value::as_str) {
+                Some("EVENT") => {
+                    if let Some(Ok(event)) = msg.get(2).cloned().map(serde_json::from_value) {
+                        events.push(event);
+                    }
+                }
+                // A relay that requires auth to read may end the subscription before the
+                // service has authenticated
+                Some("EOSE") if !self.authenticating() => break,
+                Some("CLOSED") if !self.authenticating() => {
+                    let reason = msg.get(2).and_then(Value::as_str).unwrap_or_default();
+                    return Err(Error::SyncError(format!(
+                        "{} refused the request: {reason}",
+                        self.url
+                    )));
+                }
+                _ => continue,
+            }
+        }
+
+        self.send(json!(["CLOSE", subscription_id]).to_string())
+            .await
+            .ok();
+        Ok(events)
+    }
+
+    /// Sends events and waits up to `timeout` for them to be acknowledged. Events refused
+    /// with `auth-required` are sent again once the service has authenticated.
+    /// Returns the ids of the events that were not acknowledged.
+    pub async fn publish(
+        &mut self,
+        events: &[Event],
+        timeout: Duration,
+    ) -> Result<Vec<EventId>, Error> {
+        let mut pending: HashMap<String, &Event> =
+            events.iter().map(|e| (e.id.to_hex(), e)).collect();
+        let mut auth_required: Vec<String> = Vec::new();
+        let mut resent: HashSet<String> = HashSet::new();
+        for event in events {
+            self.send(ClientMessage::new_event(event.clone()).as_json())
+                .await?;
+        }
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !pending.is_empty() {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let msg = match self.recv(remaining).await {
+                Ok(Received::Authenticated) => {
+                    for id in auth_required.drain(..) {
+                        if let Some(event) = pending.get(&id) {
+                            self.send(ClientMessage::new_event((*event).clone()).as_json())
+                                .await?;
+                            resent.insert(id);
+                        }
+                    }
+                    continue;
+                }
+                Ok(Received::Pong) => continue,
+                Ok(Received::Message(msg)) => msg,
+                Err(err) => {
+                    debug!("{err}");
+                    break;
+                }
+            };
+
+            let id = match (msg.first().and_then(Value::as_str), msg.get(1)) {
+                (Some("OK"), Some(Value::String(id))) if pending.contains_key(id) => id.clone(),
+                _ => continue,
+            };
+            let accepted = msg.get(2) == Some(&Value::Bool(true));
+            let message = msg.get(3).and_then(Value::as_str).unwrap_or_default();
+            // Events sent before the service authenticated are sent again once it has
+            if !accepted && message.starts_with("auth-required") && !resent.contains(&id) {
+                match self.authenticated {
+                    true => {
+                        self.send(ClientMessage::new_event(pending[&id].clone()).as_json())
+                            .await?;
+                        resent.insert(id);
+                    }
+                    false => auth_required.push(id),
+                }
+                continue;
+            }
+            if !accepted {
+                warn!("{} rejected event {id}: {message}", self.url);
+            }
+            pending.remove(&id);
+        }
+
+        Ok(pending
+            .values()
+            .map(|event| event.id)
+            .collect::<Vec<EventId>>())
+    }
+
+    /// Asks for all events matching `filter`, a page at a time going back from the newest,
+    /// keeping only their ids, timestamps and authors
+    async fn query_index(&mut self, filter: &Filter) -> Result<Vec<Stored>, Error> {
+        let mut stored = Vec::new();
+        let mut windows = vec![filter.clone()];
+        while let Some(window) = windows.pop() {
+            let page = self.query(window.clone().limit(PAGE_SIZE)).await?;
+            let full = page.len() >= PAGE_SIZE;
+            let oldest = page.iter().map(|event| event.created_at).min();
+            stored.extend(page.iter().map(|event| Stored {
+                item: item(event),
+                author: event.pubkey,
+            }));
+            if let Some(oldest) = oldest.filter(|_| full) {
+                next_windows(&mut windows, &window, oldest, &self.url);
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Asks for the events with `ids`, a page at a time
+    async fn query_ids(&mut self, ids: &[[u8; 32]]) -> Result<Vec<Event>, Error> {
+        let mut events = Vec::new();
+        for ids in ids.chunks(PAGE_SIZE) {
+            let ids: Vec<String> = ids.iter().map(::hex::encode).collect();
+            let wanted: HashSet<&String> = ids.iter().collect();
+            let page = self.query(Filter::new().ids(ids.clone())).await?;
+            events.extend(
+                page.into_iter()
+                    .filter(|event| wanted.contains(&event.id.to_hex())),
+            );
+        }
+        Ok(events)
+    }
+
+    /// Asks for the number of events matching `filter` (NIP-45).
+    /// Returns `None` if the relay does not support it.
+    async fn count(&mut self, filter: &Filter) -> Result<Option<u64>, Error> {
+        let subscription_id = SubscriptionId::generate().to_string();
+        let count = json!(["COUNT", subscription_id, filter]).to_string();
+        self.send(count.clone()).await?;
+
+        loop {
+            let msg = match self.recv(NEG_OPEN_TIMEOUT).await {
+                Ok(Received::Authenticated) => {
+                    self.send(count.clone()).await?;
+                    continue;
+                }
+                Ok(Received::Pong) => continue,
+                Ok(Received::Message(msg)) => msg,
+                Err(err) => {
+                    debug!("{err}");
+                    return Ok(None);
+                }
+            };
+
+            match (
+                msg.first().and_then(Value::as_str),
+                msg.get(1).and_then(Value::as_str),
+            ) {
+                (Some("COUNT"), Some(id)) if id == subscription_id => {
+                    return Ok(msg
+                        .get(2)
+                        .and_then(|count| count.get("count"))
+                        .and_then(Value::as_u64));
+                }
+                (Some("CLOSED"), Some(id)) if id == subscription_id => {
+                    if self.authenticating() {
+                        continue;
+                    }
+                    let reason = msg.get(2).and_then(Value::as_str).unwrap_or_default();
+                    debug!("{} could not count: {reason}", self.url);
+                    return Ok(None);
+                }
+                (Some("NOTICE"), _) => {
+                    debug!("{} sent a notice to COUNT: {:?}", self.url, msg.get(1));
+                    return Ok(None);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Finds the ids of events matching `filter` only one of `items` and the relay has.
+    /// Returns `None` if the relay does not support NIP-77.
+    async fn reconcile(
+        &mut self,
+        filter: &Filter,
+        items: Vec<Item>,
+    ) -> Result<Option<Diff>, Error> {
+        let mut negentropy = Negentropy::new(items);
+        let subscription_id = SubscriptionId::generate().to_string();
+        let open = json!([
+            "NEG-OPEN",
+            subscription_id,
+            filter,
+            ::hex::encode(negentropy.initiate())
+        ])
+        .to_string();
+        self.send(open.clone()).await?;
+
+        let mut diff = Diff::default();
+        let mut answered = false;
+        loop {
+            let timeout = match answered {
+                true => MESSAGE_TIMEOUT,
+                false => NEG_OPEN_TIMEOUT,
+            };
+            let msg = match self.recv(timeout).await {
+                Ok(Received::Authenticated) => {
+                    diff = Diff::default();
+                    self.send(open.clone()).await?;
+                    continue;
+                }
+                Ok(Received::Pong) => continue,
+                Ok(Received::Message(msg)) => msg,
+                Err(err) if !answered => {
+                    debug!("{err}");
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            };
+
+            match (
+                msg.first().and_then(Value::as_str),
+                msg.get(1).and_then(Value::as_str),
+            ) {
+                (Some("NEG-MSG"), Some(id)) if id == subscription_id => {
+                    answered = true;
+                    let reply = msg
+                        .get(2)
+                        .and_then(Value::as_str)
+                        .and_then(|reply| ::hex::decode(reply).ok())
+                        .ok_or_else(|| {
+                            Error::SyncError(format!("{} sent an invalid NEG-MSG", self.url))
+                        })?;
+                    let next = negentropy
+                        .reconcile(&reply, &mut diff)
+                        .map_err(|err| Error::SyncError(format!("{}: {err}", self.url)))?;
+                    match next {
+                        Some(next) => {
+                            self.send(
+                                json!(["NEG-MSG", subscription_id, ::hex::encode(next)])
+                                    .to_string(),
+                            )
+                            .await?
+                        }
+                        None => break,
+                    }
+                }
+                (Some("NEG-ERR"), Some(id)) if id == subscription_id => {
+                    let reason = msg.get(2).and_then(Value::as_str).unwrap_or_default();
+                    if reason.starts_with("auth-required") && self.authenticating() {
+                        continue;
+                    }
+                    debug!("{} could not reconcile: {reason}", self.url);
+                    return Ok(None);
+                }
+                (Some("NOTICE"), _) if !answered => {
+                    debug!("{} sent a notice to NEG-OPEN: {:?}", self.url, msg.get(1));
+                    return Ok(None);
+                }
+                _ => continue,
+            }
+        }
+
+        self.send(json!(["NEG-CLOSE", subscription_id]).to_string())
+            .await
+            .ok();
+        debug!(
+            "Reconciled with {}: {} events to send, {} to fetch",
+            self.url,
+            diff.have.len(),
+            diff.need.len()
+        );
+        Ok(Some(diff))
+    }
+
+    pub async fn close(mut self) {
+        self.socket.close(None).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Events matching the ids, authors and time window of a filter, newest first
+    /// and at most its limit
+    fn matching(events: &[Event], filter: &Filter) -> Vec<Event> {
+        let mut matching: Vec<Event> = events
+            .iter()
+            .filter(|e| {
+                filter
+                    .ids
+                    .as_ref()
+                    .is_none_or(|ids| ids.contains(&e.id.to_hex()))
+                    && filter_matches(filter, e)
+                    && filter.since.is_none_or(|since| e.created_at >= since)
+                    && filter.until.is_none_or(|until| e.created_at <= until)
+            })
+            .cloned()
+            .collect();
+        matching.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        matching.truncate(filter.limit.unwrap_or(usize::MAX));
+        matching
+    }
+
+    /// Relay that has `events` and answers REQs with those matching, NEG-OPEN if
+    /// `negentropy` is set and COUNT if `count` is set. Counts the events it sends.
+    async fn mock_relay(
+        events: Vec<Event>,
+        negentropy: bool,
+        count: bool,
+    ) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let events = events.clone();
+                let sent = counter.clone();
+                tokio::spawn(async move {
+                    let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let storage = Negentropy::new(
+                        events
+                            .iter()
+                            .map(|e| Item {
+                                timestamp: e.created_at.as_u64(),
+                                id: e.id.inner().into_inner(),
+                            })
+                            .collect(),
+                    );
+                    // Pings are answered by the websocket while reading
+                    while let Some(Ok(msg)) = socket.next().await {
+                        let WsMessage::Text(msg) = msg else { continue };
+                        let msg: Vec<Value> = serde_json::from_str(&msg).unwrap();
+                        let id = msg[1].clone();
+                        let replies = match (msg[0].as_str().unwrap(), negentropy) {
+                            ("REQ", _) => {
+                                let filter: Filter =
+                                    serde_json::from_value(msg[2].clone()).unwrap();
+                                let mut replies: Vec<Value> = matching(&events, &filter)
+                                    .iter()
+                                    .map(|e| json!(["EVENT", id, e]))
+                                    .collect();
+                                sent.fetch_add(replies.len(), Ordering::Relaxed);
+                                replies.push(json!(["EOSE", id]));
+                                replies
+                            }
+                            ("COUNT", _) if count => {
+                                let filter: Filter =
+                                    serde_json::from_value(msg[2].clone()).unwrap();
+                                let count = matching(&events, &filter).len();
+                                vec![json!(["COUNT", id, {"count": count}])]
+                            }
+                            ("COUNT", _) => vec![json!(["NOTICE", "unknown command"])],
+                            ("NEG-OPEN" | "NEG-MSG", true) => {
+                                let query =
+                                    ::hex::decode(msg.last().unwrap().as_str().unwrap()).unwrap();
+                                let reply = storage
+                                    .reconcile(&query, &mut Diff::default())
+                                    .unwrap()
+                                    .unwrap();
+                                vec![json!(["NEG-MSG", id, ::hex::encode(reply)])]
+                            }
+                            ("NEG-OPEN", false) => vec![json!(["NOTICE", "unknown command"])],
+                            _ => vec![],
+                        };
+                        for reply in replies {
+                            socket
+                                .send(WsMessage::Text(reply.to_string()))
+                                .await
+                                .unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (url, sent)
+    }
+
+    /// Signed text note created at `created_at`
+    fn note_at(keys: &Keys, created_at: u64) -> Event {
+        let mut unsigned = EventBuilder::new_text_note(created_at.to_string(), &[])
+            .to_unsigned_event(keys.public_key());
+        unsigned.created_at = Timestamp::from(created_at);
+        unsigned.id = EventId::new(
+            &unsigned.pubkey,
+            unsigned.created_at,
+            &unsigned.kind,
+            &unsigned.tags,
+            &unsigned.content,
+        );
+        unsigned.sign(keys).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..60)
+            .map(|n| {
+                EventBuilder::new_text_note(n.to_string(), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        let ids = |events: &[Event]| events.iter().map(|e| e.id).collect::<HashSet<_>>();
+        let filter = Filter::new().authors(vec![keys.public_key()]);
+
+        // The home relay has 0..50 and a protected event, the other relay 10..60
+        let protected = EventBuilder::new_text_note(
+            "protected",
+            &[Tag::Generic(TagKind::Custom("-".to_string()), vec![])],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let (home, _) = mock_relay([&events[..50], &[protected]].concat(), false, false).await;
+        let stored = HomeSet::fetch(&home, &keys, filter).await.unwrap();
+        for (negentropy, count) in [(true, false), (false, true), (false, false)] {
+            let (relay, _) = mock_relay(events[10..].to_vec(), negentropy, count).await;
+            let synced = stored.sync(&relay, true).await.unwrap();
+            assert_eq!(synced.negentropy, negentropy);
+            assert_eq!(ids(&synced.missing), ids(&events[50..]));
+            assert_eq!(ids(&synced.extra), ids(&events[..10]));
+
+            // Events of the home relay are only fetched to be uploaded
+            let synced = stored.sync(&relay, false).await.unwrap();
+            assert_eq!(ids(&synced.missing), ids(&events[50..]));
+            assert!(synced.extra.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_windows() {
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..3000)
+            .map(|n| note_at(&keys, 1_000_000 + n * 20))
+            .collect();
+        let new: Vec<Event> = (0..2).map(|n| note_at(&keys, 5_000_000 + n * 7)).collect();
+        let ids = |events: &[Event]| events.iter().map(|e| e.id).collect::<HashSet<_>>();
+        let filter = Filter::new().authors(vec![keys.public_key()]);
+
+        // The relay is missing two old events and has two newer ones the home relay is
+        // missing, in other weeks as missing events on both sides of a week cancel out
+        let (home, _) = mock_relay(events.clone(), false, false).await;
+        let stored = HomeSet::fetch(&home, &keys, filter).await.unwrap();
+        let (relay, sent) =
+            mock_relay([&events[..10], &events[12..], &new].concat(), false, true).await;
+        let synced = stored.sync(&relay, true).await.unwrap();
+        assert_eq!(ids(&synced.missing), ids(&new));
+        assert_eq!(ids(&synced.extra), ids(&events[10..12]));
+
+        // Only windows that differ are fetched
+        assert!(sent.load(Ordering::Relaxed) <= 2 * PAGE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let keys = Keys::generate();
+        let (relay, _) = mock_relay(Vec::new(), false, false).await;
+        let mut socket = RelaySocket::connect(&relay, &keys).await.unwrap();
+        socket.ping().await.unwrap();
+        assert!(matches!(
+            socket.recv(Duration::from_secs(5)).await,
+            Ok(Received::Pong)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_index_pages() {
+        let (a, b, c) = (Keys::generate(), Keys::generate(), Keys::generate());
+        let template = EventBuilder::new_text_note("", &[]).to_event(&a).unwrap();
+        let event = |keys: &Keys, created_at: u64| Event {
+            id: EventId::from_slice(&nostr_sdk::secp256k1::rand::random::<[u8; 32]>()).unwrap(),
+            pubkey: keys.public_key(),
+            created_at: Timestamp::from(created_at),
+            ..template.clone()
+        };
+
+        // Several pages of one author, and more than a page at one timestamp by two others
+        let mut events: Vec<Event> = (0..700).map(|n| event(&a, 1000 + n / 3)).collect();
+        events.extend((0..300).map(|_| event(&b, 5000)));
+        events.extend((0..300).map(|_| event(&c, 5000)));
+
+        let (relay, _) = mock_relay(events.clone(), false, false).await;
+        let mut socket = RelaySocket::connect(&relay, &a).await.unwrap();
+        let filter = Filter::new().authors(vec![a.public_key(), b.public_key(), c.public_key()]);
+        let stored = socket.query_index(&filter).await.unwrap();
+        assert_eq!(
+            stored.iter().map(|s| s.item.id).collect::<HashSet<_>>(),
+            events.iter().map(|e| item(e).id).collect()
+        );
+    }
+}
@@ -1,7 +1,18 @@
 use nostr_sdk::prelude::*;
 use nostr_sdk::{Client, Keys};
+use tracing::info;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::SystemTime;
 
+use crate::error::Error;
+
+/// Key file used when `secret_key_file` is not set
+pub const DEFAULT_KEY_FILE: &str = "service.key";
+
 /// Seconds since 1970.
 #[must_use]
 pub fn unix_time() -> u64 {
@@ -11,6 +22,49 @@ pub fn unix_time() -> u64 {
         .unwrap_or(0)
 }
 
+/// Unix time of the NIP-40 `expiration` tag of an event
+pub fn expiration(event: &Event) -> Option<u64> {
+    event.tags.iter().find_map(|tag| match tag {
+        Tag::Expiration(expires_at) => Some(expires_at.as_u64()),
+        _ => None,
+    })
+}
+
+/// Loads the service keys from the hex `secret_key` if set, otherwise from the key file.
+/// If the key file does not exist a new key is generated and written to it.
+pub fn load_or_create_keys(secret_key: Option<&str>, key_file: &Path) -> Result<Keys, Error> {
+    if let Some(secret_key) = secret_key {
+        let secret_key = SecretKey::from_str(secret_key).map_err(|_| Error::InvalidKey)?;
+        return Ok(Keys::new(secret_key));
+    }
+
+    if key_file.exists() {
+        let secret_key = fs::read_to_string(key_file)?;
+        let secret_key = SecretKey::from_str(secret_key.trim()).map_err(|_| Error::InvalidKey)?;
+        return Ok(Keys::new(secret_key));
+    }
+
+    let keys = Keys::generate();
+    let secret_key = keys.secret_key().map_err(|_| Error::InvalidKey)?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(key_file)?;
+    file.write_all(secret_key.display_secret().to_string().as_bytes())?;
+
+    info!(
+        "Created service key {} in {}",
+        keys.public_key(),
+        key_file.display()
+    );
+    Ok(keys)
+}
+
 // Creates the websocket client that is used for communicating with relays
 pub async fn create_client(
     keys: Option<&Keys>,
@@ -29,3 +83,68 @@ pub async fn create_client(
     client.connect().await;
     Ok(client)
 }
+
+/// Checks that a string is a 32-byte lowercase hex key or event id
+pub fn is_hex_key(key: &str) -> bool {
+    key.len() == 64 && key.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Checks the id of an event is the hash of its content and the signature is valid
+pub fn verify_event(event: &Event) -> Result<(), &'static str> {
+    let id = EventId::new(
+        &event.pubkey,
+        event.created_at,
+        &event.kind,
+        &event.tags,
+        &event.content,
+    );
+    if id != event.id {
+        return Err("id does not match the event");
+    }
+    event.verify().map_err(|_| "invalid signature")
+}
+
+/// Gets the first value of the first tag with `name`
+pub fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event.tags.iter().find_map(|tag| {
+        let values = tag.as_vec();
+        match values.first() {
+            Some(kind) if kind.eq(name) => values.get(1).cloned(),
+            _ => None,
+        }
+    })
+}
+
+/// Compares two byte strings in constant time
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_event() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new_text_note("hello", &[])
+            .to_event(&keys)
+            .unwrap();
+        assert!(verify_event(&event).is_ok());
+
+        let mut changed = event.clone();
+        changed.content = "changed".to_string();
+        assert_eq!(verify_event(&changed), Err("id does not match the event"));
+
+        // Signed by another key
+        let mut forged = event.clone();
+        forged.sig = EventBuilder::new_text_note("hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap()
+            .sig;
+        assert_eq!(verify_event(&forged), Err("invalid signature"));
+    }
+}
@@ -0,0 +1,280 @@
+//! NIP-57 zap admission
+//!
+//! Senders of zaps to admins are admitted for a time proportional to the amount.
+//! Zap receipts are checked as they are published to the relay and polled from the
+//! default relays. Each receipt is only counted once.
+
+use nostr_sdk::nostr::hashes::{sha256, Hash};
+use nostr_sdk::prelude::*;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bolt11::Invoice;
+use crate::client::NostrClient;
+use crate::db::Account;
+use crate::error::Error;
+use crate::reload::SharedConfig;
+use crate::repo::Repo;
+use crate::shutdown::Shutdown;
+use crate::utils;
+
+/// Kind of zap requests
+pub const ZAP_REQUEST_KIND: u64 = 9734;
+/// Kind of zap receipts
+pub const ZAP_RECEIPT_KIND: u64 = 9735;
+/// How often the default relays are polled for zap receipts
+const POLL_INTERVAL: Duration = Duration::from_secs(300);
+/// How far back the first poll looks for zap receipts
+const FIRST_POLL_LOOKBACK: u64 = 86_400;
+
+/// A validated zap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zap {
+    /// Hex pubkey of the zap request signer
+    pub sender: String,
+    /// Hex pubkey of the zapped key
+    pub recipient: String,
+    pub amount_msat: u64,
+}
+
+/// Validates a zap receipt and the zap request in its `description` tag.
+/// The receipt must be signed by one of `providers`, as the bolt11 signature is not checked
+/// anyone could otherwise sign a receipt for an invoice that was never paid.
+pub fn validate_receipt(receipt: &Event, providers: &HashSet<String>) -> Result<Zap, &'static str> {
+    if receipt.kind.as_u64() != ZAP_RECEIPT_KIND {
+        return Err("Not a zap receipt");
+    }
+    if !providers.contains(&receipt.pubkey.to_string()) {
+        return Err("Zap provider is not trusted");
+    }
+    if utils::verify_event(receipt).is_err() {
+        return Err("Invalid receipt signature");
+    }
+
+    let bolt11 = utils::tag_value(receipt, "bolt11").ok_or("No bolt11 tag")?;
+    let description = utils::tag_value(receipt, "description").ok_or("No description tag")?;
+    let recipient = utils::tag_value(receipt, "p").ok_or("No p tag")?;
+
+    let invoice = Invoice::decode(&bolt11).ok_or("Invalid bolt11")?;
+    let amount_msat = invoice.amount_msat.ok_or("Invoice has no amount")?;
+    if invoice.description_hash != Some(sha256::Hash::hash(description.as_bytes()).into_inner()) {
+        return Err("Description hash does not match");
+    }
+
+    let request = Event::from_json(&description).map_err(|_| "Invalid zap request")?;
+    if request.kind.as_u64() != ZAP_REQUEST_KIND {
+        return Err("Description is not a zap request");
+    }
+    if utils::verify_event(&request).is_err() {
+        return Err("Invalid zap request signature");
+    }
+    if utils::tag_value(&request, "p").as_deref() != Some(recipient.as_str()) {
+        return Err("Zap request is for another pubkey");
+    }
+    if let Some(amount) = utils::tag_value(&request, "amount") {
+        if amount.parse::<u64>().ok() != Some(amount_msat) {
+            return Err("Zap request amount does not match invoice");
+        }
+    }
+
+    Ok(Zap {
+        sender: request.pubkey.to_string(),
+        recipient,
+        amount_msat,
+    })
+}
+
+/// Admits senders of zaps to admins
+pub struct ZapAdmission {
+    repo: Arc<Mutex<Repo>>,
+    nostr: Arc<Mutex<NostrClient>>,
+    /// Zap admission is enabled while `zaps` is set in the config
+    config: SharedConfig,
+}
+
+impl ZapAdmission {
+    pub fn new(
+        repo: Arc<Mutex<Repo>>,
+        nostr: Arc<Mutex<NostrClient>>,
+        config: SharedConfig,
+    ) -> Self {
+        Self {
+            repo,
+            nostr,
+            config,
+        }
+    }
+
+    /// Admits the sender of a valid zap receipt to an admin.
+    /// Returns the admitted account, or `None` if the receipt was already counted.
+    pub async fn handle_receipt(&self, receipt: &Event) -> Result<Option<Account>, Error> {
+        let config = self.config.current();
+        let settings = config
+            .settings
+            .zaps
+            .as_ref()
+            .ok_or(Error::InvalidZap("Zap admission is not enabled"))?;
+        let zap = validate_receipt(receipt, &settings.trusted_providers).map_err(|reason| {
+            debug!("Invalid zap receipt {}: {}", receipt.id, reason);
+            Error::InvalidZap(reason)
+        })?;
+
+        let repo = self.repo.lock().await;
+        match repo.get_role(&zap.recipient)? {
+            Some(role) if role.can_allow() => (),
+            _ => return Err(Error::InvalidZap("Zap is not to an admin")),
+        }
+        if zap.amount_msat < settings.min_sats * 1000 {
+            return Err(Error::InvalidZap("Zap is below the minimum"));
+        }
+        if repo.is_denied(&zap.sender)? {
+            return Err(Error::InvalidZap("Sender is denied"));
+        }
+        if !repo.record_zap(&receipt.id.to_hex())? {
+            return Ok(None);
+        }
+
+        let seconds = zap.amount_msat / 1000 * 86_400 / settings.sats_per_day.max(1);
+        let account = repo.extend_admission(&zap.sender, seconds)?;
+        info!(
+            "Admitted {} until {:?} after zapping {} msats",
+            zap.sender, account.expires_at, zap.amount_msat
+        );
+        Ok(Some(account))
+    }
+
+    /// Periodically fetches zap receipts to admins from the default relays
+    pub async fn poll(self: Arc<Self>, shutdown: Shutdown) {
+        let mut since = utils::unix_time() - FIRST_POLL_LOOKBACK;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone().wait() => return,
+                _ = interval.tick() => (),
+            }
+            let now = utils::unix_time();
+            if self.config.current().settings.zaps.is_none() {
+                since = now;
+                continue;
+            }
+
+            let admins: Vec<String> = match self.repo.lock().await.get_admins() {
+                Ok(admins) => admins
+                    .into_iter()
+                    .filter(|a| a.role.can_allow())
+                    .map(|a| a.pubkey)
+                    .collect(),
+                Err(err) => {
+                    error!("Error reading admins: {}", err);
+                    continue;
+                }
+            };
+            let receipts = match self
+                .nostr
+                .lock()
+                .await
+                .fetch_zap_receipts(&admins, since)
+                .await
+            {
+                Ok(receipts) => receipts,
+                Err(err) => {
+                    error!("Error fetching zap receipts: {}", err);
+                    continue;
+                }
+            };
+
+            for receipt in receipts {
+                if let Err(err) = self.handle_receipt(&receipt).await {
+                    debug!("Zap receipt {} not counted: {}", receipt.id, err);
+                }
+            }
+            // Overlap polls in case receipts are published late
+            since = now - POLL_INTERVAL.as_secs();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_receipt() {
+        let sender = Keys::generate();
+        let provider = Keys::generate();
+        let recipient = Keys::generate().public_key().to_string();
+
+        let request = EventBuilder::new(
+            Kind::ZapRequest,
+            "",
+            &[
+                Tag::parse(vec!["p".to_string(), recipient.clone()]).unwrap(),
+                Tag::parse(vec!["amount", "2000000000"]).unwrap(),
+            ],
+        )
+        .to_event(&sender)
+        .unwrap();
+        let description = request.as_json();
+        let hash = sha256::Hash::hash(description.as_bytes()).into_inner();
+        let bolt11 = crate::bolt11::encode_unsigned("lnbc20m", &hash);
+        let receipt = |description: &str, bolt11: &str| {
+            EventBuilder::new(
+                Kind::Zap,
+                "",
+                &[
+                    Tag::parse(vec!["p".to_string(), recipient.clone()]).unwrap(),
+                    Tag::parse(vec!["bolt11", bolt11]).unwrap(),
+                    Tag::parse(vec!["description", description]).unwrap(),
+                ],
+            )
+            .to_event(&provider)
+            .unwrap()
+        };
+
+        let providers = HashSet::from([provider.public_key().to_string()]);
+        assert_eq!(
+            validate_receipt(&receipt(&description, &bolt11), &providers),
+            Ok(Zap {
+                sender: sender.public_key().to_string(),
+                recipient: recipient.clone(),
+                amount_msat: 2_000_000_000,
+            })
+        );
+        assert_eq!(
+            validate_receipt(
+                &receipt(&description, &bolt11),
+                &HashSet::from([Keys::generate().public_key().to_string()])
+            ),
+            Err("Zap provider is not trusted")
+        );
+        // Self signed receipts are not accepted without trusted providers
+        assert_eq!(
+            validate_receipt(&receipt(&description, &bolt11), &HashSet::new()),
+            Err("Zap provider is not trusted")
+        );
+        assert_eq!(
+            validate_receipt(
+                &receipt(
+                    &description,
+                    &crate::bolt11::encode_unsigned("lnbc10m", &hash)
+                ),
+                &providers
+            ),
+            Err("Zap request amount does not match invoice")
+        );
+        assert_eq!(
+            validate_receipt(
+                &receipt(
+                    &description,
+                    &crate::bolt11::encode_unsigned("lnbc20m", &[0; 32])
+                ),
+                &providers
+            ),
+            Err("Description hash does not match")
+        );
+    }
+}